@@ -1,14 +1,13 @@
 from lib.scripting_utils import *
 from jinja2 import Template 
 
-request = Interface.parse_incoming_request()
-cookies: dict = request["cookies"]
+user = Interface.current_user()
 
-def send_unauth():
+if user:
+    page_header:str = Template(Interface.read_file("data/pages/get/header_auth.html")).render(username=user["username"])
+else:
     page_header:str = Interface.read_file("data/pages/get/header_unauth.html")
-    page:Template = Template(Interface.read_file("data/pages/get/homepage.html"))
-    body = page.render(header=page_header)
-    Interface.send_to_http(200, "OK", {}, body)
-    
-if "sessionID" not in list(cookies.keys()): send_unauth()
-print("hello")
\ No newline at end of file
+
+page:Template = Template(Interface.read_file("data/pages/get/homepage.html"))
+body = page.render(header=page_header)
+Interface.send_to_http(200, "OK", {}, body)
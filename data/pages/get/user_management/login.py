@@ -1,27 +1,17 @@
 from lib.scripting_utils import *
 from jinja2 import Template
 
-database = Database()
 request = Interface.parse_incoming_request()
 
-try:
-    session_id = request["cookies"]["sessionID"]
-except KeyError:
-    session_id = None
-
-if session_id: auth = database.auth_user(session_id)
-else: auth = None
-
-if not auth:
+if not request["user"]:
     with open("data/pages/get/user_management/login.html") as f:
         template:Template = Template(f.read())
         
-    html_content = template.render(error_message=f"")
-    Interface.send_to_http({}, html_content)
+    html_content = template.render(error_message="", csrf_token=request["csrf_token"])
+    Interface.send_to_http(200, "OK", {}, html_content)
     
 else:
     try:
-        Interface.send_to_http({"Location": request["cookies"]["LoginRedirect"]}, "")
-    except:
-        Interface.send_to_http({"Location": "/"}, "")
-    
\ No newline at end of file
+        Interface.send_to_http(303, "See Other", {"Location": request["cookies"]["LoginRedirect"]}, "")
+    except KeyError:
+        Interface.send_to_http(303, "See Other", {"Location": "/"}, "")
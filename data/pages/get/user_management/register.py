@@ -0,0 +1,7 @@
+from lib.scripting_utils import *
+from jinja2 import Template
+
+request = Interface.parse_incoming_request()
+
+template:Template = Template(Interface.read_file("data/pages/get/user_management/register.html"))
+Interface.send_to_http(200, "OK", {}, template.render(csrf_token=request["csrf_token"]))
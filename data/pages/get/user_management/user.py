@@ -0,0 +1,7 @@
+from lib.scripting_utils import *
+from jinja2 import Template
+
+user = Interface.current_user()
+
+template:Template = Template(Interface.read_file("data/pages/get/user_management/user.html"))
+Interface.send_to_http(200, "OK", {}, template.render(username=user["username"], user=user["username"]))
@@ -0,0 +1,68 @@
+// Persistent worker loop used by the server when `script_workers` is enabled.
+//
+// The server sends a frame for each request on stdin and reads a frame back on stdout, a frame is a 4 bytes
+// big endian length followed by the payload:
+//     request:  {"script": "<path of the script>", "args": "<request as json>"}
+//     response: one status byte (0: success, 1: error) followed by the output of the script or its error
+//
+// The scripts are loaded again with require for each request, the modules they import stay cached.
+const fs = require("fs");
+const path = require("path");
+
+const writeResponse = fs.writeSync.bind(fs, 1);
+const realExit = process.exit;
+
+class ScriptExit extends Error {
+    constructor(code) {
+        super(`The script exited with code ${code}`);
+        this.code = code;
+    }
+}
+
+function writeFrame(status, payload) {
+    const header = Buffer.alloc(5);
+    header.writeUInt32BE(payload.length + 1, 0);
+    header.writeUInt8(status, 4);
+    const frame = Buffer.concat([header, payload]);
+    let written = 0;
+    while (written < frame.length) written += writeResponse(frame, written);
+}
+
+function runScript(script, args) {
+    const output = [];
+    const resolved = path.resolve(script);
+    const stdoutWrite = process.stdout.write;
+    process.stdout.write = (chunk, encoding, callback) => {
+        output.push(Buffer.isBuffer(chunk) ? chunk : Buffer.from(String(chunk), typeof encoding === "string" ? encoding : "utf8"));
+        if (typeof callback === "function") callback();
+        else if (typeof encoding === "function") encoding();
+        return true;
+    };
+    process.exit = (code) => { throw new ScriptExit(code || 0); };
+    process.argv = [process.argv[0], resolved, args];
+    try {
+        delete require.cache[resolved];
+        require(resolved);
+        return [0, Buffer.concat(output)];
+    } catch (e) {
+        if (e instanceof ScriptExit && e.code === 0) return [0, Buffer.concat(output)];
+        return [1, Buffer.from(String(e && e.stack || e))];
+    } finally {
+        process.stdout.write = stdoutWrite;
+        process.exit = realExit;
+    }
+}
+
+let pending = Buffer.alloc(0);
+process.stdin.on("data", (chunk) => {
+    pending = Buffer.concat([pending, chunk]);
+    while (pending.length >= 4) {
+        const length = pending.readUInt32BE(0);
+        if (pending.length < 4 + length) break;
+        const envelope = JSON.parse(pending.subarray(4, 4 + length).toString("utf8"));
+        pending = pending.subarray(4 + length);
+        const [status, payload] = runScript(envelope.script, envelope.args);
+        writeFrame(status, payload);
+    }
+});
+process.stdin.on("end", () => realExit(0));
@@ -0,0 +1,67 @@
+"""Persistent worker loop used by the server when `script_workers` is enabled.
+
+The server sends a frame for each request on stdin and reads a frame back on stdout, a frame is a 4 bytes
+big endian length followed by the payload:
+    request:  {"script": "<path of the script>", "args": "<request as json>"}
+    response: one status byte (0: success, 1: error) followed by the output of the script or its error
+
+The scripts are run unmodified with runpy, the modules they import stay loaded between requests.
+"""
+import io
+import json
+import os
+import runpy
+import struct
+import sys
+import traceback
+
+
+def read_frame(stream):
+    header = stream.read(4)
+    if len(header) < 4: return None
+    (length,) = struct.unpack(">I", header)
+    return stream.read(length)
+
+
+def write_frame(stream, status: int, payload: bytes):
+    stream.write(struct.pack(">I", len(payload) + 1) + bytes([status]) + payload)
+    stream.flush()
+
+
+def run_script(script: str, args: str):
+    output = io.BytesIO()
+    errors = io.StringIO()
+    stdout = io.TextIOWrapper(output, encoding="utf-8", write_through=True)
+    sys.stdout, sys.stderr = stdout, errors
+    sys.argv = [script, args]
+    status = 0
+    try:
+        runpy.run_path(script, run_name="__main__")
+    except SystemExit as e:
+        if e.code not in (None, 0):
+            status = 1
+            errors.write(f"The script exited with code {e.code}\n")
+    except BaseException:
+        status = 1
+        errors.write(traceback.format_exc())
+    finally:
+        stdout.flush()
+        sys.stdout, sys.stderr = sys.__stdout__, sys.__stderr__
+    return (0, output.getvalue()) if status == 0 else (1, errors.getvalue().encode())
+
+
+def main():
+    requests = sys.stdin.buffer
+    responses = os.fdopen(os.dup(1), "wb")
+    # anything written directly on fd 1 would corrupt the frames, send it to stderr instead
+    os.dup2(2, 1)
+    while True:
+        frame = read_frame(requests)
+        if frame is None: break
+        envelope = json.loads(frame)
+        status, payload = run_script(envelope["script"], envelope["args"])
+        write_frame(responses, status, payload)
+
+
+if __name__ == "__main__":
+    main()
@@ -38,11 +38,11 @@ class Database:
                 return None
             
     def auth_user(self, session_id:str) -> dict:
-        result:dict = self.get_row("users", "sessionID", session_id)
-        if not result or result["sessionExpires"] < int(time()):
+        session:dict = self.get_row("sessions", "id", session_id)
+        if not session or session["expires"] <= int(time()):
             return None
         else:
-            return result
+            return self.get_row("users", "username", session["username"])
         
             
 class Config():
@@ -80,6 +80,16 @@ class Interface:
         with open(filename, "r") as f: data=f.read()
         return data
     
+    def current_user() -> dict:
+        """The user of the session of the request (username, auth_level, session_id, session_expires, roles, permissions), None if the request has no valid session"""
+        request = Interface.parse_incoming_request()
+        return request.get("user") if request else None
+
+    def has_permission(permission: str) -> bool:
+        """True if the user of the request holds the permission, `*` and `prefix.*` grant several permissions"""
+        user = Interface.current_user()
+        return user is not None and any(permission.startswith(held[:-1]) if held.endswith("*") else held == permission for held in user.get("permissions", []))
+
     def parse_body_query() -> dict:
         result = {}
         body:str = Interface.parse_incoming_request()["body"]
@@ -0,0 +1,8 @@
+-- The routes of each method, see `Database::match_request`
+CREATE TABLE IF NOT EXISTS requests_get(path TEXT PRIMARY KEY, callback TEXT, auth_level INTEGER, params TEXT);
+CREATE TABLE IF NOT EXISTS requests_post(path TEXT PRIMARY KEY, callback TEXT, auth_level INTEGER, params TEXT);
+
+CREATE TABLE IF NOT EXISTS users(username TEXT PRIMARY KEY, hash TEXT, credits DOUBLE, auth_level INTEGER, email TEXT, sessionID text, sessionExpires BIGINT UNSIGNED, mcuuid TEXT);
+
+-- The page sent for each error, see `Database::get_error`
+CREATE TABLE IF NOT EXISTS errors (name TEXT PRIMARY KEY, response_message TEXT, callback TEXT);
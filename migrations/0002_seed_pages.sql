@@ -0,0 +1,29 @@
+INSERT OR IGNORE INTO requests_get (path, callback, auth_level, params) VALUES
+    ('/', 'data/pages/get/homepage.py', 0, ''),
+    ('/api/heartbeat', 'data/pages/api/get/heartbeat.json', 0, ''),
+    ('/api/flipbot', 'data/pages/api/get/flipbot.json', 2, ''),
+    ('/api/time', 'data/pages/api/get/gettime.py', 0, ''),
+    ('/api/test', 'data/pages/api/get/test_parsing.py', 0, ''),
+    ('/login', 'data/pages/get/user_management/login.py', 0, ''),
+    ('/register', 'data/pages/get/user_management/register.py', 0, ''),
+    ('/logoff', 'data/pages/get/user_management/logoff.py', 0, ''),
+    ('/user', 'data/pages/get/user_management/user.py', 1, ''),
+    ('/ressource', 'data/assets/ressource.py', 0, '');
+
+INSERT OR IGNORE INTO requests_post (path, callback, auth_level, params) VALUES
+    ('/login', 'data/pages/post/user_management/login.py', 0, ''),
+    ('/register', 'data/pages/post/user_management/register.py', 0, '');
+
+INSERT OR IGNORE INTO errors (name, response_message, callback) VALUES
+    ('err400', 'BAD REQUEST', 'data/pages/errors/400.json'),
+    ('err401', 'UNAUTHORIZED', 'data/pages/errors/401.py'),
+    ('err403', 'FORBIDDEN', 'data/pages/errors/403.py'),
+    ('err404', 'NOT FOUND', 'data/pages/errors/404.json'),
+    ('err405', 'METHOD NOT ALLOWED', 'data/pages/errors/405.json'),
+    ('err408', 'REQUEST TIMEOUT', 'data/pages/errors/408.json'),
+    ('err413', 'PAYLOAD TOO LARGE', 'data/pages/errors/413.json'),
+    ('err414', 'URI TOO LONG', 'data/pages/errors/414.json'),
+    ('err431', 'REQUEST HEADER FIELDS TOO LARGE', 'data/pages/errors/431.json'),
+    ('err502', 'BAD GATEWAY', 'data/pages/errors/502.json'),
+    ('err503', 'SERVICE UNAVAILABLE', 'data/pages/errors/503.json'),
+    ('err504', 'GATEWAY TIMEOUT', 'data/pages/errors/504.json');
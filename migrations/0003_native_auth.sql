@@ -0,0 +1,4 @@
+-- Moves the user management routes from the Python scripts to the built-in endpoints, see `Authenticator`
+UPDATE requests_post SET callback = 'auth://login' WHERE path = '/login' AND callback = 'data/pages/post/user_management/login.py';
+UPDATE requests_post SET callback = 'auth://register' WHERE path = '/register' AND callback = 'data/pages/post/user_management/register.py';
+UPDATE requests_get SET callback = 'auth://logout' WHERE path = '/logoff' AND callback = 'data/pages/get/user_management/logoff.py';
@@ -0,0 +1,13 @@
+-- The sessions of the users, a user can have several sessions at once, see `SessionStore`
+CREATE TABLE IF NOT EXISTS sessions(id TEXT PRIMARY KEY, username TEXT NOT NULL, created INTEGER, expires INTEGER, last_seen INTEGER, ip TEXT, user_agent TEXT);
+CREATE INDEX IF NOT EXISTS sessions_username ON sessions(username);
+CREATE INDEX IF NOT EXISTS sessions_expires ON sessions(expires);
+
+-- Keeps the sessions which were stored in the `users` table
+INSERT OR IGNORE INTO sessions (id, username, created, expires, last_seen, ip, user_agent)
+    SELECT sessionID, username, sessionExpires, sessionExpires, sessionExpires, '', '' FROM users
+    WHERE sessionID IS NOT NULL AND sessionID != '' AND sessionExpires > CAST(strftime('%s', 'now') AS INTEGER);
+UPDATE users SET sessionID = NULL, sessionExpires = NULL;
+
+INSERT OR IGNORE INTO requests_get (path, callback, auth_level, params) VALUES
+    ('/logoff/all', 'auth://logout_all', 1, '');
@@ -0,0 +1,7 @@
+-- The token of each session that the forms send back, see `check_csrf`
+ALTER TABLE sessions ADD COLUMN csrf_token TEXT;
+UPDATE sessions SET csrf_token = lower(hex(randomblob(32))) WHERE csrf_token IS NULL;
+
+-- A route with `csrf` set to 0 accepts the requests of other sites, for the APIs which don't use cookies
+ALTER TABLE requests_get ADD COLUMN csrf INTEGER DEFAULT 1;
+ALTER TABLE requests_post ADD COLUMN csrf INTEGER DEFAULT 1;
@@ -0,0 +1,3 @@
+-- The keys of the machine clients, only the SHA-256 of their token is stored, see `ApiKey`
+CREATE TABLE IF NOT EXISTS api_keys(id INTEGER PRIMARY KEY AUTOINCREMENT, token_hash TEXT NOT NULL UNIQUE, owner TEXT NOT NULL, name TEXT, scopes TEXT, auth_level INTEGER, created INTEGER, expires INTEGER, last_used INTEGER);
+CREATE INDEX IF NOT EXISTS api_keys_owner ON api_keys(owner);
@@ -0,0 +1,3 @@
+-- The ways each route accepts to authenticate its users, see `AuthSchemes`
+ALTER TABLE requests_get ADD COLUMN auth_scheme TEXT;
+ALTER TABLE requests_post ADD COLUMN auth_scheme TEXT;
@@ -0,0 +1,9 @@
+-- Roles group named permissions, a user holds the permissions of every role they are given, see `roles.rs`
+CREATE TABLE IF NOT EXISTS roles(name TEXT PRIMARY KEY, description TEXT);
+CREATE TABLE IF NOT EXISTS role_permissions(role TEXT NOT NULL, permission TEXT NOT NULL, PRIMARY KEY(role, permission));
+CREATE TABLE IF NOT EXISTS user_roles(username TEXT NOT NULL, role TEXT NOT NULL, PRIMARY KEY(username, role));
+CREATE INDEX IF NOT EXISTS user_roles_role ON user_roles(role);
+-- The permissions a route needs on top of its auth level, `method` is the suffix of its `requests_` table
+CREATE TABLE IF NOT EXISTS route_permissions(method TEXT NOT NULL, path TEXT NOT NULL, permission TEXT NOT NULL, PRIMARY KEY(method, path, permission));
+INSERT OR IGNORE INTO roles(name, description) VALUES ('admin', 'Every permission');
+INSERT OR IGNORE INTO role_permissions(role, permission) VALUES ('admin', '*');
@@ -0,0 +1,7 @@
+-- The failed password checks, kept as an audit of the attempts, see `LoginThrottle`
+CREATE TABLE IF NOT EXISTS login_failures(id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT, ip TEXT, path TEXT, time INTEGER NOT NULL);
+CREATE INDEX IF NOT EXISTS login_failures_username ON login_failures(username, time);
+CREATE INDEX IF NOT EXISTS login_failures_ip ON login_failures(ip, time);
+-- The IPs (`ip:<address>`) and usernames (`user:<username>`) which can't try a password until `locked_until`
+CREATE TABLE IF NOT EXISTS login_lockouts(subject TEXT PRIMARY KEY, locked_until INTEGER NOT NULL, lockouts INTEGER NOT NULL);
+INSERT OR IGNORE INTO errors(name, response_message, callback) VALUES ('err429', 'TOO MANY REQUESTS', 'data/pages/errors/429.json');
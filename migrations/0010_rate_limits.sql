@@ -0,0 +1,5 @@
+-- The number of requests a client can send to each route per `rate_limit_period` seconds, see `RateLimiter`
+ALTER TABLE requests_get ADD COLUMN rate_limit INTEGER;
+ALTER TABLE requests_get ADD COLUMN rate_limit_period INTEGER;
+ALTER TABLE requests_post ADD COLUMN rate_limit INTEGER;
+ALTER TABLE requests_post ADD COLUMN rate_limit_period INTEGER;
@@ -0,0 +1,12 @@
+-- The TOTP secrets of the users, `enabled` stays 0 until a first code confirms the enrollment, see `Totp`
+CREATE TABLE IF NOT EXISTS totp(username TEXT PRIMARY KEY, secret TEXT NOT NULL, enabled INTEGER NOT NULL DEFAULT 0, last_counter INTEGER NOT NULL DEFAULT 0, created INTEGER);
+-- The SHA-256 of the recovery codes the users haven't used yet
+CREATE TABLE IF NOT EXISTS recovery_codes(username TEXT NOT NULL, code_hash TEXT NOT NULL, PRIMARY KEY(username, code_hash));
+-- The logins whose password was right and which wait for their second factor, see `LoginChallenge`
+CREATE TABLE IF NOT EXISTS login_challenges(id TEXT PRIMARY KEY, username TEXT NOT NULL, expires INTEGER NOT NULL, attempts INTEGER NOT NULL DEFAULT 0);
+INSERT OR IGNORE INTO requests_get(path, callback, auth_level, params) VALUES
+    ('/login/totp', 'auth://totp', 0, ''),
+    ('/user/totp', 'auth://totp_setup', 1, '');
+INSERT OR IGNORE INTO requests_post(path, callback, auth_level, params) VALUES
+    ('/login/totp', 'auth://totp', 0, ''),
+    ('/user/totp', 'auth://totp_setup', 1, '');
@@ -0,0 +1,11 @@
+-- The limits of the scripts of each route, which take precedence over `script_limits` in the server config, see `ScriptLimits`
+ALTER TABLE requests_get ADD COLUMN script_timeout REAL;
+ALTER TABLE requests_get ADD COLUMN script_cpu_time INTEGER;
+ALTER TABLE requests_get ADD COLUMN script_memory INTEGER;
+ALTER TABLE requests_get ADD COLUMN script_open_files INTEGER;
+ALTER TABLE requests_get ADD COLUMN script_output_size INTEGER;
+ALTER TABLE requests_post ADD COLUMN script_timeout REAL;
+ALTER TABLE requests_post ADD COLUMN script_cpu_time INTEGER;
+ALTER TABLE requests_post ADD COLUMN script_memory INTEGER;
+ALTER TABLE requests_post ADD COLUMN script_open_files INTEGER;
+ALTER TABLE requests_post ADD COLUMN script_output_size INTEGER;
@@ -0,0 +1,205 @@
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::crypto_utils::{hex_encode, sha256};
+use crate::database_utils::{Database, DatabaseError, Filter, Order, Row, Value, now};
+use crate::sessions::random_token;
+
+/// The prefix of the tokens, so that a leaked token can be recognized by secret scanners
+const TOKEN_PREFIX: &str = "wrs_";
+/// The last use of a key is only written back when it is older than this number of seconds
+const LAST_USED_INTERVAL: i64 = 60;
+
+/// A key of the `api_keys` table, which authenticates a machine client with an `Authorization: Bearer <token>` header.
+///
+/// Only the SHA-256 of the token is stored, the token is shown once when the key is created:
+/// ```text
+/// webserver-rs api-key create flipbot_owner 2 --name flipbot --scope /api/* --days 90
+/// webserver-rs api-key list
+/// webserver-rs api-key revoke 3
+/// ```
+/// The auth level of a request made with a key is the level of the key, capped by the level of its owner. A key with
+/// scopes only opens the routes whose path is one of them, `/api/*` opens every path under `/api/`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: i64,
+    pub owner: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub auth_level: u8,
+    pub created: i64,
+    /// The UNIX timestamp when the key stops working, `None` if it never expires
+    pub expires: Option<i64>,
+    pub last_used: Option<i64>,
+}
+
+impl ApiKey {
+    /// Creates a key and returns it with its token, which can't be found again afterwards
+    pub fn create(database: &Database, owner: &str, name: &str, auth_level: u8, scopes: &[String], expires: Option<i64>) -> Result<(ApiKey, String), DatabaseError> {
+        let token = format!("{}{}", TOKEN_PREFIX, random_token()?);
+        let created = now();
+        let id = database.insert_row("api_keys", &[
+            ("token_hash", Value::from(hash_token(&token))),
+            ("owner", Value::from(owner)),
+            ("name", Value::from(name)),
+            ("scopes", Value::from(scopes.join(" "))),
+            ("auth_level", Value::from(auth_level as i64)),
+            ("created", Value::from(created)),
+            ("expires", expires.map(Value::from).unwrap_or(Value::Null)),
+        ])?;
+        let key = ApiKey {id, owner: owner.to_string(), name: name.to_string(), scopes: scopes.to_vec(), auth_level, created, expires, last_used: None};
+        Ok((key, token))
+    }
+
+    /// Returns the key of a token if it hasn't expired, and records its use
+    pub fn resolve(database: &Database, token: &str) -> Result<Option<ApiKey>, DatabaseError> {
+        if !token.starts_with(TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let now = now();
+        let Some(mut key) = database.select_rows("api_keys", &Filter::new().where_eq("token_hash", hash_token(token)))?.pop().map(ApiKey::from_row) else {
+            return Ok(None);
+        };
+        if key.expires.is_some_and(|v| v <= now) {
+            return Ok(None);
+        }
+        if key.last_used.map(|v| now - v >= LAST_USED_INTERVAL).unwrap_or(true) {
+            key.last_used = Some(now);
+            database.update_rows("api_keys", &[("last_used", Value::from(now))], &Filter::new().where_eq("id", key.id))?;
+        }
+        Ok(Some(key))
+    }
+
+    /// Returns every key, the most recently created first
+    pub fn list(database: &Database) -> Result<Vec<ApiKey>, DatabaseError> {
+        Ok(database.select_rows("api_keys", &Filter::new().order_by("id", Order::Descending))?.into_iter().map(ApiKey::from_row).collect())
+    }
+
+    /// Deletes a key, returns false if it didn't exist
+    pub fn revoke(database: &Database, id: i64) -> Result<bool, DatabaseError> {
+        Ok(database.delete_rows("api_keys", &Filter::new().where_eq("id", id))? > 0)
+    }
+
+    /// Returns true if the key can be used on this path
+    pub fn allows(&self, path: &str) -> bool {
+        self.scopes.is_empty() || self.scopes.iter().any(|scope| match scope.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == scope,
+        })
+    }
+
+    fn from_row(row: Row) -> ApiKey {
+        let text = |column: &str| match row.get(column) {
+            Some(Value::String(v)) => v.clone(),
+            _ => String::new(),
+        };
+        let integer = |column: &str| match row.get(column) {
+            Some(Value::Integer(v)) => Some(*v),
+            _ => None,
+        };
+        ApiKey {
+            id: integer("id").unwrap_or_default(),
+            owner: text("owner"),
+            name: text("name"),
+            scopes: text("scopes").split_whitespace().map(String::from).collect(),
+            auth_level: integer("auth_level").and_then(|v| u8::try_from(v).ok()).unwrap_or(0),
+            created: integer("created").unwrap_or_default(),
+            expires: integer("expires"),
+            last_used: integer("last_used"),
+        }
+    }
+}
+
+/// Runs the `api-key` subcommand, returns what has to be printed
+pub fn run_command(database: &Database, args: &[String]) -> Result<String, String> {
+    let usage = "Usage:\n    webserver-rs api-key create <owner> <auth_level> [--name <name>] [--scope <path>]... [--days <days>]\n    webserver-rs api-key list\n    webserver-rs api-key revoke <id>";
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["create", owner, auth_level, options @ ..] => {
+            let auth_level = auth_level.parse::<u8>().map_err(|_| format!("Invalid auth level {}", auth_level))?;
+            if database.request_row("users", "username", owner).map_err(|e| e.to_string())?.is_empty() {
+                return Err(format!("Unknown user {}", owner));
+            }
+            let (mut name, mut scopes, mut expires) = (String::new(), Vec::new(), None);
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                let value = options.next().ok_or(format!("Missing value for {}\n{}", option, usage))?;
+                match *option {
+                    "--name" => name = value.to_string(),
+                    "--scope" => scopes.push(value.to_string()),
+                    "--days" => expires = Some(now() + value.parse::<i64>().map_err(|_| format!("Invalid number of days {}", value))? * 86400),
+                    _ => return Err(format!("Unknown option {}\n{}", option, usage)),
+                }
+            }
+            let (key, token) = ApiKey::create(database, owner, &name, auth_level, &scopes, expires).map_err(|e| e.to_string())?;
+            Ok(format!("Created the key {} for {}, its token is only shown now:\n{}", key.id, key.owner, token))
+        },
+        ["list"] => Ok(ApiKey::list(database).map_err(|e| e.to_string())?.iter()
+            .map(|v| format!("{}\t{}\t{:?}\tlevel {}\tscopes: {}\texpires: {}\tlast used: {}", v.id, v.owner, v.name, v.auth_level,
+                if v.scopes.is_empty() {String::from("*")} else {v.scopes.join(" ")},
+                v.expires.map(|v| v.to_string()).unwrap_or(String::from("never")),
+                v.last_used.map(|v| v.to_string()).unwrap_or(String::from("never"))))
+            .collect::<Vec<String>>().join("\n")),
+        ["revoke", id] => match ApiKey::revoke(database, id.parse::<i64>().map_err(|_| format!("Invalid key id {}", id))?) {
+            Ok(true) => Ok(format!("Revoked the key {}", id)),
+            Ok(false) => Err(format!("No key {}", id)),
+            Err(e) => Err(e.to_string()),
+        },
+        _ => Err(String::from(usage)),
+    }
+}
+
+/// Returns the token of an `Authorization: Bearer <token>` header (RFC 6750)
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then_some(token.trim())
+}
+
+fn hash_token(token: &str) -> String {
+    hex_encode(&sha256(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api_keys::*;
+    use crate::database_utils::tests::migrated_database;
+    use crate::request_handler::{HTTPCode, IncomingRequest, ServerStatus};
+    use crate::sessions::SessionStore;
+    use crate::auth::Authenticator;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_api_keys() {
+        let database = migrated_database("api-keys");
+
+        let (key, token) = ApiKey::create(&database, "bot", "flipbot", 2, &[String::from("/api/*")], None).unwrap();
+        assert!(token.starts_with("wrs_") && token.len() == 68);
+        let resolved = ApiKey::resolve(&database, &token).unwrap().unwrap();
+        assert_eq!((key.id, 2, None), (resolved.id, resolved.auth_level, resolved.expires));
+        assert!(resolved.last_used.is_some());
+        assert!(resolved.allows("/api/flipbot") && !resolved.allows("/user"));
+        assert_eq!(None, ApiKey::resolve(&database, &token.replace("wrs_", "wrs_0")).unwrap());
+        assert_eq!(Some(token.as_str()), bearer_token(&format!("bearer  {}", token)));
+        assert_eq!(None, bearer_token("Basic YWRtaW46YWRtaW4="));
+
+        database.insert_row("users", &[("username", Value::from("bot")), ("auth_level", Value::from(1))]).unwrap();
+        let route = |path: &str, token: &str| {
+            let headers = HashMap::from([(String::from("authorization"), format!("Bearer {}", token))]);
+            let mut request = IncomingRequest::from_parts("GET", path, "HTTP/1.1", headers, Vec::new(), None, None);
+            let http_code = database.match_request(&mut request, &SessionStore::from_config(&HashMap::new()), &Authenticator::from_config(&HashMap::new()));
+            (http_code, request.user().map(|v| v.auth_level))
+        };
+        // the owner caps the level of the key
+        assert!(matches!(route("/api/flipbot", &token), (ServerStatus::Ok(HTTPCode::Err403), Some(1))));
+        database.update_rows("users", &[("auth_level", Value::from(3))], &Filter::new().where_eq("username", "bot")).unwrap();
+        assert!(matches!(route("/api/flipbot", &token), (ServerStatus::Ok(HTTPCode::Ok200(_)), Some(2))));
+        assert!(matches!(route("/logoff/all", &token), (ServerStatus::Ok(HTTPCode::Err403), Some(2))));
+        assert!(matches!(route("/login", &format!("{}0", token)), (ServerStatus::Ok(HTTPCode::Err401), None)));
+
+        let (expired, expired_token) = ApiKey::create(&database, "bot", "", 1, &[], Some(now() - 1)).unwrap();
+        assert_eq!(None, ApiKey::resolve(&database, &expired_token).unwrap());
+        assert!(ApiKey::revoke(&database, key.id).unwrap());
+        assert_eq!(None, ApiKey::resolve(&database, &token).unwrap());
+        assert_eq!(vec![expired.id], ApiKey::list(&database).unwrap().iter().map(|v| v.id).collect::<Vec<i64>>());
+    }
+}
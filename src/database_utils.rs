@@ -1,76 +1,631 @@
-use sqlite::{Error, Connection};
+use sqlite::{Error, Connection, OpenFlags, Statement};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// The most prepared statements kept by a connection of the pool
+const MAX_CACHED_STATEMENTS: usize = 64;
+
+/// A pool of SQLite connections to the server database, cloned for every worker that needs it.
+///
+/// The connections are opened in WAL mode so that the readers don't wait for the writers, they retry for
+/// `database_busy_timeout` seconds when the database is locked and each one keeps its prepared statements.
+/// At most `database_pool_size` idle connections are kept, more are opened when every one of them is in use:
+/// ```json
+/// {
+///     "database":"data/database.db",
+///     "database_pool_size":8,
+///     "database_busy_timeout":5
+/// }
+/// ```
+#[derive(Clone)]
 pub struct Database {
+    pool: Arc<Pool>,
+}
+
+struct Pool {
     filepath: String,
+    max_idle: usize,
+    busy_timeout: usize,
+    idle: Mutex<Vec<PooledConnection>>,
+    /// The tables already looked up, by lowercase name
+    schema: Mutex<HashMap<String, Table>>,
+}
+
+/// The name of a table and of its columns, as written in the schema
+#[derive(Clone)]
+struct Table {
+    name: String,
+    columns: Vec<String>,
+}
+
+impl Table {
+    fn column(&self, name: &str) -> Option<&str> {
+        self.columns.iter().find(|v| v.eq_ignore_ascii_case(name)).map(String::as_str)
+    }
+}
+
+/// An error returned by the [Database] methods
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// The table isn't in the schema of the database
+    UnknownTable (String),
+    /// The column isn't in the table, with the table and the column
+    UnknownColumn (String, String),
+    /// An error returned by SQLite
+    Sqlite (Error),
+    /// The kernel CSPRNG failed to generate a token or a secret to store, see [random_secret](crate::sessions::random_secret)
+    Random (std::io::Error),
+}
+
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DatabaseError::UnknownTable(table) => write!(f, "unknown table {:?}", table),
+            DatabaseError::UnknownColumn(table, column) => write!(f, "unknown column {:?} in table {:?}", column, table),
+            DatabaseError::Sqlite(e) => write!(f, "{}", e),
+            DatabaseError::Random(e) => write!(f, "error when generating random bytes: {}", e),
+        }
+    }
+}
+
+impl From<Error> for DatabaseError {
+    fn from(error: Error) -> DatabaseError {
+        DatabaseError::Sqlite(error)
+    }
+}
+
+/// Returns the current UNIX time in seconds, as stored in the timestamp columns
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("ERROR: TIME WENT BACKWARDS").as_secs() as i64
+}
+
+/// Quotes an identifier for SQL, the quotes it contains are doubled
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// A connection and the statements prepared on it
+struct PooledConnection {
+    // declared first so that the statements are finalized before the connection is closed
+    statements: HashMap<String, Statement<'static>>,
+    connection: Connection,
+}
+
+// SAFETY: the statements only point to the SQLite connection stored with them, which is opened without mutex
+// but only used by the thread holding the `PooledConnection`, and their column mappings are never shared
+unsafe impl Send for PooledConnection {}
+
+impl PooledConnection {
+    fn open(filepath: &str, busy_timeout: usize) -> Result<PooledConnection, Error> {
+        let flags = OpenFlags::new().set_create().set_read_write().set_no_mutex();
+        let mut connection = Connection::open_with_flags(filepath, flags)?;
+        connection.set_busy_timeout(busy_timeout)?;
+        connection.execute("PRAGMA journal_mode = WAL")?;
+        Ok(PooledConnection {statements: HashMap::new(), connection})
+    }
+
+    /// Returns the statement prepared for `sql_request`, ready to be bound
+    fn prepare(&mut self, sql_request: &str) -> Result<&mut Statement<'static>, Error> {
+        if !self.statements.contains_key(sql_request) {
+            if self.statements.len() >= MAX_CACHED_STATEMENTS {
+                self.statements.clear();
+            }
+            let statement = self.connection.prepare(sql_request)?;
+            // SAFETY: the statement is dropped with the connection, before it
+            let statement = unsafe { std::mem::transmute::<Statement<'_>, Statement<'static>>(statement) };
+            self.statements.insert(sql_request.to_string(), statement);
+        }
+        let statement = self.statements.get_mut(sql_request).unwrap();
+        // the reset returns the error of the last step of the statement again, which was already handled
+        let _ = statement.reset();
+        Ok(statement)
+    }
+}
+
+/// A connection taken from the pool, given back when dropped
+struct PoolGuard<'a> {
+    pool: &'a Pool,
+    inner: Option<PooledConnection>,
+}
+
+impl std::ops::Deref for PoolGuard<'_> {
+    type Target = PooledConnection;
+
+    fn deref(&self) -> &PooledConnection {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+impl std::ops::DerefMut for PoolGuard<'_> {
+    fn deref_mut(&mut self) -> &mut PooledConnection {
+        self.inner.as_mut().unwrap()
+    }
+}
+
+impl Drop for PoolGuard<'_> {
+    fn drop(&mut self) {
+        let mut idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.pool.max_idle {
+            idle.extend(self.inner.take());
+        }
+    }
 }
 
 #[allow(dead_code)]
 impl Database {
+    /// Creates a pool for the database at `filepath` with the default settings
     pub fn new(filepath: &str) -> Database {
-        Database {filepath: filepath.to_string()}
+        Database::with_settings(filepath, 8, 5000)
+    }
+
+    /// Creates the pool with the settings of the server config
+    pub fn from_config(config: &HashMap<String, String>) -> Database {
+        let filepath = config.get("database").map(String::as_str).unwrap_or_default();
+        let max_idle = config.get("database_pool_size").and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(8);
+        let busy_timeout = config.get("database_busy_timeout")
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|v| *v >= 0.0)
+            .map(|v| (v * 1000.0) as usize)
+            .unwrap_or(5000);
+        Database::with_settings(filepath, max_idle, busy_timeout)
     }
 
-    pub fn request_row(&self, table: &str, key_column: &str, key: &str) -> Result<HashMap<String, String>, Error> {
-        let mut result_map: HashMap<String, String> = HashMap::new();
-        let connection = Connection::open_with_full_mutex(&self.filepath)?;
+    fn with_settings(filepath: &str, max_idle: usize, busy_timeout: usize) -> Database {
+        Database {pool: Arc::new(Pool {
+            filepath: filepath.to_string(),
+            max_idle,
+            busy_timeout,
+            idle: Mutex::new(Vec::new()),
+            schema: Mutex::new(HashMap::new()),
+        })}
+    }
 
-        let sql_request = format!("SELECT * FROM {} WHERE {} = ?", table, key_column);
-        let mut statement = connection.prepare(sql_request)?;
-        statement.bind((1, key))?;
-        if let sqlite::State::Done = statement.next()? {
-            return Ok(result_map);
+    /// Looks the table up in the schema, the tables found are cached with their columns
+    fn table(&self, connection: &mut PooledConnection, name: &str, reload: bool) -> Result<Table, DatabaseError> {
+        let key = name.to_lowercase();
+        if !reload {
+            if let Some(v) = self.pool.schema.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+                return Ok(v.clone());
+            }
+        }
+        let statement = connection.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ? COLLATE NOCASE")?;
+        statement.bind((1, name))?;
+        let name = match statement.next()? {
+            sqlite::State::Row => statement.read::<String, _>(0)?,
+            sqlite::State::Done => {
+                self.pool.schema.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+                return Err(DatabaseError::UnknownTable(name.to_string()));
+            },
         };
+        statement.reset()?;
+        let statement = connection.prepare("SELECT name FROM pragma_table_info(?)")?;
+        statement.bind((1, name.as_str()))?;
+        let mut columns = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            columns.push(statement.read::<String, _>(0)?);
+        }
+        let table = Table {name, columns};
+        self.pool.schema.lock().unwrap_or_else(|e| e.into_inner()).insert(key, table.clone());
+        Ok(table)
+    }
 
-        let column_count: usize = statement.column_count();
-        for k in 0..column_count {
-            let key = statement.column_name(k)?;
-            let value = statement.read::<String, _>(k).unwrap_or_default();
-            result_map.insert(key.to_string(), value.to_string());
+    /// Returns the quoted names of the table and of the columns as written in the schema, which is looked up again
+    /// before a column is said to be unknown in case the table was altered
+    fn identifiers(&self, connection: &mut PooledConnection, table: &str, columns: &[&str]) -> Result<(String, Vec<String>), DatabaseError> {
+        let mut schema = self.table(connection, table, false)?;
+        if columns.iter().any(|v| schema.column(v).is_none()) {
+            schema = self.table(connection, table, true)?;
         }
-        Ok(result_map)
+        let columns = columns.iter()
+            .map(|v| schema.column(v).map(quote_identifier).ok_or_else(|| DatabaseError::UnknownColumn(schema.name.clone(), v.to_string())))
+            .collect::<Result<Vec<String>, DatabaseError>>()?;
+        Ok((quote_identifier(&schema.name), columns))
+    }
+
+    /// Takes an idle connection from the pool or opens a new one
+    fn connection(&self) -> Result<PoolGuard<'_>, Error> {
+        let idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let connection = match idle {
+            Some(v) => v,
+            None => PooledConnection::open(&self.pool.filepath, self.pool.busy_timeout)?,
+        };
+        Ok(PoolGuard {pool: &self.pool, inner: Some(connection)})
+    }
+
+    /// Returns the first row of `table` where `key_column` is `key` with its values as strings, the map is empty
+    /// if there is none. NULL values are empty strings, see [Database::select_rows] for the typed values.
+    pub fn request_row(&self, table: &str, key_column: &str, key: &str) -> Result<HashMap<String, String>, DatabaseError> {
+        let filter = Filter::new().where_eq(key_column, key).limit(1);
+        let row = self.select_rows(table, &filter)?.pop().unwrap_or_default();
+        Ok(row.into_iter().map(|(k, v)| (k, value_to_string(v))).collect())
+    }
+
+    /// Inserts a row in `table`, `values` maps the columns to their value
+    pub fn push_data(&self, table: &str, values: HashMap<String, String>) -> Result<(), DatabaseError> {
+        let values: Vec<(&str, Value)> = values.iter().map(|(k, v)| (k.as_str(), Value::from(v.as_str()))).collect();
+        self.insert_row(table, &values)?;
+        Ok(())
+    }
+
+    /// Returns the rows of `table` matching the filter
+    pub fn select_rows(&self, table: &str, filter: &Filter) -> Result<Vec<Row>, DatabaseError> {
+        self.run(|t| t.select_rows(table, filter))
+    }
+
+    /// Inserts a row in `table` and returns its rowid
+    pub fn insert_row(&self, table: &str, values: &[(&str, Value)]) -> Result<i64, DatabaseError> {
+        self.run(|t| t.insert_row(table, values))
     }
 
-    pub fn push_data(&self, table: &str, values: HashMap<String, String>) -> Result<(), Error> {
-        let connection = Connection::open_with_full_mutex(&self.filepath)?;
-        let mut sql_request = format!("INSERT INTO {} (", table);
-        let mut sql_values = vec![];
+    /// Inserts a row in `table`, or updates the row which has the same values in the `conflict_columns`
+    pub fn upsert_row(&self, table: &str, values: &[(&str, Value)], conflict_columns: &[&str]) -> Result<(), DatabaseError> {
+        self.run(|t| t.upsert_row(table, values, conflict_columns))
+    }
+
+    /// Sets the `values` of the rows of `table` matching the filter and returns how many rows were updated
+    pub fn update_rows(&self, table: &str, values: &[(&str, Value)], filter: &Filter) -> Result<usize, DatabaseError> {
+        self.run(|t| t.update_rows(table, values, filter))
+    }
+
+    /// Deletes the rows of `table` matching the filter and returns how many rows were deleted
+    pub fn delete_rows(&self, table: &str, filter: &Filter) -> Result<usize, DatabaseError> {
+        self.run(|t| t.delete_rows(table, filter))
+    }
 
-        for (column_name, value) in values.iter() {
-            sql_request.push_str(&format!("{}, ", column_name));
-            sql_values.push(value);
+    /// Runs the queries of `f` in a transaction on a single connection, which is committed if `f` returns `Ok`
+    /// and rolled back if it returns an error or panics.
+    ///
+    /// # Example
+    /// ```
+    /// database.transaction(|t| {
+    ///     let buyer = Filter::new().where_eq("username", "user1");
+    ///     let credits = match t.select_rows("users", &buyer)?.pop().and_then(|v| v.get("credits").cloned()) {
+    ///         Some(Value::Float(v)) => v,
+    ///         _ => 0.0,
+    ///     };
+    ///     t.update_rows("users", &[("credits", Value::from(credits - 10.0))], &buyer)
+    /// })?;
+    /// ```
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, DatabaseError>
+    where F: FnOnce(&mut Transaction) -> Result<T, DatabaseError>
+    {
+        let mut connection = self.connection()?;
+        connection.connection.execute("BEGIN IMMEDIATE")?;
+        let mut transaction = Transaction {database: self, connection: &mut connection, open: true};
+        let result = f(&mut transaction);
+        if result.is_ok() {
+            transaction.connection.connection.execute("COMMIT")?;
+            transaction.open = false;
         }
+        result
+    }
+
+    /// Runs the queries of `f` on a connection of the pool, outside of any transaction
+    fn run<T, F>(&self, f: F) -> Result<T, DatabaseError>
+    where F: FnOnce(&mut Transaction) -> Result<T, DatabaseError>
+    {
+        let mut connection = self.connection()?;
+        let mut transaction = Transaction {database: self, connection: &mut connection, open: false};
+        f(&mut transaction)
+    }
+}
+
+/// The value of a column
+pub use sqlite::Value;
+
+/// A row returned by [Database::select_rows], with the value of each column
+pub type Row = HashMap<String, Value>;
 
-        // remove trailing comma and space
-        sql_request.pop();
-        sql_request.pop();
+/// Converts a value to the string [Database::request_row] returns
+fn value_to_string(value: Value) -> String {
+    match value {
+        Value::String(v) => v,
+        Value::Integer(v) => v.to_string(),
+        Value::Float(v) => v.to_string(),
+        Value::Binary(v) => String::from_utf8_lossy(&v).to_string(),
+        Value::Null => String::new(),
+    }
+}
+
+/// How a column is compared to a value in a [Filter]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    /// `=`, or `IS` to compare with NULL
+    Equal,
+    /// `!=`, or `IS NOT` to compare with NULL
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    /// `LIKE`, with `%` and `_` as wildcards
+    Like,
+}
 
-        sql_request.push_str(") VALUES (?");
-        for _ in 1..sql_values.len() {
-            sql_request.push_str(", ?");
+impl Comparison {
+    fn operator(&self, value: &Value) -> &'static str {
+        match (self, value) {
+            (Comparison::Equal, Value::Null) => "IS",
+            (Comparison::NotEqual, Value::Null) => "IS NOT",
+            (Comparison::Equal, _) => "=",
+            (Comparison::NotEqual, _) => "!=",
+            (Comparison::Less, _) => "<",
+            (Comparison::LessOrEqual, _) => "<=",
+            (Comparison::Greater, _) => ">",
+            (Comparison::GreaterOrEqual, _) => ">=",
+            (Comparison::Like, _) => "LIKE",
         }
-        sql_request.push(')');
+    }
+}
+
+/// The order of the rows in a [Filter]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
 
-        let mut statement = connection.prepare(&sql_request)?;
+/// The rows a select, update or delete applies to: the conditions they all match, their order and how many of them.
+///
+/// # Example
+/// ```
+/// let filter = Filter::new()
+///     .where_eq("auth_level", 1)
+///     .condition("credits", Comparison::Greater, 100.0)
+///     .order_by("username", Order::Ascending)
+///     .limit(10);
+/// let users = database.select_rows("users", &filter)?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    conditions: Vec<(String, Comparison, Value)>,
+    order: Vec<(String, Order)>,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+#[allow(dead_code)]
+impl Filter {
+    /// Creates a filter matching every row
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    /// Only keeps the rows whose `column` compares to `value`
+    pub fn condition<V: Into<Value>>(mut self, column: &str, comparison: Comparison, value: V) -> Filter {
+        self.conditions.push((column.to_string(), comparison, value.into()));
+        self
+    }
 
-        for (i, value) in sql_values.iter().enumerate() {
-            statement.bind((i + 1, value.as_str()))?;
+    /// Only keeps the rows whose `column` is `value`
+    pub fn where_eq<V: Into<Value>>(self, column: &str, value: V) -> Filter {
+        self.condition(column, Comparison::Equal, value)
+    }
+
+    /// Sorts the rows by `column`, after the columns already given
+    pub fn order_by(mut self, column: &str, order: Order) -> Filter {
+        self.order.push((column.to_string(), order));
+        self
+    }
+
+    /// Keeps at most `limit` rows
+    pub fn limit(mut self, limit: usize) -> Filter {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skips the first `offset` rows
+    pub fn offset(mut self, offset: usize) -> Filter {
+        self.offset = offset;
+        self
+    }
+
+    /// Returns the `WHERE`, `ORDER BY` and `LIMIT` clauses for the quoted columns of the conditions and of the order
+    fn clauses(&self, conditions: &[String], order: &[String]) -> String {
+        let mut sql = String::new();
+        for (i, (column, (_, comparison, value))) in conditions.iter().zip(self.conditions.iter()).enumerate() {
+            sql.push_str(if i == 0 {" WHERE "} else {" AND "});
+            sql.push_str(&format!("{} {} ?", column, comparison.operator(value)));
+        }
+        for (i, (column, (_, order))) in order.iter().zip(self.order.iter()).enumerate() {
+            sql.push_str(if i == 0 {" ORDER BY "} else {", "});
+            sql.push_str(&format!("{} {}", column, if *order == Order::Ascending {"ASC"} else {"DESC"}));
+        }
+        if self.limit.is_some() || self.offset > 0 {
+            // a negative limit means no limit in SQLite
+            sql.push_str(&format!(" LIMIT {} OFFSET {}", self.limit.map(|v| v as i64).unwrap_or(-1), self.offset));
+        }
+        sql
+    }
+
+    fn columns(&self) -> Vec<&str> {
+        self.conditions.iter().map(|(k, _, _)| k.as_str()).chain(self.order.iter().map(|(k, _)| k.as_str())).collect()
+    }
+}
+
+/// The queries run by [Database::transaction] on a single connection, the methods of [Database] run a single query
+/// the same way
+pub struct Transaction<'a> {
+    database: &'a Database,
+    connection: &'a mut PooledConnection,
+    /// Whether the transaction has to be rolled back if it's dropped
+    open: bool,
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if self.open {
+            if let Err(e) = self.connection.connection.execute("ROLLBACK") {
+                log::error!("Error when rolling back a transaction: {}", e);
+            }
+        }
+    }
+}
+
+impl Transaction<'_> {
+    /// Returns the quoted table, the quoted columns and the `WHERE`, `ORDER BY` and `LIMIT` clauses of the filter
+    fn resolve(&mut self, table: &str, columns: &[&str], filter: &Filter) -> Result<(String, Vec<String>, String), DatabaseError> {
+        let filter_columns = filter.columns();
+        let all: Vec<&str> = columns.iter().copied().chain(filter_columns.iter().copied()).collect();
+        let (table, mut quoted) = self.database.identifiers(self.connection, table, &all)?;
+        let filter_quoted = quoted.split_off(columns.len());
+        let (conditions, order) = filter_quoted.split_at(filter.conditions.len());
+        Ok((table, quoted, filter.clauses(conditions, order)))
+    }
+
+    /// Runs SQL statements which don't return rows, like the migrations of the schema
+    pub fn execute(&mut self, sql: &str) -> Result<(), DatabaseError> {
+        self.connection.connection.execute(sql)?;
+        // the tables may have changed
+        self.database.pool.schema.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        Ok(())
+    }
+
+    /// See [Database::select_rows]
+    pub fn select_rows(&mut self, table: &str, filter: &Filter) -> Result<Vec<Row>, DatabaseError> {
+        let (table, _, clauses) = self.resolve(table, &[], filter)?;
+        let statement = self.connection.prepare(&format!("SELECT * FROM {}{}", table, clauses))?;
+        for (i, (_, _, value)) in filter.conditions.iter().enumerate() {
+            statement.bind((i + 1, value))?;
+        }
+        let mut rows = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            let mut row = Row::new();
+            for k in 0..statement.column_count() {
+                row.insert(statement.column_name(k)?.to_string(), statement.read::<Value, _>(k)?);
+            }
+            rows.push(row);
         }
+        Ok(rows)
+    }
 
+    /// See [Database::insert_row]
+    pub fn insert_row(&mut self, table: &str, values: &[(&str, Value)]) -> Result<i64, DatabaseError> {
+        self.insert(table, values, None)?;
+        let statement = self.connection.prepare("SELECT last_insert_rowid()")?;
         statement.next()?;
+        Ok(statement.read::<i64, _>(0)?)
+    }
 
+    /// See [Database::upsert_row]
+    pub fn upsert_row(&mut self, table: &str, values: &[(&str, Value)], conflict_columns: &[&str]) -> Result<(), DatabaseError> {
+        self.insert(table, values, Some(conflict_columns))
+    }
+
+    fn insert(&mut self, table: &str, values: &[(&str, Value)], conflict_columns: Option<&[&str]>) -> Result<(), DatabaseError> {
+        let columns: Vec<&str> = values.iter().map(|(k, _)| *k).chain(conflict_columns.unwrap_or_default().iter().copied()).collect();
+        let (table, mut quoted) = self.database.identifiers(self.connection, table, &columns)?;
+        let conflict = quoted.split_off(values.len());
+        let mut sql_request = match values.is_empty() {
+            true => format!("INSERT INTO {} DEFAULT VALUES", table),
+            false => format!("INSERT INTO {} ({}) VALUES ({})", table, quoted.join(", "), vec!["?"; values.len()].join(", ")),
+        };
+        if conflict_columns.is_some() {
+            let updates: Vec<String> = quoted.iter().filter(|v| !conflict.contains(v)).map(|v| format!("{} = excluded.{}", v, v)).collect();
+            sql_request.push_str(&format!(" ON CONFLICT ({}) ", conflict.join(", ")));
+            sql_request.push_str(&match updates.is_empty() {
+                true => String::from("DO NOTHING"),
+                false => format!("DO UPDATE SET {}", updates.join(", ")),
+            });
+        }
+        let statement = self.connection.prepare(&sql_request)?;
+        for (i, (_, value)) in values.iter().enumerate() {
+            statement.bind((i + 1, value))?;
+        }
+        statement.next()?;
         Ok(())
     }
+
+    /// See [Database::update_rows]
+    pub fn update_rows(&mut self, table: &str, values: &[(&str, Value)], filter: &Filter) -> Result<usize, DatabaseError> {
+        let columns: Vec<&str> = values.iter().map(|(k, _)| *k).collect();
+        let (table, quoted, clauses) = self.resolve(table, &columns, filter)?;
+        let assignments: Vec<String> = quoted.iter().map(|v| format!("{} = ?", v)).collect();
+        let sql_request = format!("UPDATE {} SET {}{}", table, assignments.join(", "), Transaction::row_selection(&table, filter, &clauses));
+        let statement = self.connection.prepare(&sql_request)?;
+        for (i, value) in values.iter().map(|(_, v)| v).chain(filter.conditions.iter().map(|(_, _, v)| v)).enumerate() {
+            statement.bind((i + 1, value))?;
+        }
+        statement.next()?;
+        Ok(self.connection.connection.change_count())
+    }
+
+    /// See [Database::delete_rows]
+    pub fn delete_rows(&mut self, table: &str, filter: &Filter) -> Result<usize, DatabaseError> {
+        let (table, _, clauses) = self.resolve(table, &[], filter)?;
+        let sql_request = format!("DELETE FROM {}{}", table, Transaction::row_selection(&table, filter, &clauses));
+        let statement = self.connection.prepare(&sql_request)?;
+        for (i, (_, _, value)) in filter.conditions.iter().enumerate() {
+            statement.bind((i + 1, value))?;
+        }
+        statement.next()?;
+        Ok(self.connection.connection.change_count())
+    }
+
+    /// SQLite only accepts `ORDER BY` and `LIMIT` in an update or a delete when built with them, the rows are
+    /// selected by rowid instead
+    fn row_selection(table: &str, filter: &Filter, clauses: &str) -> String {
+        match filter.order.is_empty() && filter.limit.is_none() && filter.offset == 0 {
+            true => clauses.to_string(),
+            false => format!(" WHERE rowid IN (SELECT rowid FROM {}{})", table, clauses),
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::database_utils::*;
+
+    /// A database in the temp directory, its files are deleted once it is dropped
+    pub(crate) struct TestDatabase {
+        database: Database,
+        pub filepath: String,
+    }
+
+    impl std::ops::Deref for TestDatabase {
+        type Target = Database;
+
+        fn deref(&self) -> &Database {
+            &self.database
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            self.database.pool.idle.lock().unwrap().clear();
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.filepath, suffix));
+            }
+        }
+    }
+
+    /// Creates a database with the tables of `schema` in the temp directory, `name` has to be unique to the test
+    pub(crate) fn test_database(name: &str, schema: &str) -> TestDatabase {
+        let filepath = std::env::temp_dir().join(format!("webserver-rs-{}-{}.db", name, std::process::id())).to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&filepath);
+        Connection::open(&filepath).unwrap().execute(schema).unwrap();
+        TestDatabase {database: Database::new(&filepath), filepath}
+    }
+
+    /// Creates a database with every migration applied in the temp directory, `name` has to be unique to the test
+    pub(crate) fn migrated_database(name: &str) -> TestDatabase {
+        let database = test_database(name, "");
+        crate::migrations::migrate(&database).unwrap();
+        database
+    }
+
+    fn users_database(name: &str) -> TestDatabase {
+        test_database(name, "CREATE TABLE users(username TEXT PRIMARY KEY, hash TEXT, credits DOUBLE, auth_level INTEGER, email TEXT, sessionID text, sessionExpires BIGINT UNSIGNED, mcuuid TEXT)")
+    }
+
     #[test]
     fn test_request_row() {
-        let database = Database::new("database.db");
+        let database = users_database("request_row");
         let row = database.request_row("users", "username", "admin");
-        assert_eq!(HashMap::from([]), row)
+        assert_eq!(HashMap::from([]), row.unwrap())
     }
 
     #[test]
@@ -88,7 +643,101 @@ mod tests {
             .map(|(k, v)| (k.to_string(), v.to_string()))
             .collect();
 
-        let database = Database::new("database.db");
-        database.push_data("users", values);
+        let database = users_database("push_data");
+        database.push_data("users", values).unwrap();
+        let row = database.request_row("users", "username", "user1").unwrap();
+        assert_eq!(Some(&String::from("user1@example.com")), row.get("email"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_identifiers() {
+        let database = users_database("identifiers");
+        assert!(matches!(database.request_row("users; DROP TABLE users", "username", "admin"), Err(DatabaseError::UnknownTable(_))));
+        assert!(matches!(database.request_row("requests_\"x", "path", "/"), Err(DatabaseError::UnknownTable(_))));
+        assert!(matches!(database.request_row("users", "username = username OR 1", "admin"), Err(DatabaseError::UnknownColumn(..))));
+        database.push_data("USERS", HashMap::from([(String::from("UserName"), String::from("user1"))])).unwrap();
+
+        // a column added after the table was cached is found
+        let connection = database.connection().unwrap();
+        connection.connection.execute("ALTER TABLE users ADD COLUMN \"quoted \"\" name\" TEXT").unwrap();
+        drop(connection);
+        database.push_data("users", HashMap::from([(String::from("username"), String::from("user2")), (String::from("quoted \" name"), String::from("value"))])).unwrap();
+        assert_eq!(Some(&String::from("value")), database.request_row("users", "Quoted \" Name", "value").unwrap().get("quoted \" name"));
+    }
+
+    #[test]
+    fn test_select_rows() {
+        let database = users_database("select_rows");
+        for (username, credits, auth_level) in [("user1", 150.0, 1), ("user2", 20.5, 1), ("admin", 0.0, 3)] {
+            database.insert_row("users", &[("username", Value::from(username)), ("credits", Value::from(credits)), ("auth_level", Value::from(auth_level as i64))]).unwrap();
+        }
+        database.update_rows("users", &[("hash", Value::from(vec![0u8, 159, 146, 150]))], &Filter::new().where_eq("username", "admin")).unwrap();
+
+        let filter = Filter::new().where_eq("auth_level", 1).order_by("credits", Order::Descending);
+        let users: Vec<Value> = database.select_rows("users", &filter).unwrap().into_iter().map(|v| v["username"].clone()).collect();
+        assert_eq!(vec![Value::from("user1"), Value::from("user2")], users);
+
+        let admin = database.select_rows("users", &Filter::new().condition("credits", Comparison::Less, 1.0)).unwrap().pop().unwrap();
+        assert_eq!((&Value::Integer(3), &Value::Binary(vec![0, 159, 146, 150]), &Value::Null), (&admin["auth_level"], &admin["hash"], &admin["email"]));
+        assert_eq!(1, database.select_rows("users", &Filter::new().where_eq("email", ()).order_by("username", Order::Ascending).limit(1).offset(2)).unwrap().len());
+    }
+
+    #[test]
+    fn test_update_delete_upsert() {
+        let database = users_database("update_delete");
+        for username in ["user1", "user2", "user3"] {
+            database.insert_row("users", &[("username", Value::from(username)), ("credits", Value::from(10.0))]).unwrap();
+        }
+        let filter = Filter::new().condition("username", Comparison::Like, "user%").order_by("username", Order::Descending).limit(2);
+        assert_eq!(2, database.update_rows("users", &[("credits", Value::from(0.0))], &filter).unwrap());
+        assert_eq!("10", database.request_row("users", "username", "user1").unwrap()["credits"]);
+        assert_eq!(1, database.delete_rows("users", &Filter::new().where_eq("username", "user3")).unwrap());
+
+        database.upsert_row("users", &[("username", Value::from("user1")), ("email", Value::from("user1@example.com"))], &["username"]).unwrap();
+        database.upsert_row("users", &[("username", Value::from("user4"))], &["username"]).unwrap();
+        let user1 = database.request_row("users", "username", "user1").unwrap();
+        assert_eq!(("user1@example.com", "10"), (user1["email"].as_str(), user1["credits"].as_str()));
+        assert_eq!(3, database.select_rows("users", &Filter::new()).unwrap().len());
+    }
+
+    #[test]
+    fn test_transaction() {
+        let database = users_database("transaction");
+        let result = database.transaction(|t| {
+            t.insert_row("users", &[("username", Value::from("user1"))])?;
+            t.insert_row("users", &[("username", Value::from("user1"))])
+        });
+        assert!(matches!(result, Err(DatabaseError::Sqlite(_))));
+        let result = std::panic::catch_unwind(|| database.transaction(|t| -> Result<(), DatabaseError> {
+            t.insert_row("users", &[("username", Value::from("user2"))])?;
+            panic!("the transaction is rolled back");
+        }));
+        assert!(result.is_err());
+        assert!(database.select_rows("users", &Filter::new()).unwrap().is_empty());
+
+        let rowid = database.transaction(|t| {
+            t.insert_row("users", &[("username", Value::from("user3"))])?;
+            t.insert_row("users", &[("username", Value::from("user4"))])
+        }).unwrap();
+        assert_eq!(2, rowid);
+        assert_eq!(2, database.select_rows("users", &Filter::new()).unwrap().len());
+    }
+
+    #[test]
+    fn test_pool() {
+        let database = users_database("pool");
+        let pool = database.clone();
+        std::thread::spawn(move || pool.push_data("users", HashMap::from([(String::from("username"), String::from("user1"))])).unwrap()).join().unwrap();
+        for _ in 0..3 {
+            assert!(database.request_row("users", "username", "user1").unwrap().contains_key("email"));
+        }
+        // the connection opened by the other thread was reused with its statements
+        let mut idle = database.pool.idle.lock().unwrap();
+        assert_eq!(1, idle.len());
+        // the insert, the rowid, the select and the two schema lookups
+        assert_eq!(5, idle[0].statements.len());
+        let journal_mode = idle[0].prepare("PRAGMA journal_mode").unwrap();
+        assert_eq!(sqlite::State::Row, journal_mode.next().unwrap());
+        assert_eq!("wal", journal_mode.read::<String, _>(0).unwrap());
+    }
+}
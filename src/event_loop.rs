@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::http2;
+use crate::request_handler::{IncomingRequest, ParsedRequest, handle_connection};
+use crate::server_config::ServerConfig;
+use crate::thread_pool::ThreadPool;
+
+/// How often the deadlines of the connections are checked
+const TICK: Duration = Duration::from_millis(100);
+/// The most events handled by a call to `epoll_wait`
+const MAX_EVENTS: usize = 1024;
+/// A request whose body has more than this left to receive once its head is complete is looked at by the blocking
+/// pool first, the body of a reverse proxy route is then streamed to the upstream instead of being buffered
+const STREAMED_BODY: usize = 65536;
+
+/// The networking core of the server: a single thread waiting on epoll for every connection of the listener.
+///
+/// A connection costs nothing but its buffer while the request is received, so slow or idle clients don't hold
+/// any thread. Once a request is complete it's handed to [handle_connection] on a pool of blocking threads, which
+/// runs the scripts and the SQLite queries, and the response comes back to the event loop to be sent.
+/// The routes taking over the connection (proxy, WebSocket, Server-Sent Events) and the HTTP/2 connections keep
+/// their stream and are not seen by the event loop again. The large bodies of the proxy routes are not received by
+/// the event loop, the proxy streams them to the upstream.
+///
+/// The deadlines of [RequestLimits](crate::request_handler::RequestLimits) are checked by the event loop, the size
+/// of the blocking pool, shared with the HTTP/2 connections, can be set in the server config:
+/// ```json
+/// {
+///     "blocking_threads":16
+/// }
+/// ```
+pub struct EventLoop {
+    epoll: OwnedFd,
+    listener: TcpListener,
+    waker: Arc<Waker>,
+    connections: HashMap<RawFd, Connection>,
+    sender: mpsc::Sender<Handoff>,
+    responses: mpsc::Receiver<Handoff>,
+    pool: Arc<ThreadPool>,
+    config: Arc<ServerConfig>,
+}
+
+/// A connection waiting on the event loop
+struct Connection {
+    stream: TcpStream,
+    state: State,
+    /// When the connection times out if nothing more is received or sent
+    deadline: Instant,
+}
+
+enum State {
+    /// Receiving the request, its head has to be complete before `head_deadline`.
+    /// `inspected` is set once the blocking pool saw the head of a request with a large body and let it be received.
+    Reading { buffer: Vec<u8>, head_deadline: Instant, inspected: bool },
+    /// Sending the response built by the blocking pool
+    Writing { response: Vec<u8>, written: usize },
+}
+
+/// What happened after reading from a connection
+enum Progress {
+    /// The request is incomplete
+    Pending,
+    /// The client left or the connection failed, there is nothing to answer
+    Closed,
+    /// The client sent the HTTP/2 preface
+    H2c,
+    /// The head of a request with a large body is complete, the blocking pool decides if the body is received
+    Head,
+    /// The request is complete, or was refused
+    Done (ParsedRequest),
+}
+
+/// What the blocking pool gives back to the event loop
+enum Handoff {
+    /// The response to send on the stream
+    Respond (TcpStream, Vec<u8>),
+    /// The request isn't for a reverse proxy route, the rest of its body is received by the event loop
+    Receive (TcpStream, Vec<u8>),
+}
+
+/// Wakes a thread waiting on epoll or poll up from the blocking pool when a response is ready
+pub struct Waker(OwnedFd);
+
+impl Waker {
+    pub fn new() -> std::io::Result<Waker> {
+        match unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) } {
+            -1 => Err(std::io::Error::last_os_error()),
+            v => Ok(Waker(unsafe { OwnedFd::from_raw_fd(v) })),
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+
+    pub fn wake(&self) {
+        let value: u64 = 1;
+        // the counter only has to be non zero, a failed write means it's already full
+        unsafe { libc::write(self.0.as_raw_fd(), &value as *const u64 as *const libc::c_void, 8) };
+    }
+
+    pub fn reset(&self) {
+        let mut value: u64 = 0;
+        unsafe { libc::read(self.0.as_raw_fd(), &mut value as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+impl EventLoop {
+    /// Creates the event loop of the listener, the requests are handled on `pool`
+    pub fn new(listener: TcpListener, pool: &Arc<ThreadPool>, config: &Arc<ServerConfig>) -> std::io::Result<EventLoop> {
+        listener.set_nonblocking(true)?;
+        let epoll = match unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) } {
+            -1 => return Err(std::io::Error::last_os_error()),
+            v => unsafe { OwnedFd::from_raw_fd(v) },
+        };
+        let (sender, responses) = mpsc::channel();
+        let event_loop = EventLoop {
+            epoll,
+            listener,
+            waker: Arc::new(Waker::new()?),
+            connections: HashMap::new(),
+            sender,
+            responses,
+            pool: pool.clone(),
+            config: config.clone(),
+        };
+        event_loop.control(libc::EPOLL_CTL_ADD, event_loop.listener.as_raw_fd(), libc::EPOLLIN)?;
+        event_loop.control(libc::EPOLL_CTL_ADD, event_loop.waker.fd(), libc::EPOLLIN)?;
+        Ok(event_loop)
+    }
+
+    /// Serves the connections of the listener, only returns if epoll fails
+    pub fn run(mut self) -> std::io::Error {
+        let mut events = vec![libc::epoll_event {events: 0, u64: 0}; MAX_EVENTS];
+        let mut last_check = Instant::now();
+        loop {
+            let count = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), MAX_EVENTS as i32, TICK.as_millis() as i32) };
+            if count < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return error;
+            }
+            for event in &events[..count as usize] {
+                let fd = event.u64 as RawFd;
+                if fd == self.listener.as_raw_fd() {
+                    self.accept();
+                } else if fd == self.waker.fd() {
+                    self.waker.reset();
+                    while let Ok(handoff) = self.responses.try_recv() {
+                        match handoff {
+                            Handoff::Respond(stream, response) => self.respond(stream, response),
+                            Handoff::Receive(stream, buffer) => self.receive(stream, buffer),
+                        }
+                    }
+                } else if let Some(connection) = self.connections.remove(&fd) {
+                    self.ready(connection);
+                }
+            }
+            if last_check.elapsed() >= TICK {
+                self.check_deadlines();
+                last_check = Instant::now();
+            }
+        }
+    }
+
+    fn control(&self, operation: libc::c_int, fd: RawFd, events: libc::c_int) -> std::io::Result<()> {
+        let mut event = libc::epoll_event {events: events as u32, u64: fd as u64};
+        match unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), operation, fd, &mut event) } {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((v, _)) => v,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Error when accepting connection: {}", e);
+                    return;
+                },
+            };
+            let limits = &self.config.request_limits;
+            let now = Instant::now();
+            let connection = Connection {
+                state: State::Reading {buffer: Vec::new(), head_deadline: now + limits.header_timeout, inspected: false},
+                deadline: now + limits.read_timeout.min(limits.header_timeout),
+                stream,
+            };
+            let fd = connection.stream.as_raw_fd();
+            match connection.stream.set_nonblocking(true).and_then(|_| self.control(libc::EPOLL_CTL_ADD, fd, libc::EPOLLIN)) {
+                Ok(()) => {self.connections.insert(fd, connection);},
+                Err(e) => warn!("Error when watching connection: {}", e),
+            };
+        }
+    }
+
+    fn ready(&mut self, mut connection: Connection) {
+        let fd = connection.stream.as_raw_fd();
+        match connection.state {
+            State::Reading {..} => match connection.receive(&self.config) {
+                Progress::Pending => {self.connections.insert(fd, connection);},
+                Progress::Closed => self.close(connection),
+                Progress::H2c => self.upgrade(connection),
+                Progress::Head => self.inspect(connection),
+                Progress::Done(request) => self.dispatch(connection, request),
+            },
+            State::Writing {..} => match connection.send(self.config.request_limits.write_timeout) {
+                Ok(false) => {self.connections.insert(fd, connection);},
+                Ok(true) => self.close(connection),
+                Err(e) => {
+                    info!("Error when writing data to stream: {}", e);
+                    self.close(connection);
+                },
+            },
+        }
+    }
+
+    /// Removes the connection from the event loop and gives its stream back in blocking mode
+    fn release(&self, connection: Connection) -> Option<TcpStream> {
+        let stream = connection.stream;
+        if let Err(e) = self.control(libc::EPOLL_CTL_DEL, stream.as_raw_fd(), 0).and_then(|_| stream.set_nonblocking(false)) {
+            info!("Error when releasing connection: {}", e);
+            return None;
+        }
+        Some(stream)
+    }
+
+    fn close(&self, connection: Connection) {
+        let _ = self.control(libc::EPOLL_CTL_DEL, connection.stream.as_raw_fd(), 0);
+    }
+
+    /// Hands a complete or refused request to the blocking pool, the response is sent back through `responses`
+    fn dispatch(&self, connection: Connection, request: ParsedRequest) {
+        let stream = match self.release(connection) {
+            Some(v) => v,
+            None => return,
+        };
+        let (sender, waker, config) = (self.sender.clone(), self.waker.clone(), self.config.clone());
+        self.pool.execute(move || {
+            if let Some((stream, response)) = handle_connection(stream, request, &config) {
+                if sender.send(Handoff::Respond(stream, response)).is_ok() {
+                    waker.wake();
+                }
+            }
+        });
+    }
+
+    /// Hands the head of a request with a large body to the blocking pool: the request is handled right away if it
+    /// goes to a reverse proxy route, which streams the body, otherwise the body is received by the event loop
+    fn inspect(&self, mut connection: Connection) {
+        let buffer = match &mut connection.state {
+            State::Reading {buffer, ..} => std::mem::take(buffer),
+            State::Writing {..} => return,
+        };
+        let stream = match self.release(connection) {
+            Some(v) => v,
+            None => return,
+        };
+        let (sender, waker, config) = (self.sender.clone(), self.waker.clone(), self.config.clone());
+        self.pool.execute(move || {
+            let handoff = match IncomingRequest::from_head(&buffer, stream.peer_addr().ok(), stream.local_addr().ok()) {
+                ParsedRequest::Ok(v) if !config.database.is_proxy_route(&v) => Some(Handoff::Receive(stream, buffer)),
+                request => handle_connection(stream, request, &config).map(|(stream, response)| Handoff::Respond(stream, response)),
+            };
+            if let Some(handoff) = handoff {
+                if sender.send(handoff).is_ok() {
+                    waker.wake();
+                }
+            }
+        });
+    }
+
+    /// Watches again a connection whose head was inspected, to receive the rest of its body
+    fn receive(&mut self, stream: TcpStream, buffer: Vec<u8>) {
+        let fd = stream.as_raw_fd();
+        let now = Instant::now();
+        let connection = Connection {
+            stream,
+            state: State::Reading {buffer, head_deadline: now, inspected: true},
+            deadline: now + self.config.request_limits.read_timeout,
+        };
+        match connection.stream.set_nonblocking(true).and_then(|_| self.control(libc::EPOLL_CTL_ADD, fd, libc::EPOLLIN)) {
+            Ok(()) => self.ready(connection),
+            Err(e) => info!("Error when watching connection: {}", e),
+        }
+    }
+
+    /// An HTTP/2 connection stays open, it gets its own thread to read its frames and hands its requests to the pool
+    fn upgrade(&self, mut connection: Connection) {
+        let received = match &mut connection.state {
+            State::Reading {buffer, ..} => std::mem::take(buffer),
+            State::Writing {..} => return,
+        };
+        let slot = match self.config.http2.reserve() {
+            Some(v) => v,
+            None => {
+                warn!("Too many HTTP/2 connections open, closing the connection");
+                return self.close(connection);
+            }
+        };
+        if let Some(stream) = self.release(connection) {
+            let (pool, config) = (self.pool.clone(), self.config.clone());
+            thread::spawn(move || {
+                http2::serve_h2c(stream, received, &pool, &config);
+                drop(slot);
+            });
+        }
+    }
+
+    fn respond(&mut self, stream: TcpStream, response: Vec<u8>) {
+        let fd = stream.as_raw_fd();
+        let connection = Connection {
+            stream,
+            state: State::Writing {response, written: 0},
+            deadline: Instant::now() + self.config.request_limits.write_timeout,
+        };
+        match connection.stream.set_nonblocking(true).and_then(|_| self.control(libc::EPOLL_CTL_ADD, fd, libc::EPOLLOUT)) {
+            Ok(()) => self.ready(connection),
+            Err(e) => info!("Error when watching connection: {}", e),
+        }
+    }
+
+    /// Answers the requests which are too slow to arrive with an error 408 and drops the responses which are too slow to be sent
+    fn check_deadlines(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<RawFd> = self.connections.iter()
+            .filter(|(_, connection)| connection.deadline <= now)
+            .map(|(fd, _)| *fd)
+            .collect();
+        for fd in expired {
+            let connection = match self.connections.remove(&fd) {
+                Some(v) => v,
+                None => continue,
+            };
+            match connection.state {
+                State::Reading {..} => self.dispatch(connection, ParsedRequest::Timeout),
+                State::Writing {..} => {
+                    info!("Timeout when writing data to stream");
+                    self.close(connection);
+                },
+            }
+        }
+    }
+}
+
+impl Connection {
+    /// Reads what the client sent until the request is complete or the stream would block
+    fn receive(&mut self, config: &ServerConfig) -> Progress {
+        let limits = &config.request_limits;
+        let (buffer, head_deadline, inspected) = match &mut self.state {
+            State::Reading {buffer, head_deadline, inspected} => (buffer, *head_deadline, *inspected),
+            State::Writing {..} => return Progress::Closed,
+        };
+        let mut chunk = [0u8; 16384];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) if buffer.is_empty() => return Progress::Closed,
+                Ok(0) => return Progress::Done(ParsedRequest::BadRequest),
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Progress::Pending,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("Error when reading request: {}", e);
+                    return Progress::Closed;
+                },
+            }
+            if config.http2.h2c && http2::is_preface(buffer) {
+                return Progress::H2c;
+            }
+            // a client trickling one byte at a time has to send the whole head within `header_timeout`
+            self.deadline = match IncomingRequest::request_length(buffer, limits) {
+                Ok(Some(length)) if buffer.len() >= length => {
+                    let (remote_addr, local_addr) = (self.stream.peer_addr().ok(), self.stream.local_addr().ok());
+                    return Progress::Done(IncomingRequest::from_bytes(&buffer[..length], remote_addr, local_addr));
+                },
+                Ok(Some(length)) if !inspected && length - buffer.len() > STREAMED_BODY => return Progress::Head,
+                Ok(Some(_)) => Instant::now() + limits.read_timeout,
+                Ok(None) => (Instant::now() + limits.read_timeout).min(head_deadline),
+                Err(e) => return Progress::Done(e),
+            };
+        }
+    }
+
+    /// Writes the response until it's sent, returns false if the stream would block before.
+    ///
+    /// The client has `write_timeout` to accept more of the response each time some of it is written.
+    fn send(&mut self, write_timeout: Duration) -> std::io::Result<bool> {
+        let (response, written) = match &mut self.state {
+            State::Writing {response, written} => (response, written),
+            State::Reading {..} => return Ok(true),
+        };
+        while *written < response.len() {
+            match self.stream.write(&response[*written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    *written += n;
+                    self.deadline = Instant::now() + write_timeout;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_loop::*;
+
+    #[test]
+    fn test_slow_client() {
+        let config = json::object!{
+            "database": "data/database.db",
+            "header_timeout": 0.5,
+        };
+        let config = Arc::new(ServerConfig::from_config(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let event_loop = EventLoop::new(listener, &Arc::new(ThreadPool::new(1)), &config).unwrap();
+        thread::spawn(move || event_loop.run());
+
+        // the head of the slow client never ends, it doesn't keep the only blocking thread from serving the other one
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n").unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /missing-page HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        let started = Instant::now();
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_large_body() {
+        let config = json::object!{
+            "database": "data/database.db",
+        };
+        let config = Arc::new(ServerConfig::from_config(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let event_loop = EventLoop::new(listener, &Arc::new(ThreadPool::new(1)), &config).unwrap();
+        thread::spawn(move || event_loop.run());
+
+        // the head is looked at by the blocking pool, the body isn't for a proxy route and is received by the event loop
+        let body = vec![b'x'; STREAMED_BODY * 2];
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(format!("POST /missing-page HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        client.write_all(&body).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+
+    #[test]
+    fn test_h2c_limit() {
+        let config = json::object!{
+            "database": "data/database.db",
+            "h2c": true,
+            "http2_max_connections": 1,
+        };
+        let config = Arc::new(ServerConfig::from_config(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let event_loop = EventLoop::new(listener, &Arc::new(ThreadPool::new(1)), &config).unwrap();
+        thread::spawn(move || event_loop.run());
+
+        // the first connection gets the SETTINGS of the server, the second one is closed
+        let preface = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0";
+        let mut first = TcpStream::connect(address).unwrap();
+        first.write_all(preface).unwrap();
+        let mut settings = [0u8; 9];
+        first.read_exact(&mut settings).unwrap();
+        assert_eq!(settings[3], 0x4);
+        let mut second = TcpStream::connect(address).unwrap();
+        second.write_all(preface).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(second.read(&mut settings).unwrap(), 0);
+    }
+}
@@ -0,0 +1,217 @@
+use std::net::TcpStream;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::Child;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::request_handler::{HTTPCode, IncomingRequest, ServerStatus};
+use crate::script_runner::{ScriptLimits, ScriptRunner, kill_group};
+use crate::thread_pool::ConnectionLimit;
+
+/// Streams the output of a script as Server-Sent Events for the routes whose callback starts with `sse://`:
+/// ```text
+/// sse://data/pages/sse/flipbot.py
+/// ```
+/// The script is started with the request as its first argument, like the other scripts, and the `Last-Event-ID` header
+/// of a reconnecting client in its `LAST_EVENT_ID` environment variable. Every line the script writes on its stdout is sent
+/// as the data of an event, the lines starting with `id:`, `event:` or `retry:` set that field of the next event instead:
+/// ```text
+/// id: 42
+/// {"status":"running"}
+/// ```
+/// is sent as:
+/// ```text
+/// id: 42
+/// data: {"status":"running"}
+///
+/// ```
+/// The connection is closed when the script exits and the script is killed when the client disconnects.
+///
+/// Each stream runs in its own thread so that it doesn't hold a thread of the pool, a comment is sent every `sse_keep_alive`
+/// seconds to keep the connection open and at most `sse_max_connections` streams are open at once, the next clients get
+/// an error 503:
+/// ```json
+/// {
+///     "sse_keep_alive":15,
+///     "sse_max_connections":64
+/// }
+/// ```
+pub struct EventStreamServer {
+    keep_alive: Duration,
+    connections: ConnectionLimit,
+}
+
+impl EventStreamServer {
+    /// Creates the server with the settings of the server config
+    pub fn from_config(config: &HashMap<String, String>) -> EventStreamServer {
+        EventStreamServer {
+            keep_alive: config.get("sse_keep_alive")
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| *v > 0.0)
+                .map(Duration::from_secs_f64)
+                .unwrap_or(Duration::from_secs(15)),
+            connections: ConnectionLimit::new(config.get("sse_max_connections").and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(64)),
+        }
+    }
+
+    /// Returns true if the callback of a route is a Server-Sent Events endpoint
+    pub fn is_endpoint(callback: &str) -> bool {
+        callback.starts_with("sse://")
+    }
+
+    /// Starts the script of the endpoint, sends the headers of the stream and starts the thread forwarding the events.
+    ///
+    /// Nothing is written to the client when an error is returned: `Err503` if too many streams are open
+    /// and `InternalError` if the script could not be started.
+    pub fn accept(&self, stream: &TcpStream, incoming: &IncomingRequest, callback: &str, script_runner: &ScriptRunner, limits: &ScriptLimits) -> ServerStatus<()> {
+        let slot = match self.connections.reserve() {
+            Some(v) => v,
+            None => {
+                warn!("Too many event streams open, refusing {}", incoming.path());
+                return ServerStatus::Error(HTTPCode::Err503);
+            }
+        };
+        let script = match callback.strip_prefix("sse://") {
+            Some(v) => v,
+            None => return ServerStatus::InternalError,
+        };
+        let env: Vec<(String, String)> = match incoming.headers().get("last-event-id") {
+            Some(v) => vec![(String::from("LAST_EVENT_ID"), v.trim().to_string())],
+            None => Vec::new(),
+        };
+        let mut child = match script_runner.spawn_script(script, &incoming.as_json(), &env, limits) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}", e);
+                return ServerStatus::InternalError;
+            }
+        };
+
+        let headers = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+        let mut client = stream;
+        let writer = client.write_all(headers.as_bytes()).and_then(|_| client.flush()).and_then(|_| stream.try_clone());
+        let writer = match writer {
+            Ok(v) => v,
+            Err(e) => {
+                info!("Error when opening the event stream: {}", e);
+                kill_group(&mut child);
+                return ServerStatus::Ok(());
+            }
+        };
+        let keep_alive = self.keep_alive;
+        debug!("Event stream opened on {}", callback);
+        thread::spawn(move || {
+            forward_events(child, writer, keep_alive);
+            drop(slot);
+        });
+        ServerStatus::Ok(())
+    }
+}
+
+/// Sends the lines of the script to the client as events until the script exits or the client disconnects
+fn forward_events(mut child: Child, mut client: TcpStream, keep_alive: Duration) {
+    let (sender, lines) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let mut fields = String::new();
+    loop {
+        let chunk = match lines.recv_timeout(keep_alive) {
+            Ok(line) => match format_line(&line) {
+                EventLine::Field(v) => {
+                    fields.push_str(&v);
+                    continue;
+                },
+                EventLine::Data(v) => format!("{}{}", std::mem::take(&mut fields), v),
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => String::from(": keep-alive\n\n"),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = client.write_all(chunk.as_bytes()).and_then(|_| client.flush()) {
+            debug!("Event stream client disconnected: {}", e);
+            break;
+        }
+    }
+    kill_group(&mut child);
+    let _ = client.shutdown(std::net::Shutdown::Both);
+    debug!("Event stream closed");
+}
+
+/// A line of the script, formatted for the event stream
+#[derive(Debug, PartialEq)]
+enum EventLine {
+    /// A field of the next event
+    Field (String),
+    /// The data ending an event
+    Data (String),
+}
+
+fn format_line(line: &str) -> EventLine {
+    for field in ["id", "event", "retry"] {
+        if let Some(value) = line.strip_prefix(field).and_then(|v| v.strip_prefix(':')) {
+            // a line break would end the event early
+            return EventLine::Field(format!("{}: {}\n", field, value.trim().replace(['\r', '\n'], "")));
+        }
+    }
+    EventLine::Data(format!("data: {}\n\n", line.trim_end_matches('\r')))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_stream::*;
+    use crate::request_handler::ParsedRequest;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(format_line("id: 42"), EventLine::Field(String::from("id: 42\n")));
+        assert_eq!(format_line("event:status"), EventLine::Field(String::from("event: status\n")));
+        assert_eq!(format_line("{\"id\": 1}"), EventLine::Data(String::from("data: {\"id\": 1}\n\n")));
+        assert_eq!(format_line("identity"), EventLine::Data(String::from("data: identity\n\n")));
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let script = std::env::temp_dir().join(format!("webserver-rs-sse-{}.py", std::process::id()));
+        std::fs::write(&script, "import os, time\nprint('id: 2', flush=True)\nprint('resumed after ' + os.environ['LAST_EVENT_ID'], flush=True)\ntime.sleep(0.3)\nprint('done')").unwrap();
+        let callback = format!("sse://{}", script.to_str().unwrap());
+        let config = HashMap::from([(String::from("sse_keep_alive"), String::from("0.1")), (String::from("sse_max_connections"), String::from("1"))]);
+        let server = EventStreamServer::from_config(&config);
+        let runner = ScriptRunner::from_config(&HashMap::new(), &json::JsonValue::Null);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let incoming = match IncomingRequest::from_bytes(b"GET /events HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 1\r\n\r\n", None, None) {
+            ParsedRequest::Ok(v) => v,
+            _ => panic!("invalid test request"),
+        };
+        assert!(matches!(server.accept(&stream, &incoming, &callback, &runner, &ScriptLimits::default()), ServerStatus::Ok(())));
+        // the only place is taken until the stream is closed
+        assert!(matches!(server.accept(&stream, &incoming, &callback, &runner, &ScriptLimits::default()), ServerStatus::Error(HTTPCode::Err503)));
+        drop(stream);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let (headers, events) = response.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains("Content-Type: text/event-stream"));
+        assert!(events.contains(": keep-alive\n\n"));
+        assert_eq!(events.replace(": keep-alive\n\n", ""), "id: 2\ndata: resumed after 1\n\ndata: done\n\n");
+        while server.connections.reserve().is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
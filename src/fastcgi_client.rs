@@ -0,0 +1,286 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::script_runner::{ScriptError, ScriptLimits};
+
+const FCGI_VERSION_1: u8 = 1;
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+const FCGI_RESPONDER: u16 = 1;
+const FCGI_REQUEST_COMPLETE: u8 = 0;
+/// The only request sent on a connection, the connection is closed after each request
+const REQUEST_ID: u16 = 1;
+/// The biggest content a single record can hold
+const MAX_RECORD_CONTENT: usize = 65535;
+
+/// A FastCGI application referenced by the callback of a route:
+/// ```text
+/// fcgi://127.0.0.1:9000#/var/www/index.php
+/// fcgi://unix:/run/php/php-fpm.sock#/var/www/index.php
+/// fcgi://127.0.0.1:9001
+/// ```
+/// The part after `#` is sent as `SCRIPT_FILENAME`, it is only needed by applications serving several scripts like PHP-FPM.
+#[derive(Debug, PartialEq)]
+pub struct FastCgiEndpoint {
+    pub address: FastCgiAddress,
+    pub script_filename: String,
+}
+
+/// The socket a FastCGI application listens on
+#[derive(Debug, PartialEq)]
+pub enum FastCgiAddress {
+    Tcp (String),
+    Unix (String),
+}
+
+impl FastCgiEndpoint {
+    /// Parses the part of a callback after `fcgi://`, returns `None` if there is no address
+    pub fn parse(endpoint: &str) -> Option<FastCgiEndpoint> {
+        let (address, script_filename) = endpoint.split_once('#').unwrap_or((endpoint, ""));
+        let address = match address.strip_prefix("unix:") {
+            Some(v) if !v.is_empty() => FastCgiAddress::Unix(v.to_string()),
+            Some(_) => return None,
+            None if !address.is_empty() => FastCgiAddress::Tcp(address.to_string()),
+            None => return None,
+        };
+        Some(FastCgiEndpoint {address, script_filename: script_filename.to_string()})
+    }
+}
+
+/// The connections a FastCGI request can be sent over
+trait Connection: Read + Write {}
+impl Connection for TcpStream {}
+impl Connection for UnixStream {}
+
+/// Sends a request to a FastCGI responder and returns the CGI response it wrote on its stdout.
+///
+/// `params` are the CGI meta-variables of the request and `stdin` its body, the timeout and the output size of the `limits` are
+/// applied to the exchange, what the application writes on its stderr is logged.
+pub fn run_fastcgi(endpoint: &FastCgiEndpoint, params: &[(String, String)], stdin: &[u8], limits: &ScriptLimits) -> Result<Vec<u8>, ScriptError> {
+    let mut connection = match connect(&endpoint.address, limits.timeout) {
+        Ok(v) => v,
+        Err(e) => return Err(map_io_error(e, &endpoint.address)),
+    };
+    match exchange(&mut *connection, params, stdin, limits.output_size) {
+        Ok(v) => Ok(v),
+        Err(ExchangeError::Io(e)) => Err(map_io_error(e, &endpoint.address)),
+        Err(ExchangeError::Script(e)) => Err(e),
+    }
+}
+
+/// Opens a connection to the application with the read and write timeouts set
+fn connect(address: &FastCgiAddress, timeout: Option<Duration>) -> std::io::Result<Box<dyn Connection>> {
+    match address {
+        FastCgiAddress::Tcp(v) => {
+            let socket_address = match v.to_socket_addrs()?.next() {
+                Some(v) => v,
+                None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "the address could not be resolved")),
+            };
+            let stream = match timeout {
+                Some(t) => TcpStream::connect_timeout(&socket_address, t)?,
+                None => TcpStream::connect(socket_address)?,
+            };
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+            Ok(Box::new(stream))
+        },
+        FastCgiAddress::Unix(v) => {
+            let stream = UnixStream::connect(v)?;
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+            Ok(Box::new(stream))
+        },
+    }
+}
+
+/// The errors of [exchange], io errors are converted by [map_io_error] once the address is known
+enum ExchangeError {
+    Io (std::io::Error),
+    Script (ScriptError),
+}
+
+impl From<std::io::Error> for ExchangeError {
+    fn from(e: std::io::Error) -> ExchangeError {
+        ExchangeError::Io(e)
+    }
+}
+
+/// Writes the whole request and reads the records sent back until the end of the request
+fn exchange(connection: &mut dyn Connection, params: &[(String, String)], stdin: &[u8], max_output: Option<usize>) -> Result<Vec<u8>, ExchangeError> {
+    let mut begin_request = FCGI_RESPONDER.to_be_bytes().to_vec();
+    begin_request.extend_from_slice(&[0; 6]); // flags (don't keep the connection) and reserved bytes
+    write_record(connection, FCGI_BEGIN_REQUEST, &begin_request)?;
+
+    let mut encoded_params = Vec::new();
+    for (name, value) in params {
+        encode_name_value(&mut encoded_params, name.as_bytes(), value.as_bytes());
+    }
+    write_stream(connection, FCGI_PARAMS, &encoded_params)?;
+    write_stream(connection, FCGI_STDIN, stdin)?;
+    connection.flush()?;
+
+    let mut stdout = Vec::new();
+    loop {
+        let (record_type, content) = read_record(connection)?;
+        match record_type {
+            FCGI_STDOUT => {
+                stdout.extend_from_slice(&content);
+                if max_output.is_some_and(|v| stdout.len() > v) {
+                    return Err(ExchangeError::Script(ScriptError::OutputTooLarge));
+                }
+            },
+            FCGI_STDERR if !content.is_empty() => {
+                warn!("FastCGI application: {}", String::from_utf8_lossy(&content).trim_end());
+            },
+            FCGI_END_REQUEST => {
+                return match content.get(4) {
+                    Some(&FCGI_REQUEST_COMPLETE) => Ok(stdout),
+                    _ => Err(ExchangeError::Script(ScriptError::Failed(String::from("The FastCGI application rejected the request")))),
+                };
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Converts an io error to a [ScriptError], the timeouts become [ScriptError::TimedOut]
+fn map_io_error(error: std::io::Error, address: &FastCgiAddress) -> ScriptError {
+    match error.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => ScriptError::TimedOut,
+        _ => ScriptError::Failed(format!("Error when talking to the FastCGI application {:?}: {}", address, error)),
+    }
+}
+
+/// Appends a name-value pair to `buffer`, lengths under 128 are written on one byte, the others on four
+fn encode_name_value(buffer: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    for length in [name.len(), value.len()] {
+        if length < 128 {
+            buffer.push(length as u8);
+        } else {
+            buffer.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+    buffer.extend_from_slice(name);
+    buffer.extend_from_slice(value);
+}
+
+/// Writes `content` as a stream of records of type `record_type` followed by the empty record closing the stream
+fn write_stream(stream: &mut dyn Connection, record_type: u8, content: &[u8]) -> std::io::Result<()> {
+    for chunk in content.chunks(MAX_RECORD_CONTENT) {
+        write_record(stream, record_type, chunk)?;
+    }
+    write_record(stream, record_type, &[])
+}
+
+/// Writes a single record, the content is padded to a multiple of 8 bytes
+fn write_record(stream: &mut (impl Write + ?Sized), record_type: u8, content: &[u8]) -> std::io::Result<()> {
+    let padding = (8 - content.len() % 8) % 8;
+    let mut record = vec![FCGI_VERSION_1, record_type];
+    record.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(&[padding as u8, 0]);
+    record.extend_from_slice(content);
+    record.extend_from_slice(&vec![0; padding]);
+    stream.write_all(&record)
+}
+
+/// Reads a single record and returns its type and content
+fn read_record(stream: &mut (impl Read + ?Sized)) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header)?;
+    let content_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0; content_length + header[6] as usize];
+    stream.read_exact(&mut content)?;
+    content.truncate(content_length);
+    Ok((header[1], content))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fastcgi_client::*;
+    use std::net::TcpListener;
+    use std::collections::HashMap;
+
+    /// Decodes the name-value pairs of a `FCGI_PARAMS` stream
+    fn decode_name_values(mut buffer: &[u8]) -> Vec<(String, String)> {
+        let read_length = |buffer: &mut &[u8]| -> Option<usize> {
+            let first = *buffer.first()?;
+            if first < 128 {
+                *buffer = &buffer[1..];
+                return Some(first as usize);
+            }
+            let bytes: [u8; 4] = buffer.get(..4)?.try_into().ok()?;
+            *buffer = &buffer[4..];
+            Some((u32::from_be_bytes(bytes) & 0x7fff_ffff) as usize)
+        };
+        let mut pairs = Vec::new();
+        while let (Some(name_length), Some(value_length)) = (read_length(&mut buffer), read_length(&mut buffer)) {
+            if buffer.len() < name_length + value_length {
+                break;
+            }
+            let name = String::from_utf8_lossy(&buffer[..name_length]).to_string();
+            let value = String::from_utf8_lossy(&buffer[name_length..name_length + value_length]).to_string();
+            pairs.push((name, value));
+            buffer = &buffer[name_length + value_length..];
+        }
+        pairs
+    }
+
+    /// A FastCGI responder answering a single request with its method, a param and its body
+    fn test_responder(listener: TcpListener) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (mut params, mut stdin) = (Vec::new(), Vec::new());
+            loop {
+                let (record_type, content) = read_record(&mut stream).unwrap();
+                match record_type {
+                    FCGI_PARAMS => params.extend_from_slice(&content),
+                    FCGI_STDIN if content.is_empty() => break,
+                    FCGI_STDIN => stdin.extend_from_slice(&content),
+                    _ => (),
+                }
+            }
+            let params: HashMap<String, String> = decode_name_values(&params).into_iter().collect();
+            let body = format!("Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n{} {} {}",
+                params["REQUEST_METHOD"], params["LONG_PARAM"].len(), String::from_utf8_lossy(&stdin));
+            write_record(&mut stream, FCGI_STDERR, b"a warning").unwrap();
+            write_record(&mut stream, FCGI_STDOUT, body.as_bytes()).unwrap();
+            write_record(&mut stream, FCGI_STDOUT, &[]).unwrap();
+            write_record(&mut stream, FCGI_END_REQUEST, &[0, 0, 0, 0, FCGI_REQUEST_COMPLETE, 0, 0, 0]).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint = FastCgiEndpoint::parse("unix:/run/php/php-fpm.sock#/var/www/index.php").unwrap();
+        assert_eq!(FastCgiAddress::Unix(String::from("/run/php/php-fpm.sock")), endpoint.address);
+        assert_eq!("/var/www/index.php", endpoint.script_filename);
+        assert_eq!(FastCgiAddress::Tcp(String::from("127.0.0.1:9000")), FastCgiEndpoint::parse("127.0.0.1:9000").unwrap().address);
+        assert_eq!(None, FastCgiEndpoint::parse("#/var/www/index.php"));
+    }
+
+    #[test]
+    fn test_run_fastcgi() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = FastCgiEndpoint::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        let responder = test_responder(listener);
+
+        let params = vec![
+            (String::from("REQUEST_METHOD"), String::from("POST")),
+            (String::from("LONG_PARAM"), "a".repeat(300)),
+        ];
+        let limits = ScriptLimits {timeout: Some(Duration::from_secs(5)), ..ScriptLimits::default()};
+        let output = run_fastcgi(&endpoint, &params, b"name=george", &limits).unwrap();
+        responder.join().unwrap();
+        assert_eq!("Status: 201 Created\r\nContent-Type: text/plain\r\n\r\nPOST 300 name=george", String::from_utf8(output).unwrap());
+    }
+}
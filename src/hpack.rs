@@ -0,0 +1,363 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+
+/// The static table of HPACK (RFC 7541 appendix A), the entry 0 has the index 1
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""), (":method", "GET"), (":method", "POST"), (":path", "/"), (":path", "/index.html"),
+    (":scheme", "http"), (":scheme", "https"), (":status", "200"), (":status", "204"), (":status", "206"),
+    (":status", "304"), (":status", "400"), (":status", "404"), (":status", "500"), ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"), ("accept-language", ""), ("accept-ranges", ""), ("accept", ""), ("access-control-allow-origin", ""),
+    ("age", ""), ("allow", ""), ("authorization", ""), ("cache-control", ""), ("content-disposition", ""),
+    ("content-encoding", ""), ("content-language", ""), ("content-length", ""), ("content-location", ""), ("content-range", ""),
+    ("content-type", ""), ("cookie", ""), ("date", ""), ("etag", ""), ("expect", ""),
+    ("expires", ""), ("from", ""), ("host", ""), ("if-match", ""), ("if-modified-since", ""),
+    ("if-none-match", ""), ("if-range", ""), ("if-unmodified-since", ""), ("last-modified", ""), ("link", ""),
+    ("location", ""), ("max-forwards", ""), ("proxy-authenticate", ""), ("proxy-authorization", ""), ("range", ""),
+    ("referer", ""), ("refresh", ""), ("retry-after", ""), ("server", ""), ("set-cookie", ""),
+    ("strict-transport-security", ""), ("transfer-encoding", ""), ("user-agent", ""), ("vary", ""), ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The Huffman code of every byte and of the end of string symbol (256) with its length in bits (RFC 7541 appendix B)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28), (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12), (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8), (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7), (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7), (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20), (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23), (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21), (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27), (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21), (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27), (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+/// The overhead counted for every entry of the dynamic table and of a header list on top of the length of its name and value
+pub const ENTRY_OVERHEAD: usize = 32;
+
+/// An error in a header block, the connection has to be closed with a `COMPRESSION_ERROR`
+#[derive(Debug, PartialEq)]
+pub struct HpackError (pub &'static str);
+
+impl HpackError {
+    /// The decoded header list is bigger than the limit given to [Decoder::decode]
+    pub const LIST_TOO_LARGE: HpackError = HpackError("header list too large");
+}
+
+impl std::fmt::Display for HpackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Decodes the header blocks (RFC 7541) received on an HTTP/2 connection, the dynamic table is shared by all the blocks
+/// of the connection so they have to be decoded in the order they were received.
+///
+/// # Example
+/// ```
+/// let mut decoder = Decoder::new(4096);
+/// let headers = decoder.decode(&[0x82, 0x86, 0x84], 16384).unwrap();
+/// assert_eq!(headers[0], (String::from(":method"), String::from("GET")));
+/// ```
+pub struct Decoder {
+    dynamic_table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    max_allowed: usize,
+}
+
+impl Decoder {
+    /// Creates a decoder whose dynamic table can't grow beyond `max_allowed` bytes, the `SETTINGS_HEADER_TABLE_SIZE` sent to the peer
+    pub fn new(max_allowed: usize) -> Decoder {
+        Decoder {dynamic_table: VecDeque::new(), size: 0, max_size: max_allowed, max_allowed}
+    }
+
+    /// Decodes a complete header block, the whole block has to be decoded even if the request is refused afterwards
+    /// to keep the dynamic table in sync with the encoder of the peer.
+    ///
+    /// The decoding stops with [HpackError::LIST_TOO_LARGE] once the size of the header list, counted like
+    /// `SETTINGS_MAX_HEADER_LIST_SIZE` (RFC 9113 section 6.5.2), is above `max_list_size`: a small block of indexed
+    /// fields can expand to a lot of headers.
+    pub fn decode(&mut self, block: &[u8], max_list_size: usize) -> Result<Vec<(String, String)>, HpackError> {
+        let mut headers: Vec<(String, String)> = Vec::new();
+        let mut list_size = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let first = block[pos];
+            let header = match first {
+                0x80..=0xFF => {
+                    let index = decode_integer(block, &mut pos, 7)?;
+                    self.entry(index)?
+                },
+                0x40..=0x7F => {
+                    let header = self.literal(block, &mut pos, 6)?;
+                    self.insert(header.clone());
+                    header
+                },
+                0x20..=0x3F => {
+                    if !headers.is_empty() {
+                        return Err(HpackError("table size update after a header"));
+                    }
+                    let size = decode_integer(block, &mut pos, 5)?;
+                    if size > self.max_allowed {
+                        return Err(HpackError("table size update above the limit"));
+                    }
+                    self.max_size = size;
+                    self.evict(0);
+                    continue;
+                },
+                _ => self.literal(block, &mut pos, 4)?,
+            };
+            list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            if list_size > max_list_size {
+                return Err(HpackError::LIST_TOO_LARGE);
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    /// Returns the entry of the static or dynamic table at `index`
+    fn entry(&self, index: usize) -> Result<(String, String), HpackError> {
+        match index {
+            0 => Err(HpackError("invalid index 0")),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            },
+            _ => self.dynamic_table.get(index - 62).cloned().ok_or(HpackError("index out of the tables")),
+        }
+    }
+
+    /// Decodes a literal header field whose name index has a prefix of `prefix` bits
+    fn literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Result<(String, String), HpackError> {
+        let name = match decode_integer(block, pos, prefix)? {
+            0 => decode_string(block, pos)?,
+            index => self.entry(index)?.0,
+        };
+        let value = decode_string(block, pos)?;
+        Ok((name, value))
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // an entry bigger than the table empties it and isn't added
+        if size <= self.max_size {
+            self.size += size;
+            self.dynamic_table.push_front(header);
+        }
+    }
+
+    /// Removes the oldest entries until `needed` bytes are free in the table
+    fn evict(&mut self, needed: usize) {
+        while self.size + needed > self.max_size {
+            match self.dynamic_table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encodes a header block without using the dynamic table nor Huffman coding, so the peer never has to keep a state for it:
+/// the headers of the static table are indexed and the others are sent as literals without indexing.
+pub fn encode(headers: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        if let Some(index) = STATIC_TABLE.iter().position(|(n, v)| n == name && v == value) {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|(n, _)| n == name) {
+            Some(index) => encode_integer(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name);
+            },
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+/// Decodes an integer with a prefix of `prefix` bits (RFC 7541 section 5.1)
+fn decode_integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, HpackError> {
+    let mask = (1usize << prefix) - 1;
+    let first = *block.get(*pos).ok_or(HpackError("truncated integer"))? as usize & mask;
+    *pos += 1;
+    if first < mask {
+        return Ok(first);
+    }
+    let mut value = mask;
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*pos).ok_or(HpackError("truncated integer"))?;
+        *pos += 1;
+        if shift > 28 {
+            return Err(HpackError("integer overflow"));
+        }
+        value += ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | mask as u8);
+    let mut rest = value - mask;
+    while rest >= 0x80 {
+        block.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+/// Decodes a string literal, Huffman coded or not (RFC 7541 section 5.2)
+fn decode_string(block: &[u8], pos: &mut usize) -> Result<String, HpackError> {
+    let huffman = block.get(*pos).is_some_and(|v| v & 0x80 != 0);
+    let length = decode_integer(block, pos, 7)?;
+    let end = pos.checked_add(length).filter(|v| *v <= block.len()).ok_or(HpackError("truncated string"))?;
+    let bytes = &block[*pos..end];
+    *pos = end;
+    let decoded = match huffman {
+        true => huffman_decode(bytes)?,
+        false => bytes.to_vec(),
+    };
+    Ok(String::from_utf8_lossy(&decoded).to_string())
+}
+
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+    encode_integer(block, 0x00, 7, value.len());
+    block.extend_from_slice(value.as_bytes());
+}
+
+/// Decodes a Huffman coded string, the padding has to be the most significant bits of the end of string symbol
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, HpackError> {
+    static CODES: OnceLock<HashMap<(u8, u32), u16>> = OnceLock::new();
+    let codes = CODES.get_or_init(|| {
+        HUFFMAN_CODES.iter().enumerate().map(|(symbol, (code, length))| ((*length, *code), symbol as u16)).collect()
+    });
+
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut code, mut length) = (0u32, 0u8);
+    for byte in bytes {
+        for bit in (0..8).rev() {
+            code = code << 1 | (byte >> bit & 1) as u32;
+            length += 1;
+            match codes.get(&(length, code)) {
+                Some(256) => return Err(HpackError("end of string symbol in a string")),
+                Some(symbol) => {
+                    decoded.push(*symbol as u8);
+                    (code, length) = (0, 0);
+                },
+                None if length >= 30 => return Err(HpackError("invalid Huffman code")),
+                None => (),
+            }
+        }
+    }
+    if length > 7 || code != (1 << length) - 1 {
+        return Err(HpackError("invalid Huffman padding"));
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hpack::*;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        let hex: String = hex.split_whitespace().collect();
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn headers(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_integers() {
+        // RFC 7541 appendix C.1
+        let mut block = Vec::new();
+        encode_integer(&mut block, 0, 5, 10);
+        encode_integer(&mut block, 0, 5, 1337);
+        assert_eq!(block, [0x0a, 0x1f, 0x9a, 0x0a]);
+        let mut pos = 0;
+        assert_eq!(decode_integer(&block, &mut pos, 5), Ok(10));
+        assert_eq!(decode_integer(&block, &mut pos, 5), Ok(1337));
+        assert_eq!(decode_integer(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0f], &mut 0, 5), Err(HpackError("integer overflow")));
+    }
+
+    #[test]
+    fn test_decode_requests() {
+        // RFC 7541 appendix C.3 and C.4, the same requests without and with Huffman coding
+        let requests = [
+            ("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d", "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"),
+            ("8286 84be 5808 6e6f 2d63 6163 6865", "8286 84be 5886 a8eb 1064 9cbf"),
+            ("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+                "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+        ];
+        let expected = [
+            headers(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]),
+            headers(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"), ("cache-control", "no-cache")]),
+            headers(&[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"), (":authority", "www.example.com"), ("custom-key", "custom-value")]),
+        ];
+        let (mut plain, mut huffman) = (Decoder::new(4096), Decoder::new(4096));
+        for ((plain_block, huffman_block), expected) in requests.iter().zip(expected) {
+            assert_eq!(plain.decode(&bytes(plain_block), 16384).unwrap(), expected);
+            assert_eq!(huffman.decode(&bytes(huffman_block), 16384).unwrap(), expected);
+        }
+        assert_eq!(plain.size, 164);
+        assert_eq!(plain.decode(&bytes("be"), 16384).unwrap(), headers(&[("custom-key", "custom-value")]));
+        assert!(plain.decode(&bytes("c1"), 16384).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let list = headers(&[(":status", "200"), (":status", "302"), ("content-length", "42"), ("x-powered-by", "webserver-rs")]);
+        let block = encode(&list);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::new(4096).decode(&block, 16384).unwrap(), list);
+    }
+
+    #[test]
+    fn test_list_size_limit() {
+        // a block of 1000 bytes indexing a large entry of the dynamic table expands to about 4 MB of headers
+        let mut block = vec![0x40];
+        encode_string(&mut block, "x-large");
+        encode_string(&mut block, &"a".repeat(4000));
+        block.extend(std::iter::repeat_n(0xbe, 1000));
+        assert_eq!(Decoder::new(8192).decode(&block, 16384), Err(HpackError::LIST_TOO_LARGE));
+        assert_eq!(Decoder::new(8192).decode(&block[..block.len() - 997], 16384).unwrap().len(), 4);
+    }
+}
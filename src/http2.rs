@@ -0,0 +1,1027 @@
+use std::net::{SocketAddr, TcpStream};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::hpack::{self, Decoder, HpackError};
+use crate::event_loop::Waker;
+use crate::request_handler::{HTTPCode, HTTPResponse, IncomingRequest, ServerStatus, build_response, is_token};
+use crate::server_config::ServerConfig;
+use crate::thread_pool::{ConnectionLimit, ConnectionSlot, ThreadPool};
+
+/// The bytes sent by a client at the start of every HTTP/2 connection
+const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_PRIORITY: u8 = 0x2;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PUSH_PROMISE: u8 = 0x5;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+/// The size of the frames and of the windows before the peer changes them
+const DEFAULT_FRAME_SIZE: usize = 16384;
+const DEFAULT_WINDOW_SIZE: i64 = 65535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+/// The headers which only concern an HTTP/1.1 connection and are forbidden in HTTP/2
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// The HTTP/2 (RFC 9113) settings of the server, HTTP/2 is served over TLS to the clients choosing `h2` with ALPN on `tls_ip`,
+/// and on the plain listener to the clients sending the HTTP/2 preface right away (h2c with prior knowledge) if `h2c` is set:
+/// ```json
+/// {
+///     "tls_ip":"127.0.0.1:7443",
+///     "tls_certificate":"data/tls/cert.pem",
+///     "tls_private_key":"data/tls/key.pem",
+///     "h2c":false,
+///     "http2_max_streams":100,
+///     "http2_idle_timeout":60,
+///     "http2_max_connections":256
+/// }
+/// ```
+/// The requests of the streams go through the same routing and pages as the HTTP/1.1 requests, a stream is answered once
+/// its request is complete and the responses are interleaved as the flow control windows of the client allow.
+/// The routes which take over the connection (reverse proxy, WebSocket and Server-Sent Events) are refused with
+/// `HTTP_1_1_REQUIRED`. The TLS listener only offers `h2` and closes the connections of the clients which don't choose it,
+/// so these routes are only served over HTTP/1.1 on the plain listener: a browser retrying one of them with `http/1.1`
+/// on the TLS listener can't reach it, the pages have to link to the plain listener for them.
+///
+/// Each connection has its own thread reading the frames and sending the responses, the requests of the streams are
+/// handled on the blocking pool of the [EventLoop](crate::event_loop::EventLoop) so that a slow stream doesn't hold the
+/// others. At most `http2_max_streams` streams can be open at once on a connection and the connection is closed after
+/// `http2_idle_timeout` seconds without a frame from the client while none of its requests is handled. At most
+/// `http2_max_connections` connections, over TLS and h2c, are open at once, the next ones are closed right away.
+pub struct Http2Settings {
+    pub h2c: bool,
+    max_streams: u32,
+    idle_timeout: Duration,
+    connections: ConnectionLimit,
+}
+
+impl Http2Settings {
+    /// Reads the settings from the server config
+    pub fn from_config(config: &HashMap<String, String>) -> Http2Settings {
+        Http2Settings {
+            h2c: config.get("h2c").is_some_and(|v| v.trim() == "true"),
+            max_streams: config.get("http2_max_streams").and_then(|v| v.trim().parse::<u32>().ok()).filter(|v| *v > 0).unwrap_or(100),
+            idle_timeout: config.get("http2_idle_timeout")
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| *v > 0.0)
+                .map(Duration::from_secs_f64)
+                .unwrap_or(Duration::from_secs(60)),
+            connections: ConnectionLimit::new(config.get("http2_max_connections").and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(256)),
+        }
+    }
+
+    /// Takes a place for a new connection before its thread is started, returns `None` if too many are open
+    pub fn reserve(&self) -> Option<ConnectionSlot> {
+        self.connections.reserve()
+    }
+}
+
+/// Loads the certificate chain and the private key of the TLS listener from PEM files, `h2` is the only protocol offered
+pub fn tls_config(certificate_file: &str, private_key_file: &str) -> Result<Arc<rustls::ServerConfig>, String> {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+    let certificates = CertificateDer::pem_file_iter(certificate_file)
+        .and_then(|v| v.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Invalid certificate file {}: {}", certificate_file, e))?;
+    let private_key = PrivateKeyDer::from_pem_file(private_key_file)
+        .map_err(|e| format!("Invalid private key file {}: {}", private_key_file, e))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|v| v.with_no_client_auth().with_single_cert(certificates, private_key))
+        .map_err(|e| format!("Invalid TLS configuration: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Returns true if the first bytes received on a connection are the start of the HTTP/2 preface, at least 4 are needed
+pub fn is_preface(received: &[u8]) -> bool {
+    // no HTTP/1.1 method starts with "PRI "
+    received.len() >= 4 && PREFACE.starts_with(&received[..received.len().min(PREFACE.len())])
+}
+
+/// Serves an HTTP/2 connection whose client sent the preface on the plain listener, `received` holds the bytes
+/// the event loop already read from the stream
+pub fn serve_h2c(stream: TcpStream, received: Vec<u8>, pool: &Arc<ThreadPool>, config: &Arc<ServerConfig>) {
+    let (remote_addr, local_addr) = (stream.peer_addr().ok(), stream.local_addr().ok());
+    if let Err(e) = stream.set_write_timeout(Some(config.request_limits.write_timeout)) {
+        info!("Error when setting the write timeout: {}", e);
+        return;
+    }
+    match Connection::new(stream, received, "http", remote_addr, local_addr, pool, config) {
+        Ok(v) => v.serve(),
+        Err(e) => info!("Error when creating the HTTP/2 connection: {}", e),
+    }
+}
+
+/// Completes the TLS handshake of a connection of the TLS listener and serves it if the client chose `h2`
+pub fn serve_tls(stream: TcpStream, tls: Arc<rustls::ServerConfig>, pool: &Arc<ThreadPool>, config: &Arc<ServerConfig>) {
+    let (remote_addr, local_addr) = (stream.peer_addr().ok(), stream.local_addr().ok());
+    let limits = &config.request_limits;
+    if stream.set_read_timeout(Some(limits.header_timeout)).and_then(|_| stream.set_write_timeout(Some(limits.write_timeout))).is_err() {
+        return;
+    }
+    let connection = match rustls::ServerConnection::new(tls) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Error when creating the TLS connection: {}", e);
+            return;
+        }
+    };
+    let mut tls_stream = rustls::StreamOwned::new(connection, stream);
+    while tls_stream.conn.is_handshaking() {
+        if let Err(e) = tls_stream.conn.complete_io(&mut tls_stream.sock) {
+            info!("TLS handshake failed with {:?}: {}", remote_addr, e);
+            return;
+        }
+    }
+    // HTTP/1.1 is only served on the plain listener
+    if tls_stream.conn.alpn_protocol() != Some(b"h2") {
+        info!("The client {:?} didn't negotiate h2, closing the connection", remote_addr);
+        return;
+    }
+    // the client can send its first frames with the end of the handshake
+    let mut received = Vec::new();
+    if let Err(e) = plaintext(&mut tls_stream.conn, &mut received) {
+        info!("TLS error with {:?}: {}", remote_addr, e);
+        return;
+    }
+    match Connection::new(tls_stream, received, "https", remote_addr, local_addr, pool, config) {
+        Ok(v) => v.serve(),
+        Err(e) => info!("Error when creating the HTTP/2 connection: {}", e),
+    }
+}
+
+/// The stream of a connection, plain or TLS. The connection thread waits with `poll` on [Transport::fd] and
+/// receives what arrived once it is readable.
+trait Transport {
+    fn fd(&self) -> RawFd;
+
+    /// Adds the bytes received to `input` with a single read of the socket, returns false once the client closed the connection
+    fn receive(&mut self, input: &mut Vec<u8>) -> std::io::Result<bool>;
+
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+
+    fn receive(&mut self, input: &mut Vec<u8>) -> std::io::Result<bool> {
+        let mut chunk = [0u8; 16384];
+        match self.read(&mut chunk) {
+            Ok(n) => {
+                input.extend_from_slice(&chunk[..n]);
+                Ok(n > 0)
+            },
+            Err(e) if e.kind() == ErrorKind::Interrupted => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.write_all(bytes)
+    }
+}
+
+impl Transport for rustls::StreamOwned<rustls::ServerConnection, TcpStream> {
+    fn fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+
+    fn receive(&mut self, input: &mut Vec<u8>) -> std::io::Result<bool> {
+        let read = match self.conn.read_tls(&mut self.sock) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        let open = plaintext(&mut self.conn, input)?;
+        // the alerts and the key updates
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(read > 0 && open)
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.conn.writer().write_all(bytes)?;
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+/// Moves the plaintext the TLS connection decrypted to `input`, returns false once the client closed the connection
+fn plaintext(conn: &mut rustls::ServerConnection, input: &mut Vec<u8>) -> std::io::Result<bool> {
+    let state = conn.process_new_packets().map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+    let start = input.len();
+    input.resize(start + state.plaintext_bytes_to_read(), 0);
+    conn.reader().read_exact(&mut input[start..])?;
+    Ok(!state.peer_has_closed())
+}
+
+/// A frame as it was received
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+/// Why a connection ends: the connection was lost or closed by the client, or the client has to be sent a `GOAWAY`
+/// with this error code
+enum ConnectionError {
+    Closed,
+    Io (std::io::Error),
+    Protocol (u32, &'static str),
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> ConnectionError {
+        ConnectionError::Io(e)
+    }
+}
+
+/// A stream of a connection, from the first header of its request until the last byte of its response is sent
+struct Stream {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    receiving: bool,
+    send_window: i64,
+    response: Vec<u8>,
+    sent: usize,
+}
+
+/// A header block split in a `HEADERS` frame and its `CONTINUATION` frames
+struct PendingHeaders {
+    stream_id: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+/// The response built by the blocking pool for a stream, or the error code the stream has to be reset with
+type StreamResult = Result<HTTPResponse, u32>;
+
+/// The state of an HTTP/2 connection, served by a single thread reading the frames and sending the responses built
+/// by the blocking pool as they come back
+struct Connection<S: Transport> {
+    stream: S,
+    /// The bytes received which don't make a complete frame yet
+    input: Vec<u8>,
+    /// The scheme of the listener, given to the requests
+    scheme: &'static str,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    pool: Arc<ThreadPool>,
+    config: Arc<ServerConfig>,
+    sender: mpsc::Sender<(u32, StreamResult)>,
+    results: mpsc::Receiver<(u32, StreamResult)>,
+    waker: Arc<Waker>,
+    /// The number of requests handled by the blocking pool
+    jobs: usize,
+    decoder: Decoder,
+    streams: HashMap<u32, Stream>,
+    last_stream_id: u32,
+    pending_headers: Option<PendingHeaders>,
+    send_window: i64,
+    peer_initial_window: i64,
+    peer_max_frame: usize,
+}
+
+impl<S: Transport> Connection<S> {
+    fn new(stream: S, received: Vec<u8>, scheme: &'static str, remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>,
+        pool: &Arc<ThreadPool>, config: &Arc<ServerConfig>) -> std::io::Result<Connection<S>> {
+        let (sender, results) = mpsc::channel();
+        Ok(Connection {
+            stream,
+            input: received,
+            scheme,
+            remote_addr,
+            local_addr,
+            pool: pool.clone(),
+            config: config.clone(),
+            sender,
+            results,
+            waker: Arc::new(Waker::new()?),
+            jobs: 0,
+            decoder: Decoder::new(4096),
+            streams: HashMap::new(),
+            last_stream_id: 0,
+            pending_headers: None,
+            send_window: DEFAULT_WINDOW_SIZE,
+            peer_initial_window: DEFAULT_WINDOW_SIZE,
+            peer_max_frame: DEFAULT_FRAME_SIZE,
+        })
+    }
+
+    /// Serves the connection until it is closed, sends a `GOAWAY` when the client breaks the protocol or stays idle
+    fn serve(mut self) {
+        let error = match self.run() {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        let (code, reason) = match error {
+            ConnectionError::Closed => return,
+            ConnectionError::Io(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => (NO_ERROR, "idle"),
+            ConnectionError::Io(e) => {
+                debug!("HTTP/2 connection lost: {}", e);
+                return;
+            },
+            ConnectionError::Protocol(code, reason) => {
+                info!("HTTP/2 connection error with {:?}: {}", self.remote_addr, reason);
+                (code, reason)
+            },
+        };
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(reason.as_bytes());
+        let _ = self.write_frame(FRAME_GOAWAY, 0, 0, &payload);
+    }
+
+    fn run(&mut self) -> Result<(), ConnectionError> {
+        while self.input.len() < PREFACE.len() {
+            self.receive()?;
+        }
+        if !self.input.starts_with(PREFACE) {
+            return Err(ConnectionError::Closed);
+        }
+        self.input.drain(..PREFACE.len());
+        let settings = [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, self.config.http2.max_streams),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, self.config.request_limits.max_header_bytes as u32),
+        ];
+        let payload: Vec<u8> = settings.iter().flat_map(|(id, value)| [id.to_be_bytes().to_vec(), value.to_be_bytes().to_vec()].concat()).collect();
+        self.write_frame(FRAME_SETTINGS, 0, 0, &payload)?;
+
+        let mut first = true;
+        loop {
+            while let Some(frame) = self.next_frame()? {
+                if first && (frame.kind != FRAME_SETTINGS || frame.flags & FLAG_ACK != 0) {
+                    return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "the first frame isn't SETTINGS"));
+                }
+                first = false;
+                self.handle_frame(frame)?;
+            }
+            while let Ok((stream_id, result)) = self.results.try_recv() {
+                self.jobs -= 1;
+                self.complete(stream_id, result)?;
+            }
+            self.send_data()?;
+            self.receive()?;
+        }
+    }
+
+    /// Waits until the client sends something or the blocking pool finished a request. The connection is idle once
+    /// nothing happened for `idle_timeout` while none of its requests is handled.
+    fn receive(&mut self) -> Result<(), ConnectionError> {
+        let mut fds = [
+            libc::pollfd {fd: self.stream.fd(), events: libc::POLLIN, revents: 0},
+            libc::pollfd {fd: self.waker.fd(), events: libc::POLLIN, revents: 0},
+        ];
+        let timeout = self.config.http2.idle_timeout.as_millis().min(i32::MAX as u128) as i32;
+        let count = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if count < 0 {
+            let error = std::io::Error::last_os_error();
+            return match error.kind() {
+                ErrorKind::Interrupted => Ok(()),
+                _ => Err(error.into()),
+            };
+        }
+        if count == 0 && self.jobs == 0 {
+            return Err(ConnectionError::Io(ErrorKind::TimedOut.into()));
+        }
+        if fds[1].revents != 0 {
+            self.waker.reset();
+        }
+        if fds[0].revents != 0 && !self.stream.receive(&mut self.input)? {
+            return Err(ConnectionError::Closed);
+        }
+        Ok(())
+    }
+
+    /// Takes the next complete frame out of the bytes received
+    fn next_frame(&mut self) -> Result<Option<Frame>, ConnectionError> {
+        let header = match self.input.get(..9) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if length > DEFAULT_FRAME_SIZE {
+            return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR, "frame bigger than SETTINGS_MAX_FRAME_SIZE"));
+        }
+        if self.input.len() < 9 + length {
+            return Ok(None);
+        }
+        let frame = Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7FFFFFFF,
+            payload: self.input[9..9 + length].to_vec(),
+        };
+        self.input.drain(..9 + length);
+        Ok(Some(frame))
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> std::io::Result<()> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.stream.send(&frame)
+    }
+
+    fn reset_stream(&mut self, stream_id: u32, code: u32) -> std::io::Result<()> {
+        self.streams.remove(&stream_id);
+        self.write_frame(FRAME_RST_STREAM, 0, stream_id, &code.to_be_bytes())
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if self.pending_headers.as_ref().is_some_and(|v| frame.kind != FRAME_CONTINUATION || v.stream_id != frame.stream_id) {
+            return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "expected a CONTINUATION frame"));
+        }
+        let connection_frame = matches!(frame.kind, FRAME_SETTINGS | FRAME_PING | FRAME_GOAWAY);
+        if connection_frame != (frame.stream_id == 0) && frame.kind != FRAME_WINDOW_UPDATE && frame.kind <= FRAME_CONTINUATION {
+            return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "frame sent on the wrong stream"));
+        }
+
+        match frame.kind {
+            FRAME_DATA => self.handle_data(frame),
+            FRAME_HEADERS => self.handle_headers(frame),
+            FRAME_PRIORITY if frame.payload.len() != 5 => Ok(self.reset_stream(frame.stream_id, FRAME_SIZE_ERROR)?),
+            FRAME_RST_STREAM if frame.payload.len() != 4 => Err(ConnectionError::Protocol(FRAME_SIZE_ERROR, "invalid RST_STREAM")),
+            FRAME_RST_STREAM if frame.stream_id > self.last_stream_id => Err(ConnectionError::Protocol(PROTOCOL_ERROR, "RST_STREAM on an idle stream")),
+            FRAME_RST_STREAM => {
+                self.streams.remove(&frame.stream_id);
+                Ok(())
+            },
+            FRAME_SETTINGS => self.handle_settings(frame),
+            FRAME_PUSH_PROMISE => Err(ConnectionError::Protocol(PROTOCOL_ERROR, "PUSH_PROMISE sent by a client")),
+            FRAME_PING if frame.payload.len() != 8 => Err(ConnectionError::Protocol(FRAME_SIZE_ERROR, "invalid PING")),
+            FRAME_PING if frame.flags & FLAG_ACK == 0 => Ok(self.write_frame(FRAME_PING, FLAG_ACK, 0, &frame.payload)?),
+            FRAME_GOAWAY => Err(ConnectionError::Closed),
+            FRAME_WINDOW_UPDATE => self.handle_window_update(frame),
+            FRAME_CONTINUATION => {
+                let mut pending = match self.pending_headers.take() {
+                    Some(v) => v,
+                    None => return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "unexpected CONTINUATION frame")),
+                };
+                pending.block.extend_from_slice(&frame.payload);
+                if pending.block.len() > self.config.request_limits.max_header_bytes * 2 {
+                    return Err(ConnectionError::Protocol(ENHANCE_YOUR_CALM, "header block too large"));
+                }
+                match frame.flags & FLAG_END_HEADERS {
+                    0 => {
+                        self.pending_headers = Some(pending);
+                        Ok(())
+                    },
+                    _ => self.end_headers(pending),
+                }
+            },
+            // the PING acknowledgments, the PRIORITY frames and the unknown frames are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_data(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let length = frame.payload.len();
+        let data = strip_padding(&frame)?;
+        if length > 0 {
+            // the connection window is given back right away, the stream window only while the request is wanted
+            self.write_frame(FRAME_WINDOW_UPDATE, 0, 0, &(length as u32).to_be_bytes())?;
+        }
+        let max_body_size = self.config.request_limits.max_body_size;
+        let stream = match self.streams.get_mut(&frame.stream_id) {
+            Some(v) if v.receiving => v,
+            // the rest of a request refused before it was complete
+            Some(_) => return Ok(()),
+            _ if frame.stream_id > self.last_stream_id => return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "DATA on an idle stream")),
+            _ => return Ok(self.reset_stream(frame.stream_id, STREAM_CLOSED)?),
+        };
+        stream.body.extend_from_slice(data);
+        if stream.body.len() > max_body_size {
+            stream.receiving = false;
+            stream.headers.clear();
+            self.dispatch(frame.stream_id, |config| {
+                Ok(build_response(HTTPCode::Err413, &IncomingRequest::new(), config).unwrap_or_else(HTTPResponse::internal_error))
+            });
+            return Ok(());
+        }
+        if frame.flags & FLAG_END_STREAM != 0 {
+            return self.respond(frame.stream_id);
+        }
+        if length > 0 {
+            self.write_frame(FRAME_WINDOW_UPDATE, 0, frame.stream_id, &(length as u32).to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn handle_headers(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let mut block = strip_padding(&frame)?;
+        if frame.flags & FLAG_PRIORITY != 0 {
+            block = block.get(5..).ok_or(ConnectionError::Protocol(FRAME_SIZE_ERROR, "invalid HEADERS"))?;
+        }
+        let pending = PendingHeaders {stream_id: frame.stream_id, end_stream: frame.flags & FLAG_END_STREAM != 0, block: block.to_vec()};
+        match frame.flags & FLAG_END_HEADERS {
+            0 => {
+                self.pending_headers = Some(pending);
+                Ok(())
+            },
+            _ => self.end_headers(pending),
+        }
+    }
+
+    /// Handles a complete header block: the headers of a new request or the trailers of a request
+    fn end_headers(&mut self, pending: PendingHeaders) -> Result<(), ConnectionError> {
+        let headers = match self.decoder.decode(&pending.block, self.config.request_limits.max_header_bytes) {
+            Ok(v) => v,
+            Err(HpackError::LIST_TOO_LARGE) => return Err(ConnectionError::Protocol(ENHANCE_YOUR_CALM, "header list too large")),
+            Err(e) => {
+                debug!("Invalid header block: {}", e);
+                return Err(ConnectionError::Protocol(COMPRESSION_ERROR, "invalid header block"));
+            }
+        };
+        let id = pending.stream_id;
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // trailers, they end the request and are ignored
+            if !stream.receiving || !pending.end_stream {
+                return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "HEADERS on a stream which isn't open"));
+            }
+            return self.respond(id);
+        }
+        if id.is_multiple_of(2) || id <= self.last_stream_id {
+            return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "invalid stream id"));
+        }
+        self.last_stream_id = id;
+        // the requests of the streams reset by the client count until the blocking pool is done with them
+        if self.streams.len().max(self.jobs) >= self.config.http2.max_streams as usize {
+            return Ok(self.reset_stream(id, REFUSED_STREAM)?);
+        }
+        self.streams.insert(id, Stream {
+            headers,
+            body: Vec::new(),
+            receiving: true,
+            send_window: self.peer_initial_window,
+            response: Vec::new(),
+            sent: 0,
+        });
+        match pending.end_stream {
+            true => self.respond(id),
+            false => Ok(()),
+        }
+    }
+
+    fn handle_settings(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.flags & FLAG_ACK != 0 {
+            return match frame.payload.is_empty() {
+                true => Ok(()),
+                false => Err(ConnectionError::Protocol(FRAME_SIZE_ERROR, "SETTINGS acknowledgment with a payload")),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR, "invalid SETTINGS"));
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW_SIZE {
+                        return Err(ConnectionError::Protocol(FLOW_CONTROL_ERROR, "initial window size too large"));
+                    }
+                    let delta = value as i64 - self.peer_initial_window;
+                    self.peer_initial_window = value as i64;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                    }
+                },
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_FRAME_SIZE as u32..=16777215).contains(&value) {
+                        return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "invalid max frame size"));
+                    }
+                    self.peer_max_frame = value as usize;
+                },
+                // the encoder doesn't use the dynamic table and the server never pushes
+                _ => (),
+            }
+        }
+        Ok(self.write_frame(FRAME_SETTINGS, FLAG_ACK, 0, &[])?)
+    }
+
+    fn handle_window_update(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.payload.len() != 4 {
+            return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR, "invalid WINDOW_UPDATE"));
+        }
+        let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2], frame.payload[3]]) & 0x7FFFFFFF) as i64;
+        if frame.stream_id == 0 {
+            if increment == 0 || self.send_window + increment > MAX_WINDOW_SIZE {
+                return Err(ConnectionError::Protocol(FLOW_CONTROL_ERROR, "invalid connection window update"));
+            }
+            self.send_window += increment;
+            return Ok(());
+        }
+        let window = match self.streams.get_mut(&frame.stream_id) {
+            Some(v) => {
+                v.send_window += increment;
+                v.send_window
+            },
+            None => return Ok(()),
+        };
+        if increment == 0 {
+            self.reset_stream(frame.stream_id, PROTOCOL_ERROR)?;
+        } else if window > MAX_WINDOW_SIZE {
+            self.reset_stream(frame.stream_id, FLOW_CONTROL_ERROR)?;
+        }
+        Ok(())
+    }
+
+    /// Checks a complete request and hands it to the blocking pool, its response is sent by [Connection::complete]
+    fn respond(&mut self, stream_id: u32) -> Result<(), ConnectionError> {
+        let (headers, body) = match self.streams.get_mut(&stream_id) {
+            Some(v) => {
+                v.receiving = false;
+                (std::mem::take(&mut v.headers), std::mem::take(&mut v.body))
+            },
+            None => return Ok(()),
+        };
+
+        let mut pseudo: HashMap<&str, String> = HashMap::new();
+        let mut fields: HashMap<String, String> = HashMap::new();
+        for (name, value) in headers {
+            if let Some(v) = name.strip_prefix(':') {
+                match v {
+                    "method" | "path" | "scheme" | "authority" if !fields.is_empty() => return Ok(self.reset_stream(stream_id, PROTOCOL_ERROR)?),
+                    "method" => pseudo.insert("method", value),
+                    "path" => pseudo.insert("path", value),
+                    "scheme" => pseudo.insert("scheme", value),
+                    "authority" => pseudo.insert("authority", value),
+                    _ => return Ok(self.reset_stream(stream_id, PROTOCOL_ERROR)?),
+                };
+                continue;
+            }
+            if name.bytes().any(|b| b.is_ascii_uppercase()) || CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+                return Ok(self.reset_stream(stream_id, PROTOCOL_ERROR)?);
+            }
+            // the cookie header can be split in several fields (RFC 9113 section 8.2.3)
+            let separator = if name == "cookie" {"; "} else {", "};
+            fields.entry(name).and_modify(|v| {v.push_str(separator); v.push_str(&value)}).or_insert(value);
+        }
+        let (method, path) = match (pseudo.get("method"), pseudo.get("path")) {
+            (Some(method), Some(path)) if !path.is_empty() && is_token(method) => (method.clone(), path.clone()),
+            _ => return Ok(self.reset_stream(stream_id, PROTOCOL_ERROR)?),
+        };
+        if let Some(authority) = pseudo.remove("authority") {
+            fields.insert(String::from("host"), authority);
+        }
+        if !fields.contains_key("content-length") && !body.is_empty() {
+            fields.insert(String::from("content-length"), body.len().to_string());
+        }
+
+        let (scheme, remote_addr, local_addr) = (self.scheme, self.remote_addr, self.local_addr);
+        self.dispatch(stream_id, move |config| {
+            let mut incoming = IncomingRequest::from_parts(&method, &path, "2", fields, body, remote_addr, local_addr);
+            incoming.set_scheme(scheme);
+            let http_code = match incoming.limit_prefix_rate(&config.rate_limiter) {
+                false => HTTPCode::Err429,
+                true => match config.database.match_request(&mut incoming, &config.sessions, &config.auth) {
+                    ServerStatus::Ok(v) => v,
+                    ServerStatus::Error(v) => v,
+                    ServerStatus::InternalError => return Ok(HTTPResponse::internal_error()),
+                },
+            };
+            let http_code = incoming.limit_route_rate(http_code, &config.rate_limiter);
+            debug!("{}\n", incoming.as_json());
+            if let HTTPCode::Ok200(v) = &http_code {
+                if v.takes_over_connection() {
+                    return Err(HTTP_1_1_REQUIRED);
+                }
+            }
+            Ok(build_response(http_code, &incoming, config).unwrap_or_else(HTTPResponse::internal_error))
+        });
+        Ok(())
+    }
+
+    /// Runs `job` on the blocking pool, its result comes back to [Connection::run] through `results`
+    fn dispatch(&mut self, stream_id: u32, job: impl FnOnce(&ServerConfig) -> StreamResult + Send + 'static) {
+        let (sender, waker, config) = (self.sender.clone(), self.waker.clone(), self.config.clone());
+        self.jobs += 1;
+        self.pool.execute(move || {
+            let result = job(&config);
+            if sender.send((stream_id, result)).is_ok() {
+                waker.wake();
+            }
+        });
+    }
+
+    /// Sends the response built by the blocking pool, unless the client reset the stream in the meantime
+    fn complete(&mut self, stream_id: u32, result: StreamResult) -> Result<(), ConnectionError> {
+        if !self.streams.contains_key(&stream_id) {
+            return Ok(());
+        }
+        match result {
+            Ok(response) => self.send_response(stream_id, response),
+            Err(code) => Ok(self.reset_stream(stream_id, code)?),
+        }
+    }
+
+    /// Sends the headers of a response and queues its contents, they are sent by [Connection::send_data]
+    fn send_response(&mut self, stream_id: u32, response: HTTPResponse) -> Result<(), ConnectionError> {
+        let (code, headers, contents) = response.into_parts();
+        let mut fields = vec![(String::from(":status"), code.to_string())];
+        fields.extend(headers.into_iter()
+            .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+            .filter(|(k, _)| !CONNECTION_HEADERS.contains(&k.as_str())));
+        let block = hpack::encode(&fields);
+
+        let end_stream = if contents.is_empty() {FLAG_END_STREAM} else {0};
+        let mut chunks = block.chunks(self.peer_max_frame).peekable();
+        let mut kind = FRAME_HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let end_headers = if chunks.peek().is_none() {FLAG_END_HEADERS} else {0};
+            let flags = if kind == FRAME_HEADERS {end_stream | end_headers} else {end_headers};
+            self.write_frame(kind, flags, stream_id, chunk)?;
+            kind = FRAME_CONTINUATION;
+        }
+        match self.streams.get_mut(&stream_id) {
+            Some(stream) if !contents.is_empty() => stream.response = contents,
+            _ => {
+                self.streams.remove(&stream_id);
+            },
+        }
+        Ok(())
+    }
+
+    /// Sends the queued contents of the responses as far as the flow control windows allow, the streams take turns
+    fn send_data(&mut self) -> Result<(), ConnectionError> {
+        let mut ids: Vec<u32> = self.streams.iter().filter(|(_, v)| v.sent < v.response.len()).map(|(k, _)| *k).collect();
+        ids.sort();
+        while self.send_window > 0 && !ids.is_empty() {
+            // the streams whose response was sent in the previous turn are gone
+            ids.retain(|id| matches!(self.streams.get(id), Some(v) if v.send_window > 0 && v.sent < v.response.len()));
+            for id in ids.clone() {
+                let stream = &self.streams[&id];
+                let length = (stream.response.len() - stream.sent)
+                    .min(self.peer_max_frame)
+                    .min(stream.send_window.min(self.send_window).max(0) as usize);
+                if length == 0 {
+                    continue;
+                }
+                let (start, end) = (stream.sent, stream.sent + length);
+                let flags = if end == stream.response.len() {FLAG_END_STREAM} else {0};
+                let chunk = stream.response[start..end].to_vec();
+                self.write_frame(FRAME_DATA, flags, id, &chunk)?;
+                self.send_window -= length as i64;
+                let stream = self.streams.get_mut(&id).ok_or(ConnectionError::Protocol(INTERNAL_ERROR, "stream lost"))?;
+                stream.send_window -= length as i64;
+                stream.sent = end;
+                if flags == FLAG_END_STREAM && !stream.receiving {
+                    self.streams.remove(&id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the payload of a DATA or HEADERS frame without its padding
+fn strip_padding(frame: &Frame) -> Result<&[u8], ConnectionError> {
+    if frame.flags & FLAG_PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let padding = *frame.payload.first().ok_or(ConnectionError::Protocol(PROTOCOL_ERROR, "invalid padding"))? as usize;
+    if padding >= frame.payload.len() {
+        return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "invalid padding"));
+    }
+    Ok(&frame.payload[1..frame.payload.len() - padding])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http2::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A transport keeping what the connection sends
+    struct Sent (Vec<u8>);
+
+    impl Transport for Sent {
+        fn fd(&self) -> RawFd {
+            -1
+        }
+
+        fn receive(&mut self, _: &mut Vec<u8>) -> std::io::Result<bool> {
+            Ok(false)
+        }
+
+        fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+            self.0.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    fn test_config() -> Arc<ServerConfig> {
+        Arc::new(ServerConfig::from_config(&json::object!{
+            "database": "data/database.db",
+            "http2_max_streams": 2,
+        }))
+    }
+
+    /// A stream whose request is complete
+    fn stream(response: Vec<u8>) -> Stream {
+        Stream {headers: Vec::new(), body: Vec::new(), receiving: false, send_window: DEFAULT_WINDOW_SIZE, response, sent: 0}
+    }
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn read_frame(client: &mut impl Read) -> Frame {
+        let mut header = [0u8; 9];
+        client.read_exact(&mut header).unwrap();
+        let mut payload = vec![0u8; u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize];
+        client.read_exact(&mut payload).unwrap();
+        Frame {kind: header[3], flags: header[4], stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]), payload}
+    }
+
+    /// Serves an h2c connection on a thread and returns the client once the SETTINGS have been exchanged
+    fn connect(settings: &[(u16, u32)]) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (pool, config) = (Arc::new(ThreadPool::new(2)), test_config());
+        thread::spawn(move || serve_h2c(stream, Vec::new(), &pool, &config));
+
+        let payload: Vec<u8> = settings.iter().flat_map(|(id, value)| [id.to_be_bytes().to_vec(), value.to_be_bytes().to_vec()].concat()).collect();
+        client.write_all(PREFACE).unwrap();
+        client.write_all(&frame(FRAME_SETTINGS, 0, 0, &payload)).unwrap();
+        let settings = read_frame(&mut client);
+        assert!(settings.kind == FRAME_SETTINGS && settings.flags == 0);
+        assert!(settings.payload.chunks(6).any(|v| v == [0, 3, 0, 0, 0, 2]));
+        let ack = read_frame(&mut client);
+        assert!(ack.kind == FRAME_SETTINGS && ack.flags == FLAG_ACK && ack.payload.is_empty());
+        client
+    }
+
+    fn request(client: &mut TcpStream, stream_id: u32, path: &str) {
+        let headers: Vec<(String, String)> = [(":method", "GET"), (":scheme", "http"), (":path", path), (":authority", "localhost")]
+            .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        client.write_all(&frame(FRAME_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS, stream_id, &hpack::encode(&headers))).unwrap();
+    }
+
+    /// Reads the `GOAWAY` ending the connection and returns its error code
+    fn goaway(client: &mut TcpStream) -> u32 {
+        let frame = read_frame(client);
+        assert_eq!(frame.kind, FRAME_GOAWAY);
+        u32::from_be_bytes([frame.payload[4], frame.payload[5], frame.payload[6], frame.payload[7]])
+    }
+
+    #[test]
+    fn test_settings_and_ping() {
+        let mut client = connect(&[(SETTINGS_MAX_FRAME_SIZE, 32768)]);
+        client.write_all(&frame(FRAME_PING, 0, 0, b"12345678")).unwrap();
+        let ping = read_frame(&mut client);
+        assert!(ping.kind == FRAME_PING && ping.flags == FLAG_ACK && ping.payload == b"12345678");
+
+        // a setting takes 6 bytes
+        client.write_all(&frame(FRAME_SETTINGS, 0, 0, &[0, 4, 0, 0, 0])).unwrap();
+        assert_eq!(goaway(&mut client), FRAME_SIZE_ERROR);
+
+        let mut client = connect(&[]);
+        client.write_all(&frame(FRAME_SETTINGS, 0, 0, &[0, 5, 0, 0, 0x10, 0])).unwrap();
+        assert_eq!(goaway(&mut client), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_streams() {
+        let mut client = connect(&[]);
+        request(&mut client, 1, "/api/heartbeat");
+        let headers = read_frame(&mut client);
+        assert!(headers.kind == FRAME_HEADERS && headers.stream_id == 1 && headers.flags & FLAG_END_HEADERS != 0);
+        let headers = Decoder::new(4096).decode(&headers.payload, 16384).unwrap();
+        assert_eq!(headers[0], (String::from(":status"), String::from("200")));
+        let data = read_frame(&mut client);
+        assert!(data.kind == FRAME_DATA && data.stream_id == 1 && data.flags == FLAG_END_STREAM);
+        assert!(String::from_utf8_lossy(&data.payload).contains("pong"));
+
+        // the stream is closed
+        client.write_all(&frame(FRAME_DATA, 0, 1, b"late")).unwrap();
+        let window = read_frame(&mut client);
+        assert!(window.kind == FRAME_WINDOW_UPDATE && window.stream_id == 0);
+        let reset = read_frame(&mut client);
+        assert!(reset.kind == FRAME_RST_STREAM && reset.stream_id == 1 && reset.payload == STREAM_CLOSED.to_be_bytes());
+
+        // the streams of the client have odd ids which only grow
+        request(&mut client, 1, "/api/heartbeat");
+        assert_eq!(goaway(&mut client), PROTOCOL_ERROR);
+        let mut client = connect(&[]);
+        request(&mut client, 2, "/api/heartbeat");
+        assert_eq!(goaway(&mut client), PROTOCOL_ERROR);
+        let mut client = connect(&[]);
+        client.write_all(&frame(FRAME_DATA, 0, 3, b"idle")).unwrap();
+        assert_eq!(read_frame(&mut client).kind, FRAME_WINDOW_UPDATE);
+        assert_eq!(goaway(&mut client), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let mut client = connect(&[(SETTINGS_INITIAL_WINDOW_SIZE, 10)]);
+        request(&mut client, 1, "/api/heartbeat");
+        assert_eq!(read_frame(&mut client).kind, FRAME_HEADERS);
+        let data = read_frame(&mut client);
+        assert!(data.kind == FRAME_DATA && data.flags == 0 && data.payload.len() == 10);
+
+        // the rest of the response is sent once the client opens the window of the stream
+        client.write_all(&frame(FRAME_WINDOW_UPDATE, 0, 1, &1000u32.to_be_bytes())).unwrap();
+        let data = read_frame(&mut client);
+        assert!(data.kind == FRAME_DATA && data.flags == FLAG_END_STREAM && data.payload.len() > 10);
+
+        client.write_all(&frame(FRAME_WINDOW_UPDATE, 0, 0, &0u32.to_be_bytes())).unwrap();
+        assert_eq!(goaway(&mut client), FLOW_CONTROL_ERROR);
+    }
+
+    #[test]
+    fn test_send_data() {
+        // the response of the first stream is sent in the first turn, the second turn must skip it (fixed in 0e94e00)
+        let (pool, config) = (Arc::new(ThreadPool::new(1)), test_config());
+        let mut connection = Connection::new(Sent(Vec::new()), Vec::new(), "http", None, None, &pool, &config).unwrap();
+        for (id, length) in [(1, 100), (3, DEFAULT_FRAME_SIZE + 1000)] {
+            connection.streams.insert(id, stream(vec![b'a'; length]));
+        }
+        assert!(connection.send_data().is_ok());
+        assert!(connection.streams.is_empty());
+
+        let mut sent = connection.stream.0.as_slice();
+        let frames: Vec<(u32, u8, usize)> = (0..3).map(|_| read_frame(&mut sent)).map(|v| (v.stream_id, v.flags, v.payload.len())).collect();
+        assert_eq!(frames, [(1, FLAG_END_STREAM, 100), (3, 0, DEFAULT_FRAME_SIZE), (3, FLAG_END_STREAM, 1000)]);
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_streams() {
+        // the response of a stream is sent as soon as it's ready, a slow request doesn't hold the next streams
+        let (pool, config) = (Arc::new(ThreadPool::new(2)), test_config());
+        let mut connection = Connection::new(Sent(Vec::new()), Vec::new(), "http", None, None, &pool, &config).unwrap();
+        connection.streams.insert(1, stream(Vec::new()));
+        connection.streams.insert(3, stream(Vec::new()));
+        connection.dispatch(1, |_| {
+            thread::sleep(Duration::from_millis(500));
+            Ok(HTTPResponse::new(200, String::from("OK")))
+        });
+        connection.dispatch(3, |_| Ok(HTTPResponse::new(204, String::from("No Content"))));
+        let (stream_id, result) = connection.results.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(stream_id, 3);
+        assert!(connection.complete(stream_id, result).is_ok());
+        let headers = read_frame(&mut connection.stream.0.as_slice());
+        assert!(headers.kind == FRAME_HEADERS && headers.stream_id == 3 && headers.flags & FLAG_END_STREAM != 0);
+        assert!(connection.streams.contains_key(&1) && !connection.streams.contains_key(&3));
+
+        // the result of a stream reset by the client is dropped
+        connection.streams.remove(&1);
+        let (stream_id, result) = connection.results.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(stream_id, 1);
+        let sent = connection.stream.0.len();
+        assert!(connection.complete(stream_id, result).is_ok());
+        assert_eq!(connection.stream.0.len(), sent);
+    }
+}
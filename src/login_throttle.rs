@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::database_utils::{Comparison, Database, DatabaseError, Filter, Order, Row, Value, now};
+
+/// Throttles the password checks of `auth://login` and of the routes accepting HTTP Basic credentials.
+///
+/// Every failed attempt is recorded in the `login_failures` table, which is also the audit of the attempts. After
+/// `login_max_failures` failures in `login_failure_window` seconds from an IP or for a username, the IP or the
+/// username is locked out: its attempts are answered with an error 429 and a `Retry-After` header, without checking
+/// the password. A lockout lasts `login_lockout_time` seconds and doubles each time the same IP or username is locked
+/// out again, up to `login_max_lockout_time`:
+/// ```json
+/// {
+///     "login_max_failures":5,
+///     "login_failure_window":900,
+///     "login_lockout_time":60,
+///     "login_max_lockout_time":3600
+/// }
+/// ```
+/// The failures and the lockouts can be seen and lifted with:
+/// ```text
+/// webserver-rs logins list --limit 50
+/// webserver-rs logins unlock alice
+/// ```
+pub struct LoginThrottle {
+    max_failures: usize,
+    window: i64,
+    lockout: i64,
+    max_lockout: i64,
+}
+
+impl LoginThrottle {
+    /// Creates the throttle with the limits of the server config
+    pub fn from_config(config: &HashMap<String, String>) -> LoginThrottle {
+        let number = |key: &str, default: i64| config.get(key).and_then(|v| v.trim().parse::<i64>().ok()).filter(|v| *v > 0).unwrap_or(default);
+        LoginThrottle {
+            max_failures: number("login_max_failures", 5) as usize,
+            window: number("login_failure_window", 900),
+            lockout: number("login_lockout_time", 60),
+            max_lockout: number("login_max_lockout_time", 3600),
+        }
+    }
+
+    /// Returns the number of seconds before the IP and the username can try a password again, `None` if neither
+    /// is locked out
+    pub fn locked(&self, database: &Database, ip: &str, username: &str) -> Result<Option<i64>, DatabaseError> {
+        let now = now();
+        let mut retry_after = None;
+        for subject in subjects(ip, username) {
+            let filter = Filter::new().where_eq("subject", subject.as_str()).condition("locked_until", Comparison::Greater, now);
+            if let Some(lockout) = database.select_rows("login_lockouts", &filter)?.pop().map(Lockout::from_row) {
+                retry_after = retry_after.max(Some(lockout.locked_until - now));
+            }
+        }
+        Ok(retry_after)
+    }
+
+    /// Records a failed attempt and locks out the IP and the username which reached the maximum number of failures
+    pub fn failure(&self, database: &Database, ip: &str, username: &str, path: &str) -> Result<(), DatabaseError> {
+        let now = now();
+        database.insert_row("login_failures", &[("username", Value::from(username)), ("ip", Value::from(ip)), ("path", Value::from(path)), ("time", Value::from(now))])?;
+        for (subject, column, value) in [(format!("ip:{}", ip), "ip", ip), (format!("user:{}", username), "username", username)] {
+            if value.is_empty() {
+                continue;
+            }
+            let previous = database.select_rows("login_lockouts", &Filter::new().where_eq("subject", subject.as_str()))?.pop().map(Lockout::from_row);
+            // the failures which led to the previous lockout don't count again
+            let since = previous.as_ref().map(|v| v.locked_until).unwrap_or(0).max(now - self.window);
+            let filter = Filter::new().where_eq(column, value).condition("time", Comparison::GreaterOrEqual, since).limit(self.max_failures);
+            if database.select_rows("login_failures", &filter)?.len() < self.max_failures {
+                continue;
+            }
+            // the backoff starts over once the previous lockout is long gone
+            let lockouts = previous.filter(|v| v.locked_until > now - self.max_lockout).map(|v| v.lockouts).unwrap_or(0);
+            let duration = self.lockout.saturating_mul(1 << lockouts.min(30)).min(self.max_lockout);
+            warn!("Locked out {} for {} seconds after {} failed logins", subject, duration, self.max_failures);
+            let values = [("subject", Value::from(subject.as_str())), ("locked_until", Value::from(now + duration)), ("lockouts", Value::from(lockouts + 1))];
+            database.upsert_row("login_lockouts", &values, &["subject"])?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the lockouts of the username once its password was right
+    pub fn success(&self, database: &Database, username: &str) -> Result<(), DatabaseError> {
+        database.delete_rows("login_lockouts", &Filter::new().where_eq("subject", format!("user:{}", username)))?;
+        Ok(())
+    }
+}
+
+/// A row of the `login_lockouts` table
+struct Lockout {
+    locked_until: i64,
+    lockouts: i64,
+}
+
+impl Lockout {
+    fn from_row(row: Row) -> Lockout {
+        let integer = |column: &str| match row.get(column) {
+            Some(Value::Integer(v)) => *v,
+            _ => 0,
+        };
+        Lockout {locked_until: integer("locked_until"), lockouts: integer("lockouts")}
+    }
+}
+
+/// Runs the `logins` subcommand, returns what has to be printed
+pub fn run_command(database: &Database, args: &[String]) -> Result<String, String> {
+    let usage = "Usage:\n    webserver-rs logins list [--limit <n>]\n    webserver-rs logins unlock <username or IP>";
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let text = |row: &Row, column: &str| match row.get(column) {
+        Some(Value::String(v)) => v.clone(),
+        Some(Value::Integer(v)) => v.to_string(),
+        _ => String::new(),
+    };
+    match args.as_slice() {
+        ["list", options @ ..] => {
+            let limit = match options {
+                [] => 20,
+                ["--limit", v] => v.parse::<usize>().map_err(|_| format!("Invalid limit {}", v))?,
+                _ => return Err(String::from(usage)),
+            };
+            let filter = Filter::new().condition("locked_until", Comparison::Greater, now()).order_by("locked_until", Order::Descending);
+            let lockouts = database.select_rows("login_lockouts", &filter).map_err(|e| e.to_string())?;
+            let failures = database.select_rows("login_failures", &Filter::new().order_by("id", Order::Descending).limit(limit)).map_err(|e| e.to_string())?;
+            let mut lines = vec![format!("{} lockouts:", lockouts.len())];
+            lines.extend(lockouts.iter().map(|v| format!("{}\tuntil {}\tlockout {}", text(v, "subject"), text(v, "locked_until"), text(v, "lockouts"))));
+            lines.push(format!("Last {} failed logins:", failures.len()));
+            lines.extend(failures.iter().map(|v| format!("{}\t{:?}\t{}\t{}", text(v, "time"), text(v, "username"), text(v, "ip"), text(v, "path"))));
+            Ok(lines.join("\n"))
+        },
+        ["unlock", subject] => {
+            let mut count = 0;
+            for subject in [format!("user:{}", subject), format!("ip:{}", subject)] {
+                count += database.delete_rows("login_lockouts", &Filter::new().where_eq("subject", subject)).map_err(|e| e.to_string())?;
+            }
+            match count {
+                0 => Err(format!("{} isn't locked out", subject)),
+                _ => Ok(format!("Unlocked {}", subject)),
+            }
+        },
+        _ => Err(String::from(usage)),
+    }
+}
+
+/// The subjects of the lockouts which apply to an attempt
+fn subjects(ip: &str, username: &str) -> Vec<String> {
+    [("ip", ip), ("user", username)].iter().filter(|(_, v)| !v.is_empty()).map(|(k, v)| format!("{}:{}", k, v)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::login_throttle::*;
+    use crate::database_utils::tests::migrated_database;
+    use crate::request_handler::{HTTPCode, IncomingRequest, ServerStatus};
+    use crate::sessions::SessionStore;
+    use crate::auth::Authenticator;
+
+    #[test]
+    fn test_login_throttle() {
+        let database = migrated_database("login-throttle");
+        let config = HashMap::from([(String::from("login_max_failures"), String::from("3")), (String::from("login_lockout_time"), String::from("60"))]);
+        let auth = Authenticator::from_config(&config);
+        let throttle = &auth.throttle;
+
+        for _ in 0..2 {
+            throttle.failure(&database, "10.0.0.1", "alice", "/login").unwrap();
+        }
+        assert_eq!(None, throttle.locked(&database, "10.0.0.1", "alice").unwrap());
+        throttle.failure(&database, "10.0.0.2", "alice", "/login").unwrap();
+        // the username reached the limit, not the first IP
+        assert!(throttle.locked(&database, "10.0.0.3", "alice").unwrap().is_some_and(|v| v > 55 && v <= 60));
+        assert_eq!(None, throttle.locked(&database, "10.0.0.1", "bob").unwrap());
+
+        // the second lockout is twice as long
+        database.update_rows("login_lockouts", &[("locked_until", Value::from(now() - 1))], &Filter::new()).unwrap();
+        database.update_rows("login_failures", &[("time", Value::from(now() - 10))], &Filter::new()).unwrap();
+        for _ in 0..3 {
+            throttle.failure(&database, "", "alice", "/login").unwrap();
+        }
+        assert!(throttle.locked(&database, "", "alice").unwrap().is_some_and(|v| v > 115 && v <= 120));
+        throttle.success(&database, "alice").unwrap();
+        assert_eq!(None, throttle.locked(&database, "", "alice").unwrap());
+
+        // the routes accepting HTTP Basic credentials are throttled too
+        database.insert_row("requests_get", &[("path", Value::from("/tools")), ("callback", Value::from("data/pages/api/get/heartbeat.json")),
+            ("auth_level", Value::from(1)), ("params", Value::from("")), ("auth_scheme", Value::from("basic"))]).unwrap();
+        let route = || {
+            let headers = HashMap::from([(String::from("authorization"), String::from("Basic Ym9iOndyb25n"))]);
+            let mut request = IncomingRequest::from_parts("GET", "/tools", "HTTP/1.1", headers, Vec::new(), None, None);
+            let http_code = database.match_request(&mut request, &SessionStore::from_config(&HashMap::new()), &auth);
+            (http_code, request.retry_after())
+        };
+        for _ in 0..3 {
+            assert!(matches!(route(), (ServerStatus::Ok(HTTPCode::Err401), None)));
+        }
+        assert!(matches!(route(), (ServerStatus::Ok(HTTPCode::Err429), Some(v)) if v > 55));
+
+        let list = run_command(&database, &[String::from("list")]).unwrap();
+        assert!(list.starts_with("1 lockouts:\nuser:bob") && list.contains("Last 9 failed logins:"));
+        assert!(run_command(&database, &[String::from("unlock"), String::from("bob")]).is_ok());
+        assert!(run_command(&database, &[String::from("unlock"), String::from("bob")]).is_err());
+    }
+}
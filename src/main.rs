@@ -4,16 +4,39 @@ use std::net::TcpListener;
 use log::{LevelFilter, info, warn, error};
 
 use simplelog::{CombinedLogger, WriteLogger, TermLogger, TerminalMode, Config, ColorChoice};
-use std::{fs, fs::File, sync::Arc};
+use std::{fs, fs::File, sync::Arc, thread};
 use std::env;
 
 mod script_runner;
+mod script_workers;
 mod database_utils;
+mod migrations;
 mod request_handler;
 mod thread_pool;
+mod event_loop;
+mod fastcgi_client;
+mod server_config;
+mod reverse_proxy;
+mod crypto_utils;
+mod auth;
+mod sessions;
+mod api_keys;
+mod roles;
+mod login_throttle;
+mod rate_limiter;
+mod totp;
+mod websocket;
+mod event_stream;
+mod hpack;
+mod http2;
 
-use crate::thread_pool::*;
-use crate::request_handler::*;
+use crate::event_loop::EventLoop;
+use crate::thread_pool::ThreadPool;
+use crate::server_config::{ServerConfig, flatten_config};
+use crate::database_utils::Database;
+
+/// A subcommand managing the database, returns what it prints or the error which ends the process
+type Command = fn(&Database, &[String]) -> Result<String, String>;
 
 fn main() {
     let mut pythonpath = env::var_os("PYTHONPATH").unwrap_or_default().into_string().unwrap_or_default();
@@ -30,12 +53,11 @@ fn main() {
             println!("WARN: didn't found any file specified in SERVER_CONFIG env variable, defaulting to 'data/config.json'");
             "data/config.json".to_string()},
     };
-    let mut config_string = fs::read_to_string(server_config_file).unwrap();
-    config_string = config_string.replace(['\"', '\n'], "")
-        .strip_prefix('{').unwrap().to_string()
-        .strip_suffix('}').unwrap().to_string();
-
-    let config = parse_hashmap(config_string.trim(), ",", ":");
+    let config_json = match json::parse(&fs::read_to_string(&server_config_file).unwrap()) {
+        Ok(v) => v,
+        Err(e) => panic!("Invalid config file {}: {}", server_config_file, e),
+    };
+    let config = flatten_config(&config_json);
 
     let logging_level: LevelFilter = match config.get("log_level") {
         Some(v) => match v.trim() {
@@ -52,18 +74,67 @@ fn main() {
     let tlogger = TermLogger::new(logging_level,Config::default(),TerminalMode::Stdout,ColorChoice::Auto);
     let wlogger = WriteLogger::new(logging_level,Config::default(),logfile);
     CombinedLogger::init(vec![tlogger, wlogger]).unwrap();
-    
+
+    let migrate_only = env::args().nth(1).as_deref() == Some("migrate");
+    if migrate_only || config.get("auto_migrate").map(|v| v.trim() != "false").unwrap_or(true) {
+        match migrations::migrate(&Database::from_config(&config)) {
+            Ok(v) if migrate_only => println!("Applied {} migrations to {}", v.len(), config.get("database").map(String::as_str).unwrap_or_default()),
+            Ok(_) => (),
+            Err(e) => panic!("Could not migrate the database: {}", e),
+        }
+    }
+    if migrate_only {
+        return;
+    }
+    let run_command: Option<Command> = match env::args().nth(1).as_deref() {
+        Some("api-key") => Some(api_keys::run_command),
+        Some("role") => Some(roles::run_command),
+        Some("logins") => Some(login_throttle::run_command),
+        Some("totp") => Some(totp::run_command),
+        _ => None,
+    };
+    if let Some(run_command) = run_command {
+        match run_command(&Database::from_config(&config), &env::args().skip(2).collect::<Vec<String>>()) {
+            Ok(v) => println!("{}", v),
+            Err(e) => {eprintln!("{}", e); std::process::exit(1)},
+        }
+        return;
+    }
+
     let listener = TcpListener::bind(config.get("ip").unwrap()).unwrap();
-    let pool = ThreadPool::new(4);
+    let blocking_threads = config.get("blocking_threads").and_then(|v| v.trim().parse::<usize>().ok()).filter(|v| *v > 0).unwrap_or(16);
     println!("Starting server on {}", listener.local_addr().unwrap());
 
-    let database = Arc::new(config.get("database").unwrap().to_string());
-    for stream in listener.incoming() {
-        let arc_db = database.clone();
-        let stream = stream.unwrap();
-        pool.execute(move || {
-            handle_connection(stream, &arc_db);
+    let server_config = Arc::new(ServerConfig::from_config(&config_json));
+    server_config.sessions.start_cleanup(&server_config.database);
+    let pool = Arc::new(ThreadPool::new(blocking_threads));
+    if let (Some(tls_ip), Some(certificate), Some(private_key)) = (config.get("tls_ip"), config.get("tls_certificate"), config.get("tls_private_key")) {
+        let tls = match http2::tls_config(certificate, private_key) {
+            Ok(v) => v,
+            Err(e) => panic!("{}", e),
+        };
+        let tls_listener = TcpListener::bind(tls_ip).unwrap();
+        println!("Starting HTTP/2 over TLS on {}", tls_listener.local_addr().unwrap());
+        let (tls_pool, tls_config) = (pool.clone(), server_config.clone());
+        thread::spawn(move || {
+            for stream in tls_listener.incoming() {
+                let (tls, arc_pool, arc_config) = (tls.clone(), tls_pool.clone(), tls_config.clone());
+                let stream = match stream {
+                    Ok(v) => v,
+                    Err(e) => {warn!("Error when accepting TLS connection: {}", e); continue},
+                };
+                // the connection is closed right away when too many are open
+                match tls_config.http2.reserve() {
+                    Some(slot) => {thread::spawn(move || {http2::serve_tls(stream, tls, &arc_pool, &arc_config); drop(slot)});},
+                    None => warn!("Too many HTTP/2 connections open, refusing {:?}", stream.peer_addr()),
+                };
+            }
         });
+    }
+    let event_loop = match EventLoop::new(listener, &pool, &server_config) {
+        Ok(v) => v,
+        Err(e) => panic!("Could not start the event loop: {}", e),
     };
+    error!("The event loop stopped: {}", event_loop.run());
     println!("shutting down")
 }
\ No newline at end of file
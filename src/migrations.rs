@@ -0,0 +1,112 @@
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::database_utils::{Comparison, Database, DatabaseError, Filter, Value, now};
+
+/// A change of the database schema, the migrations are applied once each in the order of their versions
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Embeds the file `migrations/<name>.sql` in the binary
+macro_rules! migration {
+    ($version:expr, $name:expr) => {
+        Migration {version: $version, name: $name, sql: include_str!(concat!("../migrations/", $name, ".sql"))}
+    };
+}
+
+/// Every migration of the schema, a new one is added at the end with the next version.
+///
+/// The first ones create the tables and the pages of a fresh database, their statements do nothing on a database
+/// made before the migrations existed, so a column is only added to their tables with an `ALTER TABLE` migration.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_initial_schema"),
+    migration!(2, "0002_seed_pages"),
+    migration!(3, "0003_native_auth"),
+    migration!(4, "0004_sessions"),
+    migration!(5, "0005_csrf"),
+    migration!(6, "0006_api_keys"),
+    migration!(7, "0007_auth_scheme"),
+    migration!(8, "0008_roles"),
+    migration!(9, "0009_login_throttle"),
+    migration!(10, "0010_rate_limits"),
+    migration!(11, "0011_totp"),
+    migration!(12, "0012_script_limits"),
+];
+
+/// Applies the migrations missing from the `schema_version` table and returns their versions.
+///
+/// Each migration runs in its own transaction with its row in `schema_version`, so that a failed migration leaves
+/// the database as it was and two servers starting together don't apply it twice. The migrations are applied
+/// when the server starts unless `auto_migrate` is false in the server config, or with:
+/// ```text
+/// webserver-rs migrate
+/// ```
+pub fn migrate(database: &Database) -> Result<Vec<i64>, DatabaseError> {
+    database.transaction(|t| t.execute("CREATE TABLE IF NOT EXISTS schema_version(version INTEGER PRIMARY KEY, name TEXT NOT NULL, applied_at INTEGER NOT NULL)"))?;
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS {
+        let done = database.transaction(|t| {
+            if !t.select_rows("schema_version", &Filter::new().where_eq("version", migration.version))?.is_empty() {
+                return Ok(false);
+            }
+            t.execute(migration.sql)?;
+            t.insert_row("schema_version", &[("version", Value::from(migration.version)), ("name", Value::from(migration.name)), ("applied_at", Value::from(now()))])?;
+            Ok(true)
+        });
+        match done {
+            Ok(true) => {
+                info!("Applied migration {}", migration.name);
+                applied.push(migration.version);
+            },
+            Ok(false) => (),
+            Err(e) => {
+                error!("Error when applying migration {}: {}", migration.name, e);
+                return Err(e);
+            },
+        }
+    }
+    let latest = MIGRATIONS.last().map(|v| v.version).unwrap_or_default();
+    let newer = Filter::new().condition("version", Comparison::Greater, latest);
+    if !database.select_rows("schema_version", &newer)?.is_empty() {
+        warn!("The database has migrations newer than this server, the latest it knows is {}", latest);
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migrations::*;
+    use crate::database_utils::tests::test_database;
+
+    #[test]
+    fn test_migrate() {
+        let database = test_database("migrate", "");
+
+        let versions: Vec<i64> = MIGRATIONS.iter().map(|v| v.version).collect();
+        assert!(versions.windows(2).all(|v| v[0] < v[1]));
+        assert_eq!(versions, migrate(&database).unwrap());
+        assert_eq!(Vec::<i64>::new(), migrate(&database).unwrap());
+
+        let route = database.request_row("requests_get", "path", "/api/heartbeat").unwrap();
+        assert_eq!("data/pages/api/get/heartbeat.json", route["callback"]);
+        assert_eq!("NOT FOUND", database.request_row("errors", "name", "err404").unwrap()["response_message"]);
+        assert!(std::path::Path::new(&database.request_row("errors", "name", "err405").unwrap()["callback"]).exists());
+    }
+
+    #[test]
+    fn test_migrate_existing_database() {
+        // the tables of a database made before the migrations existed
+        let database = test_database("migrate-existing", "CREATE TABLE requests_get(path TEXT PRIMARY KEY, callback TEXT, auth_level INTEGER, params TEXT);
+            CREATE TABLE requests_post(path TEXT PRIMARY KEY, callback TEXT, auth_level INTEGER, params TEXT);
+            INSERT INTO requests_get VALUES ('/api/slow', 'data/pages/api/get/slow.json', 0, '');");
+        migrate(&database).unwrap();
+
+        database.update_rows("requests_get", &[("script_timeout", Value::from(2.5))], &Filter::new().where_eq("path", "/api/slow")).unwrap();
+        let route = database.request_row("requests_get", "path", "/api/slow").unwrap();
+        assert_eq!(("data/pages/api/get/slow.json", "2.5"), (route["callback"].as_str(), route["script_timeout"].as_str()));
+        assert!(database.request_row("requests_post", "path", "/login").unwrap().contains_key("script_output_size"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use json::JsonValue;
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::request_handler::IncomingRequest;
+
+/// The buckets which are full again are forgotten every minute, so that the clients which stopped don't stay in memory
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits the number of requests of each client with token buckets, before the route runs any script.
+///
+/// The limits are set per path prefix in the server config, the longest prefix which matches the path is used. They are
+/// counted for the IP of the client before the request is routed, so that a client over its limit costs no session
+/// lookup nor password check:
+/// ```json
+/// {
+///     "rate_limits":{
+///         "/":{"requests":600, "period":60},
+///         "/api/":{"requests":60, "period":60}
+///     }
+/// }
+/// ```
+/// The limits can also be set per route with the `rate_limit` (requests) and `rate_limit_period` (seconds, 60 by default)
+/// columns of the `requests_*` tables. They are counted once the route is known, on top of the limit of the prefix, for
+/// the API key or the user when the request is authenticated and the IP otherwise. A client can send `requests` requests
+/// at once, then one every `period / requests` seconds. Its requests past the limit are answered with an error 429, every response of a
+/// limited path has the `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset` and `RateLimit-Policy` headers.
+pub struct RateLimiter {
+    /// The limits of the config, the longest prefix first
+    prefixes: Vec<(String, RateLimit)>,
+    buckets: Mutex<Buckets>,
+}
+
+/// A number of requests allowed per period of seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: u64,
+}
+
+/// The bucket of a client once a request was counted, sent in the `RateLimit-*` headers of the response
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStatus {
+    pub limit: RateLimit,
+    pub remaining: u32,
+    /// The number of seconds before the bucket is full again
+    pub reset: u64,
+    /// The number of seconds before the client can send a request again, `None` if the request is allowed
+    pub retry_after: Option<u64>,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    last_prune: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limit: RateLimit,
+}
+
+impl RateLimit {
+    /// Reads the limit of a route from its database row, `None` if it has no `rate_limit`
+    pub fn from_map(map: &HashMap<String, String>) -> Option<RateLimit> {
+        Some(RateLimit {
+            requests: map.get("rate_limit").and_then(|v| v.trim().parse::<u32>().ok()).filter(|v| *v > 0)?,
+            period: map.get("rate_limit_period").and_then(|v| v.trim().parse::<u64>().ok()).filter(|v| *v > 0).unwrap_or(60),
+        })
+    }
+
+    /// The number of tokens added to a bucket each second
+    fn rate(&self) -> f64 {
+        self.requests as f64 / self.period as f64
+    }
+}
+
+impl Bucket {
+    /// Adds the tokens earned since the last update
+    fn refill(&mut self, now: Instant) {
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.limit.rate()).min(self.limit.requests as f64);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    /// Creates the limiter with the `rate_limits` object of the server config, invalid entries are ignored
+    pub fn from_config(rate_limits: &JsonValue) -> RateLimiter {
+        let mut prefixes: Vec<(String, RateLimit)> = rate_limits.entries()
+            .filter_map(|(prefix, v)| match (v["requests"].as_u32().filter(|v| *v > 0), v["period"].as_u64().unwrap_or(60)) {
+                (Some(requests), period) if period > 0 => Some((prefix.to_string(), RateLimit {requests, period})),
+                _ => {warn!("Ignored the invalid rate limit of {}", prefix); None},
+            })
+            .collect();
+        prefixes.sort_by_key(|v| std::cmp::Reverse(v.0.len()));
+        RateLimiter {prefixes, buckets: Mutex::new(Buckets {buckets: HashMap::new(), last_prune: Instant::now()})}
+    }
+
+    /// Counts a request of the client with the limit of the longest prefix of the path, returns `None` if the path has no limit
+    pub fn check_prefix(&self, path: &str, client: &str) -> Option<RateLimitStatus> {
+        let (prefix, limit) = self.prefixes.iter().find(|(prefix, _)| path.starts_with(prefix.as_str()))?;
+        Some(self.check(prefix, *limit, client))
+    }
+
+    /// Counts a request of the client with the limit of its route, `route` is a name unique to the route
+    pub fn check_route(&self, route: &str, limit: RateLimit, client: &str) -> RateLimitStatus {
+        self.check(route, limit, client)
+    }
+
+    /// Takes a token from the bucket of the client for a rule, the clients have a bucket for each route and each prefix
+    fn check(&self, rule: &str, limit: RateLimit, client: &str) -> RateLimitStatus {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(buckets.last_prune) >= PRUNE_INTERVAL {
+            buckets.buckets.retain(|_, v| {v.refill(now); v.tokens < v.limit.requests as f64});
+            buckets.last_prune = now;
+        }
+        let bucket = buckets.buckets.entry(format!("{} {}", rule, client)).or_insert(Bucket {tokens: limit.requests as f64, updated: now, limit});
+        bucket.limit = limit;
+        bucket.refill(now);
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        RateLimitStatus {
+            limit,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((limit.requests as f64 - bucket.tokens) / limit.rate()).ceil() as u64,
+            retry_after: (!allowed).then(|| ((1.0 - bucket.tokens) / limit.rate()).ceil().max(1.0) as u64),
+        }
+    }
+}
+
+impl RateLimitStatus {
+    /// Returns the `RateLimit-*` headers (draft-ietf-httpapi-ratelimit-headers) of the response
+    pub fn headers(&self) -> HashMap<String, String> {
+        HashMap::from([
+            (String::from("RateLimit-Limit"), self.limit.requests.to_string()),
+            (String::from("RateLimit-Remaining"), self.remaining.to_string()),
+            (String::from("RateLimit-Reset"), self.reset.to_string()),
+            (String::from("RateLimit-Policy"), format!("{};w={}", self.limit.requests, self.limit.period)),
+        ])
+    }
+}
+
+/// Returns the client a request is counted for: its API key, its user, or its IP
+pub fn client(incoming_request: &IncomingRequest) -> String {
+    match incoming_request.user() {
+        Some(user) => match &user.api_key {
+            Some(key) => format!("key:{}", key.id),
+            None => format!("user:{}", user.username),
+        },
+        None => format!("ip:{}", incoming_request.remote_addr().map(|v| v.ip().to_string()).unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limiter::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let config = json::parse(r#"{"/":{"requests":3, "period":60}, "/api/":{"requests":1}, "/bad/":{"requests":0}}"#).unwrap();
+        let limiter = RateLimiter::from_config(&config);
+        let retry_after = |path: &str, client: &str| limiter.check_prefix(path, client).unwrap().retry_after;
+
+        for _ in 0..3 {
+            assert_eq!(None, retry_after("/index", "ip:10.0.0.1"));
+        }
+        assert!(retry_after("/index", "ip:10.0.0.1").is_some_and(|v| v > 0 && v <= 20));
+        assert_eq!(None, retry_after("/index", "ip:10.0.0.2"));
+        // each prefix has its own buckets
+        assert_eq!(None, retry_after("/api/flipbot", "ip:10.0.0.1"));
+        assert!(retry_after("/api/flipbot", "ip:10.0.0.1").is_some_and(|v| v > 55 && v <= 60));
+        assert_eq!(None, retry_after("/bad/path", "ip:10.0.0.2"));
+
+        // the bucket of a route is apart from the one of its prefix
+        let status = limiter.check_route("GET /api/flipbot", RateLimit {requests: 10, period: 1}, "ip:10.0.0.1");
+        assert_eq!((9, 1, None), (status.remaining, status.reset, status.retry_after));
+        assert_eq!(Some(&String::from("10;w=1")), status.headers().get("RateLimit-Policy"));
+        assert_eq!(None, RateLimiter::from_config(&JsonValue::Null).check_prefix("/index", "ip:10.0.0.1"));
+
+        let row = HashMap::from([(String::from("rate_limit"), String::from("5")), (String::from("rate_limit_period"), String::new())]);
+        assert_eq!(Some(RateLimit {requests: 5, period: 60}), RateLimit::from_map(&row));
+        assert_eq!(None, RateLimit::from_map(&HashMap::new()));
+    }
+}
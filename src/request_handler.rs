@@ -1,13 +1,24 @@
 use std::net::{SocketAddr, Ipv4Addr, IpAddr, TcpStream};
-use std::{fs, str, io::prelude::*, io::BufReader};
+use std::{fs, str};
 use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[allow(unused_imports)]
 use log::{debug, info, warn, error};
 
 use crate::script_runner::*;
-use crate::database_utils::Database;
+use crate::database_utils::{Database, DatabaseError, Value};
+use crate::server_config::ServerConfig;
+use crate::fastcgi_client::{FastCgiEndpoint, run_fastcgi};
+use crate::websocket::WebSocketServer;
+use crate::event_stream::EventStreamServer;
+use crate::auth::{AuthSchemes, Authenticator, Challenge, basic_credentials, check_csrf, verify_user};
+use crate::sessions::{SessionStore, random_token};
+use crate::api_keys::{ApiKey, bearer_token};
+use crate::roles::{Permissions, route_permissions};
+use crate::totp::LoginChallenge;
+use crate::rate_limiter::{RateLimit, RateLimitStatus, RateLimiter, client};
 
 /// The response which is sent to the client if there was an error in the [handle_connection] function or any function called by it.
 /// 
@@ -24,84 +35,161 @@ use crate::database_utils::Database;
 /// }
 /// ```
 static ERR500: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Length: 188\r\n\r\n{\n    \"status\":\"error\",\n    \"status_code\":\"500\",\n    \"message\":\"internal server error\",\n    \"result\":[\"There was an internal server error, if the issue persists please contact support.\"]\n}";
-/// Sends back to the client `stream` the message `msg`
+/// Returns the message `msg` with the stream to the [EventLoop](crate::event_loop::EventLoop), which sends it back to the client
 ///
 /// # Example
 /// ```
-/// let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
 /// let message = "HTTP/1.1 200 OK";
-/// send!(listener.incoming(), message);
+/// respond!(stream, message.as_bytes());
 /// ```
 /// this will send and OK message to the client
-macro_rules! send {
+macro_rules! respond {
     ($stream: expr, $msg:expr) => {
         {
-            match $stream.write_all($msg) {
-                Ok(_) => (),
-                Err(e) => {info!("Error when writing data to stream: {}", e);},
-            }
-            match $stream.flush() {
-                Ok(_) => (),
-                Err(e) => {info!("Error when flushing data to client: {}", e);},
-            };
-            return;
+            return Some(($stream, $msg.to_vec()));
         }
     };
 }
 
-/// Handles the incoming HTTP request
-/// Parses the HTTP request, gets the page in the database, runs the script associated or returns the html or json files
-/// 
+/// Handles an HTTP request read by the [EventLoop](crate::event_loop::EventLoop), on a thread of the blocking pool.
+/// Gets the page in the database, runs the script associated or returns the html or json files
+///
+/// Returns the stream and the response the event loop has to send, `None` means the connection was taken over by
+/// a route (proxy, WebSocket or Server-Sent Events) or has nothing to answer.
+///
 /// # Example:
 /// ```
-/// let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-/// handle_connection(listener.incoming(), "database.db");
+/// let request = IncomingRequest::from_bytes(&buffer, stream.peer_addr().ok(), stream.local_addr().ok());
+/// let (stream, response) = handle_connection(stream, request, &server_config).unwrap();
 /// ```
-/// this will send back to the client the result of the HTTP request made to `127.0.0.1:7878` by said client
-pub fn handle_connection(mut stream: TcpStream, database_filepath: &str) {
-    let database = Database::new(database_filepath);
-    let incoming_request = match IncomingRequest::parse_request(&stream){
-        ParsedRequest::Ok(v) => v,
-        ParsedRequest::Empty => {return},
-        ParsedRequest::BadRequest => {
+/// this will return the response to the HTTP request made by the client
+pub fn handle_connection(mut stream: TcpStream, request: ParsedRequest, config: &Arc<ServerConfig>) -> Option<(TcpStream, Vec<u8>)> {
+    let database = &config.database;
+    let limits = &config.request_limits;
+    if let Err(e) = stream.set_write_timeout(Some(limits.write_timeout)).and_then(|_| stream.set_read_timeout(Some(limits.read_timeout))) {
+        info!("Error when setting the timeouts: {}", e);
+    }
+    let error_code = match request {
+        ParsedRequest::Ok(v) => Ok(v),
+        ParsedRequest::Empty => return None,
+        ParsedRequest::BadRequest => Err(HTTPCode::Err400),
+        ParsedRequest::Timeout => Err(HTTPCode::Err408),
+        ParsedRequest::PayloadTooLarge => Err(HTTPCode::Err413),
+        ParsedRequest::UriTooLong => Err(HTTPCode::Err414),
+        ParsedRequest::HeadersTooLarge => Err(HTTPCode::Err431),
+    };
+    let mut incoming_request = match error_code {
+        Ok(v) => v,
+        Err(http_code) => {
             info!("An invalid request has been formulated by {}", stream.peer_addr().unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)));
-            let mut http_response = match database.get_error(HTTPCode::Err400, &IncomingRequest::new()) {
+            let mut http_response = match database.get_error(http_code, &IncomingRequest::new(), &config.script_runner) {
                 ServerStatus::Ok(Some(v)) => v,
-                _ => {send!(stream, ERR500.as_bytes());}
+                _ => {respond!(stream, ERR500.as_bytes());}
             };
             let response = http_response.prepare_response();
-            send!(stream, &response);
+            respond!(stream, &response);
         }
     };
 
+    // a client over the limit of its IP doesn't get to the session lookups nor the password checks of the route
+    let http_code = match incoming_request.limit_prefix_rate(&config.rate_limiter) {
+        false => HTTPCode::Err429,
+        true => match database.match_request(&mut incoming_request, &config.sessions, &config.auth) {
+            ServerStatus::Ok(v) => v,
+            ServerStatus::Error(v) => v,
+            ServerStatus::InternalError => {
+                respond!(stream, ERR500.as_bytes())
+            },
+        },
+    };
+    let http_code = incoming_request.limit_route_rate(http_code, &config.rate_limiter);
     debug!("{}\n", incoming_request.as_json());
 
-    let http_code = match database.match_request(&incoming_request) {
-        ServerStatus::Ok(v) => v,
-        ServerStatus::InternalError => {
-            send!(stream, ERR500.as_bytes())
+    let error_response = |http_code: HTTPCode| build_response(http_code, &incoming_request, config);
+    let http_response = match http_code {
+        HTTPCode::Ok200(v) if v.callback.starts_with("proxy://") => {
+            match config.reverse_proxy.forward(&mut stream, &incoming_request, &v.path, &v.callback) {
+                ServerStatus::Ok(()) => return None,
+                ServerStatus::Error(v) => error_response(v),
+                ServerStatus::InternalError => None,
+            }
+        },
+        // the body is still on the stream, only the reverse proxy reads it
+        HTTPCode::Ok200(_) if incoming_request.pending_body() > 0 => error_response(HTTPCode::Err400),
+        HTTPCode::Ok200(v) if WebSocketServer::is_endpoint(&v.callback) => {
+            match config.websockets.accept(&stream, &incoming_request, &v.callback, &config.script_runner, &v.script_limits) {
+                ServerStatus::Ok(()) => return None,
+                ServerStatus::Error(v) => error_response(v),
+                ServerStatus::InternalError => None,
+            }
+        },
+        HTTPCode::Ok200(v) if EventStreamServer::is_endpoint(&v.callback) => {
+            match config.event_streams.accept(&stream, &incoming_request, &v.callback, &config.script_runner, &v.script_limits) {
+                ServerStatus::Ok(()) => return None,
+                ServerStatus::Error(v) => error_response(v),
+                ServerStatus::InternalError => None,
+            }
         },
+        _ => error_response(http_code),
     };
-
-    let response = match http_code {
-        HTTPCode::Ok200(v) => {match HTTPResponse::from_matched_request(v, *incoming_request) {
-            ServerStatus::Ok(mut v) => v.prepare_response(),
-            _ => {send!(stream, ERR500.as_bytes());}
-        }},
-        _ => {
-            let mut http_response = match database.get_error(http_code, &incoming_request) {
-                ServerStatus::Ok(Some(v)) => v,
-                _ => {send!(stream, ERR500.as_bytes());}
-            };
-            http_response.prepare_response()
-        }
+    let response = match http_response {
+        Some(mut v) => v.prepare_response(),
+        None => {respond!(stream, ERR500.as_bytes());}
     };
     match std::str::from_utf8(&response) {
         Ok(v) => debug!("{:?}", v),
         Err(_) => debug!("{:?}", response),
     }
 
-    send!(stream, &response);
+    respond!(stream, &response);
+}
+
+/// Runs the route matched for a request, or loads the page of the error it ran into, `None` means an error 500 has to be sent.
+///
+/// The routes which take over the connection (see [MatchedRequest::takes_over_connection]) have to be handled before.
+pub fn build_response(http_code: HTTPCode, incoming_request: &IncomingRequest, config: &ServerConfig) -> Option<HTTPResponse> {
+    let (database, script_runner) = (&config.database, &config.script_runner);
+    let error_response = |http_code: HTTPCode| match database.get_error(http_code, incoming_request, script_runner) {
+        ServerStatus::Ok(v) => v,
+        _ => None,
+    };
+    let challenge = incoming_request.challenge.filter(|_| matches!(http_code, HTTPCode::Err401));
+    let retry_after = incoming_request.retry_after.filter(|_| matches!(http_code, HTTPCode::Err429));
+    let rate_limit = incoming_request.rate_limit;
+    let response = match http_code {
+        HTTPCode::Ok200(v) if Authenticator::is_endpoint(&v.callback) => match config.auth.handle(&v.callback, incoming_request, database, &config.sessions) {
+            ServerStatus::Ok(v) => Some(v),
+            ServerStatus::Error(v) => error_response(v),
+            ServerStatus::InternalError => None,
+        },
+        HTTPCode::Ok200(v) => match HTTPResponse::from_matched_request(v, incoming_request, script_runner) {
+            ServerStatus::Ok(v) => Some(v),
+            ServerStatus::Error(v) => error_response(v),
+            ServerStatus::InternalError => None,
+        },
+        _ => error_response(http_code),
+    };
+    // the cookie is set on the next response if the route sets its own cookie, a response has only one `Set-Cookie`
+    response.map(|mut v| {
+        if let Some(retry_after) = retry_after {
+            v.add_headers(HashMap::from([(String::from("Retry-After"), retry_after.to_string())]));
+        }
+        if let Some(rate_limit) = rate_limit {
+            v.add_headers(rate_limit.headers());
+        }
+        // the client is asked for credentials instead of being sent to the login page of the error 401
+        if let Some(challenge) = challenge {
+            v.response_code = 401;
+            v.response_message = String::from("UNAUTHORIZED");
+            v.headers.retain(|k, _| !k.eq_ignore_ascii_case("location") && !k.eq_ignore_ascii_case("set-cookie"));
+            v.add_headers(HashMap::from([(String::from("WWW-Authenticate"), config.auth.challenge_header(challenge))]));
+        }
+        if incoming_request.new_csrf_token && !v.headers.keys().any(|k| k.eq_ignore_ascii_case("set-cookie")) {
+            let cookie = format!("csrfToken={}; Path=/; HttpOnly; SameSite=Lax", incoming_request.csrf_token);
+            v.add_headers(HashMap::from([(String::from("Set-Cookie"), cookie)]));
+        }
+        v
+    })
 }
 
 //--//
@@ -113,10 +201,30 @@ pub struct IncomingRequest {
     method: String,
     path: String,
     query: HashMap<String, String>,
+    query_string: String,
     _version: String,
     headers: HashMap<String, String>,
     cookies: HashMap<String, String>,
-    body: String,
+    body: Vec<u8>,
+    /// The number of bytes of the body still on the stream, see [IncomingRequest::from_head]
+    pending_body: usize,
+    /// The scheme of the listener which received the request, `http` or `https`
+    scheme: &'static str,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    /// The user of the session, set by [Database::match_request]
+    user: Option<AuthenticatedUser>,
+    /// The token the forms have to send back, the one of the session or of the `csrfToken` cookie, see [check_csrf]
+    csrf_token: String,
+    /// The client has no `csrfToken` cookie yet, the response sets it
+    new_csrf_token: bool,
+    /// The challenge of the response if it is an error 401, set by [Database::match_request]
+    challenge: Option<Challenge>,
+    /// The number of seconds sent in the `Retry-After` header of an error 429, set by [Database::match_request],
+    /// [IncomingRequest::limit_prefix_rate] or [IncomingRequest::limit_route_rate]
+    retry_after: Option<i64>,
+    /// The bucket of the client once the request was counted, set by [IncomingRequest::limit_prefix_rate] and [IncomingRequest::limit_route_rate]
+    rate_limit: Option<RateLimitStatus>,
 }
 
 impl IncomingRequest {
@@ -126,12 +234,74 @@ impl IncomingRequest {
             method: String::new(), 
             path: String::new(),
             query: HashMap::new(), 
+            query_string: String::new(),
             _version: String::new(),
             headers: HashMap::new(), 
             cookies: HashMap::new(), 
-            body: String::new()}
+            body: Vec::new(),
+            pending_body: 0,
+            scheme: "http",
+            remote_addr: None,
+            local_addr: None,
+            user: None,
+            csrf_token: String::new(),
+            new_csrf_token: false,
+            challenge: None,
+            retry_after: None,
+            rate_limit: None}
+    }
+    /// Returns the length of the request at the start of `buffer` once its head has been received, so that the
+    /// event loop knows how many bytes to read before handing it to [IncomingRequest::from_bytes].
+    ///
+    /// `Ok(None)` means the head is still incomplete, the returned length can be larger than the buffer while the body
+    /// is being received. The limits are checked as soon as possible so that an oversized request is refused before
+    /// it is buffered.
+    pub fn request_length(buffer: &[u8], limits: &RequestLimits) -> Result<Option<usize>, ParsedRequest> {
+        let request_line_end = match buffer.iter().position(|v| *v == b'\n') {
+            Some(v) => v + 1,
+            None if buffer.len() > limits.max_request_line => return Err(ParsedRequest::UriTooLong),
+            None => return Ok(None),
+        };
+        if request_line_end > limits.max_request_line {
+            return Err(ParsedRequest::UriTooLong);
+        }
+        let mut content_length: usize = 0;
+        let mut header_count: usize = 0;
+        let mut header_bytes: usize = 0;
+        let mut start = request_line_end;
+        loop {
+            let line_end = match buffer[start..].iter().position(|v| *v == b'\n') {
+                Some(v) => start + v + 1,
+                None if header_bytes + buffer.len() - start >= limits.max_header_bytes => return Err(ParsedRequest::HeadersTooLarge),
+                None => return Ok(None),
+            };
+            let line = &buffer[start..line_end];
+            start = line_end;
+            if line == b"\r\n" || line == b"\n" {break}
+
+            header_bytes += line.len();
+            header_count += 1;
+            if header_bytes >= limits.max_header_bytes || header_count > limits.max_headers {
+                return Err(ParsedRequest::HeadersTooLarge);
+            }
+            let line = String::from_utf8_lossy(line);
+            let (key, value) = line.split_once(':').unwrap_or((&line, ""));
+            if key.trim_end().eq_ignore_ascii_case("content-length") {
+                content_length = match value.trim().parse::<usize>() {
+                    Ok(v) => v,
+                    _ => return Err(ParsedRequest::BadRequest),
+                };
+            }
+        }
+        if content_length > limits.max_body_size {
+            return Err(ParsedRequest::PayloadTooLarge);
+        }
+        Ok(Some(start + content_length))
     }
-    /// Parses an HTTP request and returns a [ParsedRequest], also see [IncomingRequest]
+
+    /// Parses an HTTP request whose bytes have all been received and returns a [ParsedRequest], also see [IncomingRequest].
+    ///
+    /// The limits have to be checked with [IncomingRequest::request_length] before.
     ///
     /// # Example
     ///
@@ -155,19 +325,20 @@ impl IncomingRequest {
     ///     headers: {"First-Header":"Value", "Content-Length":"31", "Content-Type":"application/json"}
     ///     body: "{\n\t"body":["thing1", "thing2"]\n}"
     /// }
-    pub fn parse_request(mut stream: &TcpStream) -> ParsedRequest {
-        let mut buf_reader = BufReader::new(&mut stream);
-        let mut request_line = String::new();
-        match buf_reader.read_line(&mut request_line) {
-            Ok(_) => (),
-            Err(_) => {
-                error!("Error when reading request line from ip {}:\n{:?}",
-                    stream.peer_addr().unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 80)),
-                    stream);
-                return ParsedRequest::BadRequest;
-            },
-        };
+    /// ```
+    pub fn from_bytes(buffer: &[u8], remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> ParsedRequest {
+        match IncomingRequest::from_head(buffer, remote_addr, local_addr) {
+            ParsedRequest::Ok(v) if v.pending_body > 0 => ParsedRequest::BadRequest,
+            v => v,
+        }
+    }
 
+    /// Parses an HTTP request whose head has been received but whose body may still be on the stream, the number of
+    /// bytes left is in [IncomingRequest::pending_body]. The event loop hands the requests with a large body over
+    /// this way, so that the reverse proxy can stream them to the upstream.
+    pub fn from_head(buffer: &[u8], remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> ParsedRequest {
+        let mut lines = buffer.split_inclusive(|v| *v == b'\n');
+        let request_line = String::from_utf8_lossy(lines.next().unwrap_or_default());
         let (method, uri, version) = match request_line.split_once(' ') {
             Some((method, rest)) => {
                 let (uri, version) = rest.split_once(' ').unwrap_or((rest, "HTTP/1.1"));
@@ -175,54 +346,92 @@ impl IncomingRequest {
             }
             None => return ParsedRequest::Empty,
         };
-        let (path, query_map): (String, HashMap<String, String>) = match uri.split_once('?') {
-            Some((path, query)) => {
-                (path.to_string(), parse_hashmap(query, "&", "="))
-            }
-            None => (uri.to_string(), HashMap::new()),
-        };
+        if !is_token(method) {
+            return ParsedRequest::BadRequest;
+        }
+        let (path, query_string) = uri.split_once('?').unwrap_or((uri, ""));
+        let (path, query_map) = (path.to_string(), parse_hashmap(query_string, "&", "="));
         let mut headers_map: HashMap<String, String> = HashMap::new();
+        let mut head_length = request_line.len();
         loop {
-            let mut line = String::new();
-            buf_reader.read_line(&mut line).unwrap();
-            if line.as_str() == "\r\n" {break}
+            let line = match lines.next() {
+                Some(v) => v,
+                None => return ParsedRequest::BadRequest,
+            };
+            head_length += line.len();
+            if line == b"\r\n" || line == b"\n" {break}
 
-            let mut header_splitted = line.split(':');
-            let key = String::from(header_splitted.next().unwrap_or("").trim_end());
-            let value = String::from(header_splitted.next().unwrap_or("").trim_end());
+            let line = String::from_utf8_lossy(line);
+            let (key, value) = line.split_once(':').unwrap_or((&line, ""));
+            let (key, value) = (String::from(key.trim_end()), String::from(value.trim_end()));
             headers_map.insert(key.to_lowercase(), value);
         }
         let cookie_map = match headers_map.get("cookie") {
             Some(s) => parse_hashmap(s, ";", "="),
             None => HashMap::new(),
         };
-        let mut body = String::new();
-        match headers_map.get("content-length") {
-            None => 0usize,
-            Some(v) => {
-                match v.trim().parse::<usize>() {
-                    Ok(v) => {
-                        let mut body_buffer = vec![0; v];
-                        buf_reader.read_exact(&mut body_buffer).unwrap();
-                        body = String::from_utf8_lossy(&body_buffer).to_string();
-                        v
-                    },
-                    _ => return ParsedRequest::BadRequest,
-                }
-            }
+        let length = match headers_map.get("content-length").map(|v| v.trim().parse::<usize>()) {
+            Some(Ok(v)) => v,
+            Some(Err(_)) => return ParsedRequest::BadRequest,
+            None => 0,
+        };
+        let body = match buffer.get(head_length..) {
+            Some(v) => v[..v.len().min(length)].to_vec(),
+            None => return ParsedRequest::BadRequest,
         };
+        let pending_body = length - body.len();
         let incoming = IncomingRequest {
             method: method.to_string(), 
             path,
             query: query_map, 
+            query_string: query_string.to_string(),
             _version: version.trim().to_string(),
             headers: headers_map, 
             cookies: cookie_map, 
-            body};
+            body,
+            pending_body,
+            scheme: "http",
+            remote_addr,
+            local_addr,
+            user: None,
+            csrf_token: String::new(),
+            new_csrf_token: false,
+            challenge: None,
+            retry_after: None,
+            rate_limit: None};
 
         ParsedRequest::Ok(Box::new(incoming))
     }
 
+    /// Builds a request from the parts received on an HTTP/2 stream, the `:authority` should already be in the `host` header
+    pub fn from_parts(method: &str, target: &str, version: &str, headers: HashMap<String, String>, body: Vec<u8>,
+        remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> IncomingRequest {
+        let (path, query_string) = target.split_once('?').unwrap_or((target, ""));
+        let cookies = match headers.get("cookie") {
+            Some(s) => parse_hashmap(s, ";", "="),
+            None => HashMap::new(),
+        };
+        IncomingRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: parse_hashmap(query_string, "&", "="),
+            query_string: query_string.to_string(),
+            _version: version.to_string(),
+            headers,
+            cookies,
+            body,
+            pending_body: 0,
+            scheme: "http",
+            remote_addr,
+            local_addr,
+            user: None,
+            csrf_token: String::new(),
+            new_csrf_token: false,
+            challenge: None,
+            retry_after: None,
+            rate_limit: None}
+    }
+
     /// Converts the incoming request to a json String in the following format:
     /// ```json
     /// {
@@ -232,10 +441,25 @@ impl IncomingRequest {
     ///     "version":"1.1",
     ///     "headers":{"accept-language":"en-US,en;q=0.9","cookie":"sessionID=9999;cookie2=hello"},
     ///     "cookies":{"sessionID":"9999","cookie2":"hello"},
+    ///     "user":{"username":"admin","auth_level":255,"session_id":"9999","session_expires":1700000000,"api_key":null,"roles":["admin"],"permissions":["*"]},
+    ///     "csrf_token":"5f2b...",
     ///     "body":"",
     /// }
     /// ```
+    /// `user` is `null` when the request has no valid session.
     pub fn as_json(&self) -> String {
+        let user = match &self.user {
+            Some(v) => json::stringify(json::object! {
+                username: v.username.as_str(),
+                auth_level: v.auth_level,
+                session_id: v.session_id.as_str(),
+                session_expires: v.session_expires,
+                api_key: v.api_key.as_ref().map(|v| v.id),
+                roles: v.permissions.roles.clone(),
+                permissions: v.permissions.permissions.clone(),
+            }),
+            None => String::from("null"),
+        };
         format!("{{
             \"method\":\"{}\",
             \"path\":\"{}\",
@@ -243,6 +467,8 @@ impl IncomingRequest {
             \"version\":\"{}\",
             \"headers\":{},
             \"cookies\":{},
+            \"user\":{},
+            \"csrf_token\":\"{}\",
             \"body\":\"{}\"
         }}", 
         self.method,
@@ -251,24 +477,254 @@ impl IncomingRequest {
         self._version,
         format!("{:?}", self.headers).replace(' ', ""),
         format!("{:?}", self.cookies).replace(' ', ""),
-        self.body)
+        user,
+        self.csrf_token,
+        String::from_utf8_lossy(&self.body))
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query_string(&self) -> &str {
+        &self.query_string
+    }
+
+    /// The headers of the request, the names are lowercase
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    pub fn cookies(&self) -> &HashMap<String, String> {
+        &self.cookies
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The number of bytes of the body which haven't been read from the stream yet
+    pub fn pending_body(&self) -> usize {
+        self.pending_body
+    }
+
+    pub fn scheme(&self) -> &str {
+        self.scheme
+    }
+
+    /// Sets the scheme of the listener which received the request, `http` by default
+    pub fn set_scheme(&mut self, scheme: &'static str) {
+        self.scheme = scheme;
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// The user of the session of the request, once it went through [Database::match_request]
+    pub fn user(&self) -> Option<&AuthenticatedUser> {
+        self.user.as_ref()
+    }
+
+    /// Counts the request in the bucket of its IP for the prefix of its path, before it goes through [Database::match_request],
+    /// see [RateLimiter].
+    ///
+    /// Returns false once the client has no request left, the request is then answered with an error 429 without
+    /// looking up its session nor its route.
+    pub fn limit_prefix_rate(&mut self, rate_limiter: &RateLimiter) -> bool {
+        let status = rate_limiter.check_prefix(&self.path, &client(self));
+        self.count_rate(status)
+    }
+
+    /// Counts the request in the bucket of its client for its route, if the route has a limit, see [RateLimiter].
+    ///
+    /// Returns the error 429 which replaces the route once the client has no request left, so that no script is run for it.
+    pub fn limit_route_rate(&mut self, http_code: HTTPCode, rate_limiter: &RateLimiter) -> HTTPCode {
+        let status = match &http_code {
+            HTTPCode::Ok200(v) => v.rate_limit.map(|limit| rate_limiter.check_route(&format!("{} {}", self.method, v.path), limit, &client(self))),
+            _ => None,
+        };
+        match status.is_none() || self.count_rate(status) {
+            true => http_code,
+            false => HTTPCode::Err429,
+        }
+    }
+
+    /// Keeps the bucket of the client for the `RateLimit-*` headers, returns false if the request is over the limit
+    fn count_rate(&mut self, status: Option<RateLimitStatus>) -> bool {
+        if status.is_some() {
+            self.rate_limit = status;
+        }
+        match status.and_then(|v| v.retry_after) {
+            Some(v) => {
+                info!("Refused a {} request to {} from {}, over its rate limit", self.method, self.path, client(self));
+                self.retry_after = Some(v as i64);
+                false
+            },
+            None => true,
+        }
+    }
+
+    /// The number of seconds the client has to wait before trying again, when the request was throttled
+    #[allow(dead_code)]
+    pub fn retry_after(&self) -> Option<i64> {
+        self.retry_after
+    }
+
+    /// The CSRF token the forms of the response have to send back, once the request went through [Database::match_request]
+    pub fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+
+    /// Returns the CGI/1.1 meta-variables (RFC 3875) describing this request, used by the FastCGI and CGI backends.
+    ///
+    /// `script_filename` is the file the application should run, every header is also passed as `HTTP_<NAME>`.
+    pub fn cgi_variables(&self, script_filename: &str) -> Vec<(String, String)> {
+        let host = self.headers.get("host").map(|v| v.trim().to_string()).unwrap_or_default();
+        let server_name = match host.rsplit_once(':') {
+            Some((name, _)) => name.to_string(),
+            None if !host.is_empty() => host,
+            None => self.local_addr.map(|v| v.ip().to_string()).unwrap_or_default(),
+        };
+        let request_uri = match self.query_string.as_str() {
+            "" => self.path.clone(),
+            v => format!("{}?{}", self.path, v),
+        };
+        let mut variables: Vec<(String, String)> = vec![
+            ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+            ("SERVER_SOFTWARE", format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+            ("SERVER_PROTOCOL", self._version.clone()),
+            ("SERVER_NAME", server_name),
+            ("SERVER_PORT", self.local_addr.map(|v| v.port().to_string()).unwrap_or_default()),
+            ("REQUEST_METHOD", self.method.clone()),
+            ("REQUEST_URI", request_uri),
+            ("SCRIPT_NAME", self.path.clone()),
+            ("SCRIPT_FILENAME", script_filename.to_string()),
+            ("PATH_INFO", String::new()),
+            ("QUERY_STRING", self.query_string.clone()),
+            ("CONTENT_LENGTH", self.body.len().to_string()),
+            ("CONTENT_TYPE", self.headers.get("content-type").map(|v| v.trim().to_string()).unwrap_or_default()),
+            ("REMOTE_ADDR", self.remote_addr.map(|v| v.ip().to_string()).unwrap_or_default()),
+            ("REMOTE_PORT", self.remote_addr.map(|v| v.port().to_string()).unwrap_or_default()),
+        ].into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        if let Some(user) = &self.user {
+            variables.push((String::from("REMOTE_USER"), user.username.clone()));
+            variables.push((String::from("AUTH_LEVEL"), user.auth_level.to_string()));
+            variables.push((String::from("SESSION_ID"), user.session_id.clone()));
+            variables.push((String::from("SESSION_EXPIRES"), user.session_expires.to_string()));
+            variables.push((String::from("AUTH_ROLES"), user.permissions.roles.join(" ")));
+            variables.push((String::from("AUTH_PERMISSIONS"), user.permissions.permissions.join(" ")));
+        }
+        variables.push((String::from("CSRF_TOKEN"), self.csrf_token.clone()));
+
+        for (key, value) in &self.headers {
+            let name = key.to_uppercase().replace('-', "_");
+            // Content-Type and Content-Length already have their own variable, Proxy could be used to set HTTP_PROXY (httpoxy)
+            if name == "CONTENT_TYPE" || name == "CONTENT_LENGTH" || name == "PROXY" || name.is_empty() {
+                continue;
+            }
+            variables.push((format!("HTTP_{}", name), value.trim().to_string()));
+        }
+        variables
     }
 }
 
-/// An enum used by the [IncomingRequest::parse_request] method to handle empty and invalid requests
+/// An enum used by the [IncomingRequest::request_length] and [IncomingRequest::from_bytes] methods to handle empty, invalid and oversized requests
 pub enum ParsedRequest {
     Ok (Box<IncomingRequest>),
     Empty,
     BadRequest,
+    Timeout,
+    PayloadTooLarge,
+    UriTooLong,
+    HeadersTooLarge,
+}
+
+/// The limits applied by the [EventLoop](crate::event_loop::EventLoop) to protect the server from oversized or slow requests.
+///
+/// Each field can be set in the server config, durations are in seconds:
+/// ```json
+/// {
+///     "max_request_line":8192,
+///     "max_headers":100,
+///     "max_header_bytes":16384,
+///     "max_body_size":1048576,
+///     "read_timeout":10,
+///     "write_timeout":10,
+///     "header_timeout":20
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    pub max_request_line: usize,
+    pub max_headers: usize,
+    pub max_header_bytes: usize,
+    pub max_body_size: usize,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub header_timeout: Duration,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_request_line: 8192,
+            max_headers: 100,
+            max_header_bytes: 16384,
+            max_body_size: 1048576,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            header_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+impl RequestLimits {
+    /// Builds the limits from the server config, missing or invalid keys keep their default value
+    pub fn from_config(config: &HashMap<String, String>) -> RequestLimits {
+        let default = RequestLimits::default();
+        let size = |key: &str, default: usize| -> usize {
+            config.get(key).and_then(|v| v.trim().parse::<usize>().ok()).filter(|v| *v > 0).unwrap_or(default)
+        };
+        let duration = |key: &str, default: Duration| -> Duration {
+            config.get(key).and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| *v > 0.0)
+                .map(Duration::from_secs_f64)
+                .unwrap_or(default)
+        };
+        RequestLimits {
+            max_request_line: size("max_request_line", default.max_request_line),
+            max_headers: size("max_headers", default.max_headers),
+            max_header_bytes: size("max_header_bytes", default.max_header_bytes),
+            max_body_size: size("max_body_size", default.max_body_size),
+            read_timeout: duration("read_timeout", default.read_timeout),
+            write_timeout: duration("write_timeout", default.write_timeout),
+            header_timeout: duration("header_timeout", default.header_timeout),
+        }
+    }
 }
 
 /// An enum to store common HTTP error codes and OK for the [handle_connection] function
+#[derive(Debug)]
 pub enum HTTPCode {
     Ok200 (MatchedRequest),
     Err400,
     Err401,
     Err403,
     Err404,
+    Err405,
+    Err408,
+    Err413,
+    Err414,
+    Err429,
+    Err431,
+    Err502,
+    Err503,
+    Err504,
 }
 
 /// An enum to handle errors and prevent the threads from panicking, most functions in `request_handler.rs` uses this enum.
@@ -276,39 +732,96 @@ pub enum HTTPCode {
 #[derive(Debug)]
 pub enum ServerStatus<T> {
     Ok (T),
+    /// The request can't be answered normally, the page of the given code in the `errors` table is sent instead
+    Error (HTTPCode),
     InternalError,
 }
 
-/// An enum to handle user authentication by the server, if the auth succeeds, the [Database::auth_user] function will
-/// returns `Ok(name, n)` where `n` is the auth level of the user.
+/// The user of a request with a valid session, resolved by [Database::match_request] for every request and passed
+/// to the scripts, see [IncomingRequest::as_json] and [IncomingRequest::cgi_variables].
 ///
 /// # Auth levels:
 /// ```text
 /// 0: not logged in
 /// 255: admin
 /// ```
-#[derive(Debug)]
-pub enum UserAuth {
-    Ok (u8),
-    ErrAuth,
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthenticatedUser {
+    pub username: String,
+    pub auth_level: u8,
+    pub session_id: String,
+    /// The UNIX timestamp when the session expires if it isn't used
+    pub session_expires: i64,
+    /// Not sent in the `user` object, the scripts get it as the `csrf_token` of the request
+    pub csrf_token: String,
+    /// The key of a request authenticated with an `Authorization: Bearer` header instead of a session, the session
+    /// ID is then empty and the session expiry is the one of the key, 0 if it never expires
+    pub api_key: Option<ApiKey>,
+    /// The roles of the user and their permissions, filled once the user of the route is known
+    pub permissions: Permissions,
 }
 
 impl Database {
-    /// This function is to find the information (path, page/script filepath, auth level needed and query parameters) in the database and returns a [MatchedRequest]
-    pub fn match_request(&self, incoming: &IncomingRequest) -> ServerStatus<HTTPCode> {
-        let table = &format!("requests_{}", incoming.method.to_lowercase());
-        let key_column = "path";
-        let key = &incoming.path;
+    /// Returns the row of the route of a method and a path, the row is empty if no route matches.
+    ///
+    /// A route ending with `/*` matches every path under it, the most specific one is used.
+    fn route(&self, method: &str, path: &str) -> Result<HashMap<String, String>, DatabaseError> {
+        let table = &format!("requests_{}", method.to_lowercase());
+        let mut request_result = self.request_row(table, "path", path)?;
+        let mut prefix = path;
+        while request_result.is_empty() {
+            prefix = match prefix.rsplit_once('/') {
+                Some((parent, _)) => parent,
+                None => break,
+            };
+            request_result = self.request_row(table, "path", &format!("{}/*", prefix))?;
+        }
+        Ok(request_result)
+    }
+
+    /// Returns true if the request goes to a reverse proxy route, the body of these requests is streamed to the upstream
+    pub fn is_proxy_route(&self, incoming: &IncomingRequest) -> bool {
+        match self.route(&incoming.method, &incoming.path) {
+            Ok(v) => v.get("callback").is_some_and(|v| v.starts_with("proxy://")),
+            Err(_) => false,
+        }
+    }
 
-        let request_result = match self.request_row(table, key_column, key) {
+    /// This function is to find the information (path, page/script filepath, auth level needed and query parameters) in the database and returns a [MatchedRequest]
+    ///
+    /// The user of the session is resolved first and stored in the request, even if the route doesn't need it.
+    /// The user needs the auth level of the route and every permission listed for it in `route_permissions`, see [Permissions].
+    ///
+    /// The password checks of `auth://login` and of the routes accepting HTTP Basic credentials are refused with an
+    /// error 429 while the IP or the username is locked out by the [LoginThrottle](crate::login_throttle::LoginThrottle). HTTP Basic
+    /// credentials are refused with an error 403 for the users who have to give a second factor, see [Authenticator].
+    pub fn match_request(&self, incoming: &mut IncomingRequest, sessions: &SessionStore, auth: &Authenticator) -> ServerStatus<HTTPCode> {
+        incoming.user = match self.auth_user(incoming, sessions) {
+            ServerStatus::Ok(v) => v,
+            ServerStatus::Error(v) => {incoming.challenge = Some(Challenge::InvalidBearer); return ServerStatus::Ok(v)},
+            _ => {error!("Error when trying to get the user of the session");
+                return ServerStatus::InternalError},
+        };
+        incoming.csrf_token = match (&incoming.user, incoming.cookies.get("csrfToken")) {
+            (Some(user), _) => user.csrf_token.clone(),
+            (None, Some(v)) if v.len() == 64 && v.bytes().all(|b| b.is_ascii_hexdigit()) => v.clone(),
+            (None, _) => match random_token() {
+                Ok(v) => {incoming.new_csrf_token = true; v},
+                Err(e) => {error!("{}", e); return ServerStatus::InternalError},
+            },
+        };
+        let request_result = match self.route(&incoming.method, &incoming.path) {
             Ok(v) => v,
-            Err(_) => {return ServerStatus::InternalError}, 
+            // no route uses this method
+            Err(DatabaseError::UnknownTable(_)) => return ServerStatus::Ok(HTTPCode::Err405),
+            Err(e) => {error!("{}", e); return ServerStatus::InternalError},
         };
         if request_result.is_empty() {
             return ServerStatus::Ok(HTTPCode::Err404);
         }
         let path = String::from(request_result.get("path").unwrap());
         let callback = String::from(request_result.get("callback").unwrap());
+        #[allow(clippy::manual_unwrap_or)]
         let auth_level: u8 = match request_result.get("auth_level").unwrap().parse::<u8>() {
             Ok(v) => v,
             _ => 255,
@@ -327,52 +840,154 @@ impl Database {
                 }
             }
         }
-        if auth_level > 0 {
-            let user_auth_level = match self.auth_user(incoming) {
-                ServerStatus::Ok(v) => match v {
-                    UserAuth::Ok(v) => v,
-                    UserAuth::ErrAuth => {return ServerStatus::Ok(HTTPCode::Err401)},
+        let csrf = request_result.get("csrf").map(|v| v.trim() != "0").unwrap_or(true);
+        if csrf && !check_csrf(incoming) {
+            info!("Refused a {} request to {} without a valid CSRF token or from another origin", incoming.method, incoming.path);
+            return ServerStatus::Ok(HTTPCode::Err403);
+        }
+        let schemes = AuthSchemes::parse(request_result.get("auth_scheme").map(String::as_str));
+        if incoming.user.as_ref().is_some_and(|v| if v.api_key.is_some() {!schemes.bearer} else {!schemes.session}) {
+            incoming.user = None;
+        }
+        let basic = incoming.headers.get("authorization").and_then(|v| basic_credentials(v)).filter(|_| schemes.basic);
+        let attempt = match &basic {
+            Some((username, _)) => Some(username.clone()),
+            None if callback == "auth://login" => Some(parse_hashmap(&String::from_utf8_lossy(&incoming.body), "&", "=").get("username").map(|v| url_decode(v)).unwrap_or_default()),
+            None if callback == "auth://totp" => incoming.cookies.get("loginChallenge").and_then(|v| LoginChallenge::resolve(self, v).ok().flatten()).map(|v| v.username),
+            None => None,
+        };
+        let ip = incoming.remote_addr.map(|v| v.ip().to_string()).unwrap_or_default();
+        if let Some(username) = attempt {
+            match auth.throttle.locked(self, &ip, &username) {
+                Ok(None) => (),
+                Ok(Some(v)) => {
+                    info!("Refused a login attempt for the user {:?} from {:?}, locked out for {} seconds", username, incoming.remote_addr, v);
+                    incoming.retry_after = Some(v);
+                    return ServerStatus::Ok(HTTPCode::Err429);
+                },
+                Err(e) => {error!("{}", e); return ServerStatus::InternalError},
+            }
+        }
+        if let Some((username, password)) = basic {
+            let auth_level = match verify_user(self, &username, &password, &format!("{}{}", username, password)) {
+                Ok(row) => row.map(|row| match row.get("auth_level") {
+                    Some(Value::Integer(v)) => u8::try_from(*v).unwrap_or(0),
+                    _ => 0,
+                }),
+                Err(e) => {error!("{}", e); return ServerStatus::InternalError},
+            };
+            let recorded = match auth_level {
+                // Basic credentials can't carry the second factor of the users who need one
+                Some(level) => match auth.requires_second_factor(self, &username, level) {
+                    Ok(true) => {
+                        info!("Refused the Basic credentials of the user {:?}, who has to give a second factor", username);
+                        return ServerStatus::Ok(HTTPCode::Err403);
+                    },
+                    Ok(false) => auth.throttle.success(self, &username),
+                    Err(e) => Err(e),
+                },
+                None => auth.throttle.failure(self, &ip, &username, &incoming.path),
+            };
+            if let Err(e) = recorded {
+                error!("{}", e);
+                return ServerStatus::InternalError;
+            }
+            incoming.user = match auth_level {
+                Some(auth_level) => Some(AuthenticatedUser {
+                    auth_level,
+                    username,
+                    session_id: String::new(),
+                    session_expires: 0,
+                    csrf_token: String::new(),
+                    api_key: None,
+                    permissions: Permissions::default(),
+                }),
+                None => {
+                    info!("Failed Basic authentication for the user {:?} from {:?}", username, incoming.remote_addr);
+                    incoming.challenge = Some(Challenge::Basic);
+                    return ServerStatus::Ok(HTTPCode::Err401);
+                },
+            };
+        }
+        if incoming.user.as_ref().and_then(|v| v.api_key.as_ref()).is_some_and(|v| !v.allows(&incoming.path)) {
+            return ServerStatus::Ok(HTTPCode::Err403);
+        }
+        if let Some(user) = incoming.user.as_mut() {
+            user.permissions = match Permissions::of_user(self, &user.username, user.auth_level) {
+                Ok(v) => v,
+                Err(e) => {error!("{}", e); return ServerStatus::InternalError},
+            };
+        }
+        let permissions = match route_permissions(self, &incoming.method, &path) {
+            Ok(v) => v,
+            Err(e) => {error!("{}", e); return ServerStatus::InternalError},
+        };
+        if auth_level > 0 || !permissions.is_empty() {
+            let user = match &incoming.user {
+                Some(v) => v,
+                None => {
+                    incoming.challenge = schemes.basic.then_some(Challenge::Basic);
+                    return ServerStatus::Ok(HTTPCode::Err401)
                 },
-                _ => {error!("Error when trying to get user auth level");
-                    return ServerStatus::InternalError},
             };
 
-            if user_auth_level < auth_level {
+            if user.auth_level < auth_level || !permissions.iter().all(|v| user.permissions.grants(v)) {
                 return ServerStatus::Ok(HTTPCode::Err403);
             }
         }
-        ServerStatus::Ok(HTTPCode::Ok200(MatchedRequest {path, callback, auth_level, params: parameters}))
+        let script_limits = ScriptLimits::from_map(&request_result);
+        let rate_limit = RateLimit::from_map(&request_result);
+        ServerStatus::Ok(HTTPCode::Ok200(MatchedRequest {path, callback, auth_level, params: parameters, script_limits, rate_limit}))
     }
 
-    /// This function will look in the database for a valid `sessionID` found in the [IncomingRequest]'s cookies field and will return the auth_level of this user.
-    fn auth_user(&self, incoming: &IncomingRequest) -> ServerStatus<UserAuth> {
+    /// This function will look in the `sessions` table for a valid `sessionID` found in the [IncomingRequest]'s cookies field and will return its user.
+    ///
+    /// A request with an `Authorization: Bearer` header is authenticated by its [ApiKey] instead, an invalid key is an error 401.
+    fn auth_user(&self, incoming: &IncomingRequest, sessions: &SessionStore) -> ServerStatus<Option<AuthenticatedUser>> {
+        if let Some(token) = incoming.headers.get("authorization").and_then(|v| bearer_token(v)) {
+            let key = match ApiKey::resolve(self, token) {
+                Ok(Some(v)) => v,
+                Ok(None) => {return ServerStatus::Error(HTTPCode::Err401)},
+                Err(e) => {error!("{}", e); return ServerStatus::InternalError;},
+            };
+            let owner_auth_level = match self.request_row("users", "username", &key.owner) {
+                Ok(v) => match v.get("auth_level").map(|v| v.parse::<u8>()) {
+                    Some(Ok(v)) => v,
+                    _ => {return ServerStatus::Error(HTTPCode::Err401)},
+                },
+                Err(e) => {error!("{}", e); return ServerStatus::InternalError;},
+            };
+            return ServerStatus::Ok(Some(AuthenticatedUser {
+                username: key.owner.clone(),
+                auth_level: key.auth_level.min(owner_auth_level),
+                session_id: String::new(),
+                session_expires: key.expires.unwrap_or(0),
+                csrf_token: String::new(),
+                api_key: Some(key),
+                permissions: Permissions::default(),
+            }));
+        }
         let session_id = match incoming.cookies.get("sessionID") {
             Some(v) => v,
-            None => {return ServerStatus::Ok(UserAuth::ErrAuth)}
+            None => {return ServerStatus::Ok(None)}
         };
-        let time_now = SystemTime::now().duration_since(UNIX_EPOCH).expect("ERROR: TIME WENT BACKWARDS").as_secs();
-        let result = match self.request_row("users", "sessionID", session_id) {
+        let session = match sessions.resolve(self, session_id) {
+            Ok(Some(v)) => v,
+            Ok(None) => {return ServerStatus::Ok(None)},
+            Err(e) => {error!("{}", e); return ServerStatus::InternalError;},
+        };
+        let result = match self.request_row("users", "username", &session.username) {
             Ok(v) => v,
             Err(e) => {error!("{}", e); return ServerStatus::InternalError;}, 
         };
         let auth_level = match result.get("auth_level") {
             Some(v) => match v.parse::<u8>() {
-                Ok(v) => ServerStatus::Ok(UserAuth::Ok(v)),
-                Err(e) => {error!("{}", e); return ServerStatus::InternalError;}
-            },
-            None => ServerStatus::Ok(UserAuth::ErrAuth)
-        };
-        let expires = match result.get("sessionExpires") {
-            Some(v) => match v.parse::<u64>() {
                 Ok(v) => v,
-                Err(e) => {error!("{}", e); return ServerStatus::InternalError;},
+                Err(e) => {error!("{}", e); return ServerStatus::InternalError}
             },
-            None => {return ServerStatus::Ok(UserAuth::ErrAuth);},
+            None => {return ServerStatus::Ok(None)}
         };
-        if expires <= time_now {
-            return ServerStatus::Ok(UserAuth::ErrAuth);
-        }
-        auth_level
+        ServerStatus::Ok(Some(AuthenticatedUser {username: session.username, auth_level, session_id: session.id, session_expires: session.expires, csrf_token: session.csrf_token, api_key: None, permissions: Permissions::default()}))
     }
 
     ///This function will look in the database in the `errors` table for where to find the content to send to the client when an error occurs
@@ -384,13 +999,22 @@ impl Database {
     /// let content = match database.get_error(error) {
     ///     ServerStatus::Ok(v) => v.unwrap()
     /// }
-    pub fn get_error(&self, httpcode: HTTPCode, incoming_request: &IncomingRequest) -> ServerStatus<Option<HTTPResponse>> {
+    pub fn get_error(&self, httpcode: HTTPCode, incoming_request: &IncomingRequest, script_runner: &ScriptRunner) -> ServerStatus<Option<HTTPResponse>> {
         let error_name = match httpcode {
             HTTPCode::Ok200 (_) => {return ServerStatus::Ok(None)},
             HTTPCode::Err400 => "err400",
             HTTPCode::Err401 => "err401",
             HTTPCode::Err403 => "err403",
             HTTPCode::Err404 => "err404",
+            HTTPCode::Err405 => "err405",
+            HTTPCode::Err408 => "err408",
+            HTTPCode::Err413 => "err413",
+            HTTPCode::Err414 => "err414",
+            HTTPCode::Err429 => "err429",
+            HTTPCode::Err431 => "err431",
+            HTTPCode::Err502 => "err502",
+            HTTPCode::Err503 => "err503",
+            HTTPCode::Err504 => "err504",
         };
 
         let request_result = match self.request_row("errors", "name", error_name) {
@@ -405,19 +1029,31 @@ impl Database {
 
         let error_code: u32 = error_name[3..].parse::<u32>().unwrap(); 
         let mut http_response = HTTPResponse::new(error_code, String::from(response_message));
-        http_response.load_contents(String::from(page_filepath), &incoming_request.as_json());
+        http_response.load_contents(String::from(page_filepath), incoming_request, script_runner, &ScriptLimits::default());
 
         ServerStatus::Ok(Some(http_response))
     }
 }
 
 /// A struct returned by the [Database::match_request] function that searches in the database for a page corresponding to the path requested.
+#[derive(Debug)]
 #[allow(dead_code)]
 pub struct MatchedRequest {
     path: String, 
     callback: String,
     auth_level: u8,
     params: Vec<String>,
+    script_limits: ScriptLimits,
+    /// The limit of the `rate_limit` columns of the route, see [RateLimiter]
+    rate_limit: Option<RateLimit>,
+}
+
+impl MatchedRequest {
+    /// Returns true for the routes which take over the connection instead of sending a response:
+    /// the reverse proxy, WebSocket and Server-Sent Events routes
+    pub fn takes_over_connection(&self) -> bool {
+        self.callback.starts_with("proxy://") || WebSocketServer::is_endpoint(&self.callback) || EventStreamServer::is_endpoint(&self.callback)
+    }
 }
 
 /// A struct representing the response that will be sent by the server to the client
@@ -430,15 +1066,31 @@ pub struct HTTPResponse {
 
 impl HTTPResponse {
     ///Creates a new HTTPResponse object
-    fn new(response_code: u32, response_message: String) -> HTTPResponse {
+    pub fn new(response_code: u32, response_message: String) -> HTTPResponse {
         HTTPResponse {response_code, response_message, headers: HashMap::new(), contents: Vec::new()}
     }
 
+    /// Returns the response sent when an error 500 occurs, see [ERR500]
+    pub fn internal_error() -> HTTPResponse {
+        let contents = ERR500.split_once("\r\n\r\n").map(|v| v.1).unwrap_or_default();
+        let mut http_response = HTTPResponse::new(500, String::from("INTERNAL SERVER ERROR"));
+        http_response.set_contents(contents.as_bytes().to_vec());
+        http_response
+    }
+
+    /// Returns the response code, the headers and the contents of the response, for HTTP/2 which has its own framing
+    pub fn into_parts(self) -> (u32, HashMap<String, String>, Vec<u8>) {
+        (self.response_code, self.headers, self.contents)
+    }
+
     ///Uses the [MatchedRequest] containing the file the user requested and other informations and returns a valid HTTPResponse object
-    pub fn from_matched_request(matched_request: MatchedRequest, incoming_request: IncomingRequest) -> ServerStatus<HTTPResponse> {
+    ///
+    /// The script limits of the route take precedence over the global limits of the `script_runner`.
+    pub fn from_matched_request(matched_request: MatchedRequest, incoming_request: &IncomingRequest, script_runner: &ScriptRunner) -> ServerStatus<HTTPResponse> {
         let mut http_response = HTTPResponse::new(200, String::from("OK"));
-        match http_response.load_contents(matched_request.callback, &incoming_request.as_json()) {
+        match http_response.load_contents(matched_request.callback, incoming_request, script_runner, &matched_request.script_limits) {
             ServerStatus::Ok(()) => (),
+            ServerStatus::Error(v) => return ServerStatus::Error(v),
             ServerStatus::InternalError => return ServerStatus::InternalError,
         };
         ServerStatus::Ok(http_response)
@@ -457,27 +1109,51 @@ impl HTTPResponse {
     /// main.rs:
     /// ```
     /// let response = HTTPResponse::new(200, String::from("OK"))
-    /// response.load_contents(String::from("myfile.json"), "");
+    /// response.load_contents(String::from("myfile.json"), &incoming_request, &script_runner, &ScriptLimits::default());
     /// println!("{}", response.contents);
     /// ```
-    fn load_contents(&mut self, filename: String, script_args: &str) -> ServerStatus<()> {
-        let result = match filename.split('.').last().unwrap_or("") {
-            "py" => run_python(&filename, script_args),
-            "js" => run_js(&filename, script_args),
+    ///
+    /// A callback starting with `fcgi://` is sent to a FastCGI application instead, see [FastCgiEndpoint],
+    /// and a callback starting with `cgi://` is run as a CGI/1.1 script, see [run_cgi].
+    fn load_contents(&mut self, filename: String, incoming_request: &IncomingRequest, script_runner: &ScriptRunner, script_limits: &ScriptLimits) -> ServerStatus<()> {
+        let script_args = &incoming_request.as_json();
+        let fastcgi_endpoint = filename.strip_prefix("fcgi://").map(FastCgiEndpoint::parse);
+        let cgi_script = filename.strip_prefix("cgi://");
+        let interpreter = match (&fastcgi_endpoint, cgi_script) {
+            (None, None) => script_runner.find_interpreter(&filename),
+            _ => None,
+        };
+        let result = match (&fastcgi_endpoint, cgi_script, &interpreter) {
+            (Some(Some(endpoint)), _, _) => {
+                let params = incoming_request.cgi_variables(&endpoint.script_filename);
+                run_fastcgi(endpoint, &params, &incoming_request.body, &script_limits.or(&script_runner.limits))
+            },
+            (Some(None), _, _) => Err(ScriptError::Failed(format!("Invalid FastCGI endpoint {}", filename))),
+            (None, Some(script), _) => {
+                let script_filename = fs::canonicalize(script).map(|v| v.to_string_lossy().to_string()).unwrap_or_default();
+                let variables = incoming_request.cgi_variables(&script_filename);
+                script_runner.run_cgi(script, &variables, &incoming_request.body, script_limits)
+            },
+            (None, None, Some(interpreter)) => script_runner.run_script(interpreter, &filename, script_args, script_limits),
             _ => match fs::read(&filename) {
                 Ok(v) => Ok(v),
-                _ => Err(format!("Error when loading text file {}", filename)),
+                _ => Err(ScriptError::Failed(format!("Error when loading text file {}", filename))),
             },
         };
         let contents = match result {
             Ok(v) => v,
+            Err(ScriptError::TimedOut) => {
+                warn!("The script {} timed out", filename);
+                return ServerStatus::Error(HTTPCode::Err504);
+            },
             Err(e) => {
                 error!("Error when accessing content:\n{}", e);
                 return ServerStatus::InternalError;
             }
         };
-        match filename.split('.').last().unwrap_or("") {
-            "py"|"js" => {
+        match (fastcgi_endpoint, cgi_script, interpreter) {
+            (Some(_), _, _) | (None, Some(_), _) => self.load_cgi_response(contents),
+            (None, None, Some(_)) => {
                 let (code_and_message, headers_and_body): (String, Vec<u8>) = match contents.split_once(&[13u8,10u8]) { //[13u8,10u8] <=> b"\r\n"
                     Some((cm, hb)) => (String::from_utf8_lossy(&cm).to_string(), hb.to_vec()),
                     None => (String::from("200 OK"), contents),
@@ -498,6 +1174,35 @@ impl HTTPResponse {
         ServerStatus::Ok(())
     }
 
+    /// Fills the response from the output of a CGI or FastCGI application: a header section, an empty line and the body.
+    ///
+    /// The `Status` header sets the response code and message, a `Location` header without `Status` is a `302 Found` redirect.
+    fn load_cgi_response(&mut self, output: Vec<u8>) {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut status: Option<String> = None;
+        let mut rest = &output[..];
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            let line = String::from_utf8_lossy(&rest[..end]).trim_end_matches('\r').to_string();
+            rest = &rest[end + 1..];
+            if line.is_empty() {break}
+            let Some((key, value)) = line.split_once(':') else {continue};
+            match key.trim() {
+                v if v.eq_ignore_ascii_case("status") => status = Some(value.trim().to_string()),
+                v => {headers.insert(v.to_string(), value.trim().to_string());},
+            }
+        }
+        let is_redirect = headers.keys().any(|k| k.eq_ignore_ascii_case("location"));
+        let (code, message) = match &status {
+            Some(v) => v.split_once(' ').unwrap_or((v, "")),
+            None if is_redirect => ("302", "Found"),
+            None => ("200", "OK"),
+        };
+        self.response_code = code.parse::<u32>().unwrap_or(200u32);
+        self.response_message = String::from(message);
+        self.set_contents(rest.to_vec());
+        self.add_headers(headers);
+    }
+
     /// Sets the content from the given string
     /// 
     /// #Example
@@ -507,7 +1212,7 @@ impl HTTPResponse {
     /// response.set_contents("Hello World");
     /// println!("{}", response.contents);
     /// ```
-    fn set_contents(&mut self, contents: Vec<u8>) {
+    pub fn set_contents(&mut self, contents: Vec<u8>) {
         self.contents = contents;
         self.update_content_length();
     }
@@ -518,7 +1223,7 @@ impl HTTPResponse {
     }
 
     /// Adds headers in the response from a given [HashMap]
-    fn add_headers(&mut self, headers: HashMap<String, String>) {
+    pub fn add_headers(&mut self, headers: HashMap<String, String>) {
         self.headers.extend(headers);
     }
 
@@ -537,6 +1242,16 @@ impl HTTPResponse {
     }
 }
 
+/// Returns true if `value` is a token as defined in RFC 9110 section 5.6.2, which every method has to be
+///
+/// ```text
+/// token = 1*tchar
+/// tchar = "!" / "#" / "$" / "%" / "&" / "'" / "*" / "+" / "-" / "." / "^" / "_" / "`" / "|" / "~" / DIGIT / ALPHA
+/// ```
+pub fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
 pub fn parse_hashmap(target: &str, entries_separator: &str, key_value_separator: &str) -> HashMap<String, String> {
     let mut result: HashMap<String, String> = HashMap::new();
     let entries = target.split(entries_separator);
@@ -549,9 +1264,29 @@ pub fn parse_hashmap(target: &str, entries_separator: &str, key_value_separator:
     result
 }
 
+/// Decodes a component of a query string or of an `application/x-www-form-urlencoded` body: `+` is a space and
+/// `%XX` is the byte `XX`, the invalid UTF-8 sequences are replaced
+pub fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex_byte = bytes.get(i + 1..i + 3).and_then(|v| str::from_utf8(v).ok()).and_then(|v| u8::from_str_radix(v, 16).ok());
+        match (bytes[i], hex_byte) {
+            (b'+', _) => decoded.push(b' '),
+            (b'%', Some(v)) => {decoded.push(v); i += 2},
+            (v, _) => decoded.push(v),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use crate::request_handler::*;
+    use crate::database_utils::tests::test_database;
     #[test]
     fn test_parse_hashmap() {
         let hashmap_test: HashMap<String, String> = HashMap::from([(String::from("sessionID"), String::from("1")),(String::from("cookie2"), String::from("hello"))]);
@@ -559,6 +1294,119 @@ mod tests {
         let parsed_hashmap = parse_hashmap(target, ";", "=");
         assert_eq!(hashmap_test,parsed_hashmap);
     }
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!("george@example.com", url_decode("george%40example.com"));
+        assert_eq!("long enough é", url_decode("long+enough+%C3%A9"));
+        assert_eq!("100%", url_decode("100%"));
+        assert_eq!("%zz%4", url_decode("%zz%4"));
+    }
+
+    /// Parses the first request of `buffer` the way the event loop does, returns `None` if it's incomplete
+    /// and the length of the request with it
+    fn parse_with_limits(buffer: &[u8], limits: &RequestLimits) -> Option<(ParsedRequest, usize)> {
+        match IncomingRequest::request_length(buffer, limits) {
+            Ok(Some(length)) if buffer.len() >= length => Some((IncomingRequest::from_bytes(&buffer[..length], None, None), length)),
+            Ok(_) => None,
+            Err(e) => Some((e, 0)),
+        }
+    }
+
+    #[test]
+    fn test_parse_request_limits() {
+        let request = b"POST /login HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let parse = |limits: RequestLimits| parse_with_limits(request, &limits).unwrap().0;
+        assert!(matches!(parse(RequestLimits::default()), ParsedRequest::Ok(v) if v.body == b"hello"));
+        assert!(matches!(parse(RequestLimits {max_body_size: 4, ..RequestLimits::default()}), ParsedRequest::PayloadTooLarge));
+        assert!(matches!(parse(RequestLimits {max_headers: 1, ..RequestLimits::default()}), ParsedRequest::HeadersTooLarge));
+        assert!(matches!(parse(RequestLimits {max_request_line: 10, ..RequestLimits::default()}), ParsedRequest::UriTooLong));
+    }
+
+    #[test]
+    fn test_partial_request() {
+        // every part of a request received so far is incomplete, the event loop times out the clients who stop there
+        let request = b"POST /login?next=%2F HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        for length in 0..request.len() {
+            assert!(parse_with_limits(&request[..length], &RequestLimits::default()).is_none(), "{}", length);
+        }
+        let (parsed, length) = parse_with_limits(request, &RequestLimits::default()).unwrap();
+        assert_eq!(length, request.len());
+        assert!(matches!(parsed, ParsedRequest::Ok(v) if v.path == "/login" && v.query["next"] == "%2F" && v.body == b"hello"));
+
+        // the limits are checked before the head is complete
+        let limits = RequestLimits {max_request_line: 10, ..RequestLimits::default()};
+        assert!(matches!(parse_with_limits(b"GET /a-long-path", &limits), Some((ParsedRequest::UriTooLong, _))));
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let buffer = b"POST /first HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /second HTTP/1.1\r\nHost: localhost\r\n\r\nGET /thi";
+        let (first, length) = parse_with_limits(buffer, &RequestLimits::default()).unwrap();
+        assert!(matches!(first, ParsedRequest::Ok(v) if v.path == "/first" && v.body == b"abc"));
+        let (second, second_length) = parse_with_limits(&buffer[length..], &RequestLimits::default()).unwrap();
+        assert!(matches!(second, ParsedRequest::Ok(v) if v.path == "/second" && v.body.is_empty() && v.headers["host"] == " localhost"));
+        assert!(parse_with_limits(&buffer[length + second_length..], &RequestLimits::default()).is_none());
+    }
+
+    #[test]
+    fn test_method_token() {
+        assert!(is_token("GET") && is_token("M-SEARCH") && is_token("x~1"));
+        assert!(!is_token("") && !is_token("GET;") && !is_token("requests_get\"") && !is_token("G T"));
+        assert!(matches!(IncomingRequest::from_bytes(b"PATCH / HTTP/1.1\r\n\r\n", None, None), ParsedRequest::Ok(_)));
+        assert!(matches!(IncomingRequest::from_bytes(b"GET/**/UNION / HTTP/1.1\r\n\r\n", None, None), ParsedRequest::BadRequest));
+    }
+
+    #[test]
+    fn test_authenticated_user() {
+        let mut incoming = IncomingRequest::from_parts("GET", "/user", "HTTP/1.1", HashMap::new(), Vec::new(), None, None);
+        assert!(json::parse(&incoming.as_json()).unwrap()["user"].is_null());
+        assert!(!incoming.cgi_variables("").iter().any(|(k, _)| k == "REMOTE_USER"));
+
+        incoming.user = Some(AuthenticatedUser {username: String::from("ad\"min"), auth_level: 255, session_id: String::from("9999"), session_expires: 1700000000, csrf_token: String::new(), api_key: None, permissions: Permissions::default()});
+        let user = &json::parse(&incoming.as_json()).unwrap()["user"];
+        assert_eq!((Some("ad\"min"), Some(255), Some("9999"), Some(1700000000)), (user["username"].as_str(), user["auth_level"].as_u8(), user["session_id"].as_str(), user["session_expires"].as_i64()));
+        assert!(incoming.cgi_variables("").contains(&(String::from("REMOTE_USER"), String::from("ad\"min"))));
+        assert!(user["permissions"].is_empty() && user["roles"].is_array());
+    }
+
+    #[test]
+    fn test_route_script_limits() {
+        // a database made before the migrations existed, whose routes get their script limits from a migration
+        let database = test_database("script-limits", "CREATE TABLE requests_get(path TEXT PRIMARY KEY, callback TEXT, auth_level INTEGER, params TEXT);
+            CREATE TABLE requests_post(path TEXT PRIMARY KEY, callback TEXT, auth_level INTEGER, params TEXT);");
+        crate::migrations::migrate(&database).unwrap();
+        let config = ServerConfig::from_config(&json::object!{"database": database.filepath.as_str()});
+
+        let script = std::env::temp_dir().join(format!("webserver-rs-slow-route-{}.py", std::process::id()));
+        std::fs::write(&script, "import time\ntime.sleep(30)").unwrap();
+        config.database.insert_row("requests_get", &[("path", Value::from("/slow")), ("callback", Value::from(script.to_str().unwrap())),
+            ("auth_level", Value::from(0)), ("params", Value::from("")), ("script_timeout", Value::from(0.3))]).unwrap();
+
+        let mut incoming = IncomingRequest::from_parts("GET", "/slow", "HTTP/1.1", HashMap::new(), Vec::new(), None, None);
+        let matched_request = match config.database.match_request(&mut incoming, &config.sessions, &config.auth) {
+            ServerStatus::Ok(HTTPCode::Ok200(v)) => v,
+            _ => panic!("the route wasn't matched"),
+        };
+        assert_eq!(Some(Duration::from_millis(300)), matched_request.script_limits.timeout);
+        let started = std::time::Instant::now();
+        assert!(matches!(HTTPResponse::from_matched_request(matched_request, &incoming, &config.script_runner), ServerStatus::Error(HTTPCode::Err504)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_load_cgi_response() {
+        let mut response = HTTPResponse::new(200, String::from("OK"));
+        response.load_cgi_response(b"Status: 404 Not Found\r\nContent-Type: text/plain\r\n\r\nmissing\r\n".to_vec());
+        assert_eq!((404, "Not Found"), (response.response_code, response.response_message.as_str()));
+        assert_eq!(Some(&String::from("text/plain")), response.headers.get("Content-Type"));
+        assert_eq!(b"missing\r\n".to_vec(), response.contents);
+
+        let mut response = HTTPResponse::new(200, String::from("OK"));
+        response.load_cgi_response(b"Location: /login\n\n".to_vec());
+        assert_eq!(302, response.response_code);
+        assert!(response.contents.is_empty());
+    }
 }
 
 trait SplitOnce {
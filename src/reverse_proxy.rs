@@ -0,0 +1,426 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::request_handler::{HTTPCode, IncomingRequest, ServerStatus, parse_hashmap};
+
+/// The headers which only concern a single connection and are never forwarded (RFC 9110 section 7.6.1)
+const HOP_BY_HOP_HEADERS: [&str; 10] = ["connection", "keep-alive", "proxy-connection", "proxy-authenticate", "proxy-authorization",
+    "te", "trailer", "transfer-encoding", "upgrade", "host"];
+
+/// Forwards the requests of the routes whose callback starts with `proxy://` to upstream HTTP servers:
+/// ```text
+/// proxy://127.0.0.1:5000
+/// proxy://127.0.0.1:5000,127.0.0.1:5001/stats#timeout=60&max_fails=3&fail_timeout=10
+/// ```
+/// The requests are spread over the upstreams with a round robin, an upstream which fails `max_fails` times in a row
+/// (connection refused, timeout) is left out for `fail_timeout` seconds. The path after the upstreams replaces the part of
+/// the route before `*`, a request to `/dashboard/index.html` on the route `/dashboard/*` with the callback
+/// `proxy://127.0.0.1:5000/app` is forwarded as `/app/index.html`.
+///
+/// The part of the body which hasn't been received yet when the request is forwarded is streamed from the client to
+/// the upstream, see [IncomingRequest::from_head].
+///
+/// The default timeouts are read from the server config, in seconds, and can be overridden in the callback:
+/// ```json
+/// {
+///     "proxy_connect_timeout":5,
+///     "proxy_timeout":30
+/// }
+/// ```
+pub struct ReverseProxy {
+    connect_timeout: Duration,
+    timeout: Duration,
+    groups: Mutex<HashMap<String, Arc<UpstreamGroup>>>,
+}
+
+/// The upstreams of a `proxy://` callback with their options and their health
+struct UpstreamGroup {
+    upstreams: Vec<Upstream>,
+    base_path: String,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_fails: u32,
+    fail_timeout: Duration,
+    next: AtomicUsize,
+}
+
+/// An upstream server and the failures seen by the passive health check
+struct Upstream {
+    address: String,
+    health: Mutex<UpstreamHealth>,
+}
+
+#[derive(Default)]
+struct UpstreamHealth {
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl ReverseProxy {
+    /// Creates the proxy with the default timeouts of the server config
+    pub fn from_config(config: &HashMap<String, String>) -> ReverseProxy {
+        let seconds = |key: &str, default: u64| -> Duration {
+            config.get(key).and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| *v > 0.0)
+                .map(Duration::from_secs_f64)
+                .unwrap_or(Duration::from_secs(default))
+        };
+        ReverseProxy {
+            connect_timeout: seconds("proxy_connect_timeout", 5),
+            timeout: seconds("proxy_timeout", 30),
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Forwards the request to an upstream of the `callback` and streams the response back to the client.
+    ///
+    /// `route_path` is the path of the matched route, used to rewrite the path of the request. Nothing is written to the client
+    /// when an error is returned: `Err502` if no upstream could be reached, `Err504` if the upstream didn't answer in time
+    /// and `Err408` or `Err400` if the client stopped sending the body.
+    pub fn forward(&self, client: &mut TcpStream, incoming: &IncomingRequest, route_path: &str, callback: &str) -> ServerStatus<()> {
+        let group = match self.group(callback) {
+            Some(v) => v,
+            None => {
+                error!("Invalid proxy callback {}", callback);
+                return ServerStatus::InternalError;
+            }
+        };
+
+        let path = match route_path.strip_suffix('*') {
+            Some(prefix) => {
+                let rest = incoming.path().strip_prefix(prefix).unwrap_or("");
+                format!("{}/{}", group.base_path.trim_end_matches('/'), rest)
+            },
+            None if group.base_path.is_empty() => incoming.path().to_string(),
+            None => group.base_path.clone(),
+        };
+        let target = match incoming.query_string() {
+            "" => path,
+            query => format!("{}?{}", path, query),
+        };
+
+        for index in group.candidates() {
+            let upstream = &group.upstreams[index];
+            let mut connection = match upstream.connect(group.connect_timeout, group.timeout) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Upstream {} unreachable: {}", upstream.address, e);
+                    group.mark_failed(upstream);
+                    continue;
+                }
+            };
+            let head = build_request(incoming, &upstream.address, &target);
+            if let Err(e) = connection.write_all(&head).and_then(|_| connection.write_all(incoming.body())) {
+                warn!("Error when sending the request to upstream {}: {}", upstream.address, e);
+                group.mark_failed(upstream);
+                return ServerStatus::Error(HTTPCode::Err502);
+            }
+            match stream_body(client, &mut connection, incoming.pending_body()) {
+                Ok(()) => (),
+                Err(BodyError::Client(e)) => {
+                    info!("Error when reading the body of the request to upstream {}: {}", upstream.address, e);
+                    return match e.kind() {
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => ServerStatus::Error(HTTPCode::Err408),
+                        _ => ServerStatus::Error(HTTPCode::Err400),
+                    };
+                },
+                Err(BodyError::Upstream(e)) => {
+                    warn!("Error when sending the request to upstream {}: {}", upstream.address, e);
+                    group.mark_failed(upstream);
+                    return ServerStatus::Error(HTTPCode::Err502);
+                },
+            }
+
+            // the response is only committed to the client once the upstream started answering
+            let mut buffer = vec![0u8; 16384];
+            let read = match connection.read(&mut buffer) {
+                Ok(0) => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "empty response")),
+                v => v,
+            };
+            let length = match read {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Error when reading the response of upstream {}: {}", upstream.address, e);
+                    group.mark_failed(upstream);
+                    return match e.kind() {
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => ServerStatus::Error(HTTPCode::Err504),
+                        _ => ServerStatus::Error(HTTPCode::Err502),
+                    };
+                }
+            };
+            group.mark_ok(upstream);
+            if let Err(e) = client.write_all(&buffer[..length]).and_then(|_| std::io::copy(&mut connection, client)) {
+                info!("Error when streaming the response of upstream {}: {}", upstream.address, e);
+            }
+            let _ = client.flush();
+            return ServerStatus::Ok(());
+        }
+        ServerStatus::Error(HTTPCode::Err502)
+    }
+
+    /// Returns the upstream group of a callback, creating it the first time the callback is used
+    fn group(&self, callback: &str) -> Option<Arc<UpstreamGroup>> {
+        let mut groups = self.groups.lock().unwrap();
+        if let Some(v) = groups.get(callback) {
+            return Some(v.clone());
+        }
+        let group = Arc::new(UpstreamGroup::parse(callback.strip_prefix("proxy://")?, self.connect_timeout, self.timeout)?);
+        groups.insert(callback.to_string(), group.clone());
+        Some(group)
+    }
+}
+
+impl UpstreamGroup {
+    /// Parses the part of the callback after `proxy://`, returns `None` if there is no upstream
+    fn parse(callback: &str, connect_timeout: Duration, timeout: Duration) -> Option<UpstreamGroup> {
+        let (target, options) = callback.split_once('#').unwrap_or((callback, ""));
+        let (addresses, base_path) = match target.find('/') {
+            Some(i) => (&target[..i], &target[i..]),
+            None => (target, ""),
+        };
+        let upstreams: Vec<Upstream> = addresses.split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| Upstream {address: v.to_string(), health: Mutex::new(UpstreamHealth::default())})
+            .collect();
+        if upstreams.is_empty() {
+            return None;
+        }
+        let options = parse_hashmap(options, "&", "=");
+        let seconds = |key: &str, default: Duration| -> Duration {
+            options.get(key).and_then(|v| v.parse::<f64>().ok()).filter(|v| *v > 0.0).map(Duration::from_secs_f64).unwrap_or(default)
+        };
+        Some(UpstreamGroup {
+            upstreams,
+            base_path: base_path.to_string(),
+            connect_timeout: seconds("connect_timeout", connect_timeout),
+            timeout: seconds("timeout", timeout),
+            max_fails: options.get("max_fails").and_then(|v| v.parse::<u32>().ok()).unwrap_or(1).max(1),
+            fail_timeout: seconds("fail_timeout", Duration::from_secs(10)),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns the indexes of the upstreams in the order they should be tried: the next ones in the round robin first,
+    /// the upstreams marked as down last in case they all are
+    fn candidates(&self) -> Vec<usize> {
+        let count = self.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        let now = Instant::now();
+        let (mut up, down): (Vec<usize>, Vec<usize>) = (0..count)
+            .map(|i| (start + i) % count)
+            .partition(|i| self.upstreams[*i].health.lock().unwrap().down_until.is_none_or(|v| v <= now));
+        up.extend(down);
+        up
+    }
+
+    fn mark_failed(&self, upstream: &Upstream) {
+        let mut health = upstream.health.lock().unwrap();
+        health.failures += 1;
+        if health.failures >= self.max_fails {
+            warn!("Upstream {} marked as down for {:?}", upstream.address, self.fail_timeout);
+            health.failures = 0;
+            health.down_until = Some(Instant::now() + self.fail_timeout);
+        }
+    }
+
+    fn mark_ok(&self, upstream: &Upstream) {
+        let mut health = upstream.health.lock().unwrap();
+        health.failures = 0;
+        health.down_until = None;
+    }
+}
+
+impl Upstream {
+    /// Opens a connection to the upstream with the read and write timeouts set
+    fn connect(&self, connect_timeout: Duration, timeout: Duration) -> std::io::Result<TcpStream> {
+        let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "the address could not be resolved");
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, connect_timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    return Ok(stream);
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Which side of the proxy failed while the body was streamed
+enum BodyError {
+    Client (std::io::Error),
+    Upstream (std::io::Error),
+}
+
+/// Copies the `length` bytes of the body still on the client stream to the upstream
+fn stream_body(client: &mut TcpStream, upstream: &mut TcpStream, mut length: usize) -> Result<(), BodyError> {
+    let mut buffer = vec![0u8; 16384];
+    while length > 0 {
+        let read = match client.read(&mut buffer[..length.min(16384)]) {
+            Ok(0) => return Err(BodyError::Client(std::io::ErrorKind::UnexpectedEof.into())),
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(BodyError::Client(e)),
+        };
+        upstream.write_all(&buffer[..read]).map_err(BodyError::Upstream)?;
+        length -= read;
+    }
+    upstream.flush().map_err(BodyError::Upstream)
+}
+
+/// Builds the head of the request sent to the upstream: the `Host` is rewritten, the hop-by-hop headers are dropped,
+/// the `X-Forwarded-*` headers are added and the upstream is asked to close the connection after the response
+fn build_request(incoming: &IncomingRequest, upstream: &str, target: &str) -> Vec<u8> {
+    let headers = incoming.headers();
+    let client_ip = incoming.remote_addr().map(|v| v.ip().to_string()).unwrap_or_default();
+    let forwarded_for = match headers.get("x-forwarded-for").map(|v| v.trim()) {
+        Some(v) if !v.is_empty() => format!("{}, {}", v, client_ip),
+        _ => client_ip,
+    };
+    let connection_headers: Vec<String> = headers.get("connection")
+        .map(|v| v.split(',').map(|h| h.trim().to_lowercase()).collect())
+        .unwrap_or_default();
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", incoming.method(), target, upstream);
+    for (key, value) in headers {
+        if HOP_BY_HOP_HEADERS.contains(&key.as_str()) || connection_headers.contains(key) || key.starts_with("x-forwarded-") {
+            continue;
+        }
+        if key == "content-length" {
+            continue;
+        }
+        request.push_str(&format!("{}: {}\r\n", key, value.trim()));
+    }
+    request.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    request.push_str(&format!("X-Forwarded-Proto: {}\r\n", incoming.scheme()));
+    if let Some(host) = headers.get("host") {
+        request.push_str(&format!("X-Forwarded-Host: {}\r\n", host.trim()));
+    }
+    let length = incoming.body().len() + incoming.pending_body();
+    if length > 0 || incoming.headers().contains_key("content-length") {
+        request.push_str(&format!("Content-Length: {}\r\n", length));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    request.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reverse_proxy::*;
+    use crate::request_handler::{ParsedRequest, RequestLimits};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Reads a request from the stream, only until its head is complete if `whole` is false
+    fn read_request(stream: &TcpStream, whole: bool) -> Option<Box<IncomingRequest>> {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 4096];
+        let length = loop {
+            match IncomingRequest::request_length(&buffer, &RequestLimits::default()) {
+                Ok(Some(v)) if !whole || buffer.len() >= v => break v,
+                Ok(_) => (),
+                Err(_) => return None,
+            }
+            match (&*stream).read(&mut chunk) {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            }
+        };
+        match IncomingRequest::from_head(&buffer[..length.min(buffer.len())], stream.peer_addr().ok(), stream.local_addr().ok()) {
+            ParsedRequest::Ok(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// An upstream answering every request with its name, the request line, the forwarding headers and the body it received
+    fn test_upstream(name: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let request = match read_request(&stream, true) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let body = format!("{} {} {} {} {} {}", name, request.path(), request.query_string(),
+                    request.headers()["x-forwarded-for"].trim(), request.headers()["x-forwarded-proto"].trim(),
+                    String::from_utf8_lossy(request.body()));
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                (&stream).write_all(response.as_bytes()).unwrap();
+            }
+        });
+        address
+    }
+
+    /// Sends the head of `request` through the proxy, then the rest of its body, and returns what the client received
+    fn proxy_request(proxy: &ReverseProxy, route_path: &str, callback: &str, request: &[u8], scheme: &'static str) -> (String, bool) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let head_length = request.windows(4).position(|v| v == b"\r\n\r\n").unwrap() + 4;
+        let (head, body) = (request[..head_length].to_vec(), request[head_length..].to_vec());
+        let client = thread::spawn(move || {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(&head).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            client.write_all(&body).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut incoming = read_request(&stream, false).expect("invalid test request");
+        incoming.set_scheme(scheme);
+        let forwarded = matches!(proxy.forward(&mut stream, &incoming, route_path, callback), ServerStatus::Ok(()));
+        drop(stream);
+        (client.join().unwrap(), forwarded)
+    }
+
+    #[test]
+    fn test_forward() {
+        let proxy = ReverseProxy::from_config(&HashMap::new());
+        let callback = format!("proxy://{}/app", test_upstream("a"));
+        let request = b"POST /dashboard/stats?day=1 HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\nContent-Length: 4\r\n\r\nping";
+        let (response, forwarded) = proxy_request(&proxy, "/dashboard/*", &callback, request, "http");
+        assert!(forwarded);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("a /app/stats day=1 10.0.0.1, 127.0.0.1 http ping"), "{}", response);
+
+        // the body still on the client stream is streamed to the upstream
+        let body = "x".repeat(200000);
+        let request = format!("POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let (response, forwarded) = proxy_request(&proxy, "/upload", &callback, request.as_bytes(), "https");
+        assert!(forwarded);
+        assert!(response.ends_with(&format!("a /app  127.0.0.1 https {}", body)), "{}", &response[..200]);
+    }
+
+    #[test]
+    fn test_round_robin_and_health() {
+        let proxy = ReverseProxy::from_config(&HashMap::new());
+        // nothing listens on the first upstream once the listener is dropped
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let callback = format!("proxy://{},{},{}#max_fails=1&fail_timeout=60", closed, test_upstream("a"), test_upstream("b"));
+        let request = b"GET /api/stats HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        let names: Vec<String> = (0..4).map(|_| {
+            let (response, forwarded) = proxy_request(&proxy, "/api/stats", &callback, request, "http");
+            assert!(forwarded);
+            response.rsplit("\r\n\r\n").next().unwrap().split(' ').next().unwrap().to_string()
+        }).collect();
+        // the closed upstream is skipped and marked as down, the two others alternate
+        assert_ne!(names[1], names[2]);
+        assert_ne!(names[2], names[3]);
+        let group = proxy.group(&callback).unwrap();
+        assert!(group.upstreams[0].health.lock().unwrap().down_until.is_some());
+    }
+}
@@ -0,0 +1,193 @@
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::database_utils::{Database, DatabaseError, Filter, Order, Value};
+
+/// The auth level which holds every permission, whatever the roles of the user
+const ADMIN_AUTH_LEVEL: u8 = 255;
+
+/// The roles and permissions of a user, read from the `user_roles` and `role_permissions` tables.
+///
+/// A permission is a name like `reports.view`, `*` grants every permission and `reports.*` every permission starting
+/// with `reports.`. A route needs every permission listed for it in `route_permissions` on top of its auth level, so
+/// that moderators can open `/admin/reports` but not `/admin/users`:
+/// ```text
+/// webserver-rs role create moderator --description "Reads the reports"
+/// webserver-rs role grant moderator reports.view
+/// webserver-rs role assign alice moderator
+/// sqlite3 data/database.db "INSERT INTO route_permissions VALUES ('get', '/admin/reports', 'reports.view')"
+/// ```
+/// The auth level 255 stays a shortcut for `*`, the users without roles keep working with the auth levels alone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Permissions {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+impl Permissions {
+    /// Returns the roles of the user and the permissions they grant, sorted and without duplicates
+    pub fn of_user(database: &Database, username: &str, auth_level: u8) -> Result<Permissions, DatabaseError> {
+        let roles: Vec<String> = database.select_rows("user_roles", &Filter::new().where_eq("username", username).order_by("role", Order::Ascending))?
+            .into_iter().filter_map(|mut v| match v.remove("role") {
+                Some(Value::String(v)) => Some(v),
+                _ => None,
+            }).collect();
+        let mut permissions = Vec::new();
+        for role in &roles {
+            for mut row in database.select_rows("role_permissions", &Filter::new().where_eq("role", role.as_str()))? {
+                if let Some(Value::String(v)) = row.remove("permission") {
+                    permissions.push(v);
+                }
+            }
+        }
+        if auth_level == ADMIN_AUTH_LEVEL {
+            permissions.push(String::from("*"));
+        }
+        permissions.sort();
+        permissions.dedup();
+        Ok(Permissions {roles, permissions})
+    }
+
+    /// Returns true if one of the permissions grants this one
+    pub fn grants(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|held| match held.strip_suffix('*') {
+            Some(prefix) => permission.starts_with(prefix),
+            None => held == permission,
+        })
+    }
+}
+
+/// Returns the permissions the route of this method (`get`, `post`...) and path needs
+pub fn route_permissions(database: &Database, method: &str, path: &str) -> Result<Vec<String>, DatabaseError> {
+    let filter = Filter::new().where_eq("method", method.to_lowercase()).where_eq("path", path);
+    Ok(database.select_rows("route_permissions", &filter)?.into_iter().filter_map(|mut v| match v.remove("permission") {
+        Some(Value::String(v)) => Some(v),
+        _ => None,
+    }).collect())
+}
+
+/// Runs the `role` subcommand, returns what has to be printed
+pub fn run_command(database: &Database, args: &[String]) -> Result<String, String> {
+    let usage = "Usage:\n    webserver-rs role list\n    webserver-rs role create <role> [--description <text>]\n    webserver-rs role delete <role>\n    webserver-rs role grant|revoke <role> <permission>\n    webserver-rs role assign|unassign <username> <role>";
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let known_role = |role: &str| match database.request_row("roles", "name", role) {
+        Ok(v) if v.is_empty() => Err(format!("Unknown role {}", role)),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    };
+    match args.as_slice() {
+        ["list"] => {
+            let mut lines = Vec::new();
+            for row in database.select_rows("roles", &Filter::new().order_by("name", Order::Ascending)).map_err(|e| e.to_string())? {
+                let text = |column: &str| match row.get(column) {
+                    Some(Value::String(v)) => v.clone(),
+                    _ => String::new(),
+                };
+                let name = text("name");
+                let column = |table: &str, column: &str| database.select_rows(table, &Filter::new().where_eq("role", name.as_str()).order_by(column, Order::Ascending))
+                    .map(|rows| rows.iter().filter_map(|v| match v.get(column) {
+                        Some(Value::String(v)) => Some(v.clone()),
+                        _ => None,
+                    }).collect::<Vec<String>>().join(" "))
+                    .map_err(|e| e.to_string());
+                lines.push(format!("{}\t{:?}\tpermissions: {}\tusers: {}", name, text("description"), column("role_permissions", "permission")?, column("user_roles", "username")?));
+            }
+            Ok(lines.join("\n"))
+        },
+        ["create", role, options @ ..] => {
+            let description = match options {
+                [] => "",
+                ["--description", v] => v,
+                _ => return Err(String::from(usage)),
+            };
+            if known_role(role).is_ok() {
+                return Err(format!("The role {} already exists", role));
+            }
+            database.insert_row("roles", &[("name", Value::from(*role)), ("description", Value::from(description))]).map_err(|e| e.to_string())?;
+            Ok(format!("Created the role {}", role))
+        },
+        ["delete", role] => {
+            known_role(role)?;
+            database.transaction(|t| {
+                t.delete_rows("role_permissions", &Filter::new().where_eq("role", *role))?;
+                t.delete_rows("user_roles", &Filter::new().where_eq("role", *role))?;
+                t.delete_rows("roles", &Filter::new().where_eq("name", *role))
+            }).map_err(|e| e.to_string())?;
+            Ok(format!("Deleted the role {}", role))
+        },
+        ["grant", role, permission] => {
+            known_role(role)?;
+            database.upsert_row("role_permissions", &[("role", Value::from(*role)), ("permission", Value::from(*permission))], &["role", "permission"]).map_err(|e| e.to_string())?;
+            Ok(format!("Granted {} to the role {}", permission, role))
+        },
+        ["revoke", role, permission] => match database.delete_rows("role_permissions", &Filter::new().where_eq("role", *role).where_eq("permission", *permission)) {
+            Ok(0) => Err(format!("The role {} doesn't have {}", role, permission)),
+            Ok(_) => Ok(format!("Revoked {} from the role {}", permission, role)),
+            Err(e) => Err(e.to_string()),
+        },
+        ["assign", username, role] => {
+            known_role(role)?;
+            if database.request_row("users", "username", username).map_err(|e| e.to_string())?.is_empty() {
+                return Err(format!("Unknown user {}", username));
+            }
+            database.upsert_row("user_roles", &[("username", Value::from(*username)), ("role", Value::from(*role))], &["username", "role"]).map_err(|e| e.to_string())?;
+            Ok(format!("Gave the role {} to {}", role, username))
+        },
+        ["unassign", username, role] => match database.delete_rows("user_roles", &Filter::new().where_eq("username", *username).where_eq("role", *role)) {
+            Ok(0) => Err(format!("{} doesn't have the role {}", username, role)),
+            Ok(_) => Ok(format!("Took the role {} from {}", role, username)),
+            Err(e) => Err(e.to_string()),
+        },
+        _ => Err(String::from(usage)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::roles::*;
+    use crate::database_utils::tests::migrated_database;
+    use crate::request_handler::{HTTPCode, IncomingRequest, ServerStatus};
+    use crate::sessions::SessionStore;
+    use crate::auth::Authenticator;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_roles() {
+        let database = migrated_database("roles");
+        let command = |args: &str| run_command(&database, &args.split(' ').map(String::from).collect::<Vec<String>>());
+
+        database.insert_row("users", &[("username", Value::from("alice")), ("auth_level", Value::from(1))]).unwrap();
+        assert!(command("create moderator").is_ok());
+        assert!(command("create moderator").is_err());
+        assert!(command("grant moderator reports.*").is_ok());
+        assert!(command("grant moderator reports.*").is_ok());
+        assert!(command("grant nobody reports.view").is_err());
+        assert!(command("assign bob moderator").is_err());
+        assert!(command("assign alice moderator").is_ok());
+
+        let permissions = Permissions::of_user(&database, "alice", 1).unwrap();
+        assert_eq!((vec![String::from("moderator")], vec![String::from("reports.*")]), (permissions.roles.clone(), permissions.permissions.clone()));
+        assert!(permissions.grants("reports.view") && !permissions.grants("users.edit"));
+        assert!(Permissions::of_user(&database, "bob", 255).unwrap().grants("users.edit"));
+        assert_eq!(Permissions::default(), Permissions::of_user(&database, "bob", 254).unwrap());
+
+        let session = SessionStore::from_config(&HashMap::new());
+        let id = session.create(&database, "alice", &IncomingRequest::new()).unwrap().id;
+        database.insert_row("requests_get", &[("path", Value::from("/admin/*")), ("callback", Value::from("admin.py")), ("auth_level", Value::from(1)), ("params", Value::from(""))]).unwrap();
+        database.insert_row("route_permissions", &[("method", Value::from("get")), ("path", Value::from("/admin/*")), ("permission", Value::from("reports.view"))]).unwrap();
+        let route = |path: &str, cookie: &str| {
+            let headers = HashMap::from([(String::from("cookie"), String::from(cookie))]);
+            let mut request = IncomingRequest::from_parts("GET", path, "HTTP/1.1", headers, Vec::new(), None, None);
+            let http_code = database.match_request(&mut request, &session, &Authenticator::from_config(&HashMap::new()));
+            (http_code, request.user().map(|v| v.permissions.roles.clone()))
+        };
+        assert!(matches!(route("/admin/reports", &format!("sessionID={}", id)), (ServerStatus::Ok(HTTPCode::Ok200(_)), Some(v)) if v == ["moderator"]));
+        assert!(matches!(route("/admin/reports", ""), (ServerStatus::Ok(HTTPCode::Err401), None)));
+        assert!(command("revoke moderator reports.*").is_ok());
+        assert!(matches!(route("/admin/reports", &format!("sessionID={}", id)), (ServerStatus::Ok(HTTPCode::Err403), Some(_))));
+
+        assert!(command("delete moderator").is_ok());
+        assert!(command("unassign alice moderator").is_err());
+        assert_eq!(Ok(String::new()), command("list").map(|v| v.lines().filter(|v| v.starts_with("moderator")).collect::<String>()));
+    }
+}
@@ -1,74 +1,548 @@
-use std::process::Command;
+use std::process::{Child, Command, Stdio};
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::{env, fs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-/// A function to run javascript code from a file,
-/// 
-/// # Example
-/// helloworld.js:
-/// ```js
-/// console.log("Hello world")
-/// ```
-/// 
-/// main.rs
+use json::JsonValue;
+
+use crate::script_workers::WorkerPool;
+
+/// Sets both the soft and the hard limit of a resource in the current process, returns the OS error if it fails
+macro_rules! set_rlimit {
+    ($resource: expr, $value: expr) => {
+        {
+            let limit = libc::rlimit {rlim_cur: $value as libc::rlim_t, rlim_max: $value as libc::rlim_t};
+            if libc::setrlimit($resource, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    };
+}
+
+/// The limits applied to a script run by [run_script] or [run_cgi], a `None` field means no limit.
+///
+/// The global limits are read from the server config and can be overridden for each route by the columns
+/// of the same name in the `requests_*` tables:
+/// ```json
+/// {
+///     "script_timeout":30,
+///     "script_cpu_time":10,
+///     "script_memory":536870912,
+///     "script_open_files":64,
+///     "script_output_size":10485760
+/// }
 /// ```
-/// let filepath = "helloworld.js";
-/// let output = run_js(filepath, "");
-/// println!("{}", output);
+/// `script_timeout` and `script_cpu_time` are in seconds, `script_memory` and `script_output_size` in bytes.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptLimits {
+    pub timeout: Option<Duration>,
+    pub cpu_time: Option<u64>,
+    pub memory: Option<u64>,
+    pub open_files: Option<u64>,
+    pub output_size: Option<usize>,
+}
+
+impl ScriptLimits {
+    /// Reads the limits from a config or a database row, missing, empty or invalid values are left unset
+    pub fn from_map(map: &HashMap<String, String>) -> ScriptLimits {
+        let number = |key: &str| map.get(key).and_then(|v| v.trim().parse::<u64>().ok()).filter(|v| *v > 0);
+        ScriptLimits {
+            timeout: map.get("script_timeout")
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| *v > 0.0)
+                .map(Duration::from_secs_f64),
+            cpu_time: number("script_cpu_time"),
+            memory: number("script_memory"),
+            open_files: number("script_open_files"),
+            output_size: number("script_output_size").map(|v| v as usize),
+        }
+    }
+
+    /// Returns these limits with every unset field taken from `fallback`
+    pub fn or(&self, fallback: &ScriptLimits) -> ScriptLimits {
+        ScriptLimits {
+            timeout: self.timeout.or(fallback.timeout),
+            cpu_time: self.cpu_time.or(fallback.cpu_time),
+            memory: self.memory.or(fallback.memory),
+            open_files: self.open_files.or(fallback.open_files),
+            output_size: self.output_size.or(fallback.output_size),
+        }
+    }
+}
+
+/// The reasons why running a script can fail
+#[derive(Debug)]
+pub enum ScriptError {
+    /// The script could not be started or exited with an error, contains the error message or the stderr of the script
+    Failed (String),
+    /// The script ran longer than its timeout and was killed
+    TimedOut,
+    /// The script wrote more than its output size limit and was killed
+    OutputTooLarge,
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ScriptError::Failed(e) => write!(f, "{}", e),
+            ScriptError::TimedOut => write!(f, "the script timed out"),
+            ScriptError::OutputTooLarge => write!(f, "the script output is too large"),
+        }
+    }
+}
+
+/// An interpreter of the registry of a [ScriptRunner], the scripts are run with `command args... script request`.
+///
+/// An interpreter without command runs the script itself, for compiled executables. `working_directory` and `env` are
+/// applied to the interpreter process, `worker` is the worker loop used by the persistent workers, see [WorkerPool].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interpreter {
+    pub name: String,
+    pub extensions: Vec<String>,
+    pub command: Option<String>,
+    pub args: Vec<String>,
+    pub working_directory: Option<String>,
+    pub env: Vec<(String, String)>,
+    pub worker: Option<String>,
+}
+
+impl Interpreter {
+    /// Reads an interpreter from its entry in the `interpreters` object of the server config
+    pub fn from_json(name: &str, entry: &JsonValue) -> Interpreter {
+        let strings = |value: &JsonValue| -> Vec<String> {
+            value.members().filter_map(|v| v.as_str()).map(String::from).collect()
+        };
+        Interpreter {
+            name: name.to_string(),
+            extensions: strings(&entry["extensions"]),
+            command: entry["command"].as_str().filter(|v| !v.is_empty()).map(String::from),
+            args: strings(&entry["args"]),
+            working_directory: entry["working_directory"].as_str().map(String::from),
+            env: entry["env"].entries().filter_map(|(k, v)| Some((k.to_string(), v.as_str()?.to_string()))).collect(),
+            worker: entry["worker"].as_str().map(String::from),
+        }
+    }
+
+    /// Builds an interpreter from the shebang line of a script, `#!/usr/bin/env ruby -w` gives `ruby -w`
+    fn from_shebang(shebang: &str) -> Option<Interpreter> {
+        let mut words = shebang.strip_prefix("#!")?.split_whitespace();
+        let mut command = words.next()?;
+        if command.rsplit('/').next() == Some("env") {
+            command = words.next()?;
+        }
+        Some(Interpreter {
+            name: command.rsplit('/').next().unwrap_or(command).to_string(),
+            extensions: Vec::new(),
+            command: Some(command.to_string()),
+            args: words.map(String::from).collect(),
+            working_directory: None,
+            env: Vec::new(),
+            worker: None,
+        })
+    }
+
+    /// Returns the command running `program_file`, the path of the script is made absolute when the interpreter
+    /// changes its working directory
+    pub fn command(&self, program_file: &str) -> Command {
+        let path = match self.working_directory {
+            Some(_) => fs::canonicalize(program_file).unwrap_or_else(|_| PathBuf::from(program_file)),
+            None => PathBuf::from(program_file),
+        };
+        let mut command = match &self.command {
+            Some(v) => {
+                let mut command = Command::new(v);
+                command.args(&self.args).arg(&path);
+                command
+            },
+            // a relative path without a slash would be looked up in PATH
+            None if path.is_relative() => Command::new(PathBuf::from(".").join(&path)),
+            None => Command::new(&path),
+        };
+        if let Some(v) = &self.working_directory {
+            command.current_dir(v);
+        }
+        command.envs(self.env.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        command
+    }
+}
+
+/// Runs the scripts of the routes with the interpreters of its registry, either by spawning an interpreter for each
+/// request or in the persistent workers of a [WorkerPool] when `script_workers` is set in the server config:
+/// ```json
+/// {
+///     "script_workers":2,
+///     "script_worker_max_requests":500,
+///     "interpreters":{
+///         "python":{"extensions":["py"], "command":"python3", "worker":"lib/script_worker.py"},
+///         "node":{"extensions":["js"], "command":"node", "worker":"lib/script_worker.js"},
+///         "ruby":{"extensions":["rb"], "command":"ruby", "args":["-W0"], "env":{"RUBYLIB":"lib"}},
+///         "venv":{"extensions":["vpy"], "command":".venv/bin/python", "working_directory":"data"},
+///         "binary":{"extensions":["bin"]}
+///     }
+/// }
 /// ```
-pub fn run_js(program_file: &str, args: &str) -> Result<Vec<u8>, String> {
-    let path = PathBuf::from(program_file);
-    let output = match Command::new("node")
-        .arg(path)
-        .arg(args)
-        .output()
-    {
-        Ok(v) => v,
-        Err(e) => {
-            return Err(format!("Failed to run script \"{:?}\": {}", program_file, e));
+/// `script_workers` is the number of workers of each interpreter with a `worker`, 0 (the default) disables the persistent workers.
+/// A file without extension is run by the interpreter named in its shebang line, `#!/usr/bin/env ruby` uses the `ruby`
+/// entry of the registry if there is one or runs `ruby` as is. Without `interpreters` in the config, only the python and node
+/// entries above are registered.
+pub struct ScriptRunner {
+    /// The global limits, used when a route doesn't override them
+    pub limits: ScriptLimits,
+    interpreters: Vec<Interpreter>,
+    workers: HashMap<String, WorkerPool>,
+}
+
+impl ScriptRunner {
+    /// Creates the runner, its registry and its worker pools from the server config, see [ScriptLimits] for the limits
+    pub fn from_config(config: &HashMap<String, String>, interpreters: &JsonValue) -> ScriptRunner {
+        let limits = ScriptLimits::from_map(config);
+        let interpreters: Vec<Interpreter> = match interpreters.is_object() {
+            true => interpreters.entries().map(|(name, entry)| Interpreter::from_json(name, entry)).collect(),
+            false => default_interpreters(),
+        };
+        let size = config.get("script_workers").and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(0);
+        let max_requests = config.get("script_worker_max_requests").and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(500);
+        let mut workers = HashMap::new();
+        if size > 0 {
+            for interpreter in interpreters.iter().filter(|v| v.worker.is_some() && v.command.is_some()) {
+                workers.insert(interpreter.name.clone(), WorkerPool::new(interpreter, size, max_requests, &limits));
+            }
         }
-    };
+        ScriptRunner {limits, interpreters, workers}
+    }
 
-    if output.status.success() {
-        Ok(output.stdout)
-    } else {
-        let result = String::from_utf8_lossy(&output.stderr);
-        Err(result.to_string())
+    /// Finds the interpreter of `program_file` from its extension or, for a file without extension, its shebang line.
+    ///
+    /// Returns `None` for the files which aren't scripts and are sent as they are.
+    pub fn find_interpreter(&self, program_file: &str) -> Option<Interpreter> {
+        let path = std::path::Path::new(program_file);
+        if let Some(extension) = path.extension() {
+            return self.interpreters.iter().find(|v| v.extensions.iter().any(|e| e.as_str() == extension)).cloned();
+        }
+        let mut first_line = String::new();
+        let file = fs::File::open(path).ok()?;
+        std::io::BufReader::new(file.take(256)).read_line(&mut first_line).ok()?;
+        let shebang = Interpreter::from_shebang(first_line.trim_end())?;
+        let registered = self.interpreters.iter().find(|v| {
+            v.name == shebang.name || v.command.as_deref().and_then(|c| c.rsplit('/').next()) == Some(shebang.name.as_str())
+        });
+        Some(registered.cloned().unwrap_or(shebang))
     }
+
+    /// Runs a script with its interpreter and the route `limits`, falling back on the global limits, see [run_script]
+    pub fn run_script(&self, interpreter: &Interpreter, program_file: &str, args: &str, limits: &ScriptLimits) -> Result<Vec<u8>, ScriptError> {
+        let limits = limits.or(&self.limits);
+        match self.workers.get(&interpreter.name) {
+            Some(pool) if &pool.interpreter == interpreter => pool.run(program_file, args, &limits),
+            _ => run_script(interpreter, program_file, args, &limits),
+        }
+    }
+
+    /// Runs a CGI script with the route `limits`, falling back on the global limits, see [run_cgi]
+    pub fn run_cgi(&self, program_file: &str, variables: &[(String, String)], body: &[u8], limits: &ScriptLimits) -> Result<Vec<u8>, ScriptError> {
+        let interpreter = self.find_interpreter(program_file);
+        run_cgi(interpreter.as_ref(), program_file, variables, body, &limits.or(&self.limits))
+    }
+
+    /// Starts a long-running script with piped stdin and stdout in its own process group and the variables of `env` added
+    /// to its environment, used by the WebSocket and Server-Sent Events endpoints.
+    ///
+    /// The timeout and output size limits don't apply, the caller has to kill the process group with [kill_group].
+    pub fn spawn_script(&self, program_file: &str, args: &str, env: &[(String, String)], limits: &ScriptLimits) -> Result<Child, ScriptError> {
+        let interpreter = match self.find_interpreter(program_file) {
+            Some(v) => v,
+            None => return Err(ScriptError::Failed(format!("No interpreter found for \"{}\"", program_file))),
+        };
+        let mut command = interpreter.command(program_file);
+        command.arg(args)
+            .envs(env.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .process_group(0);
+        set_process_limits(&mut command, &limits.or(&self.limits));
+        command.spawn().map_err(|e| ScriptError::Failed(format!("Failed to start script \"{}\": {}", program_file, e)))
+    }
+}
+
+/// The interpreters registered when the config has no `interpreters` object
+fn default_interpreters() -> Vec<Interpreter> {
+    let interpreter = |name: &str, extension: &str, command: &str, worker: &str| Interpreter {
+        name: name.to_string(),
+        extensions: vec![extension.to_string()],
+        command: Some(command.to_string()),
+        args: Vec::new(),
+        working_directory: None,
+        env: Vec::new(),
+        worker: Some(worker.to_string()),
+    };
+    vec![
+        interpreter("python", "py", "python3", "lib/script_worker.py"),
+        interpreter("node", "js", "node", "lib/script_worker.js"),
+    ]
 }
 
-/// A function to run python code from a file,
-/// 
+/// A function to run a script from a file with its interpreter, the request is passed as the last argument
+///
 /// # Example
 /// helloworld.py:
 /// ```python
 /// print("Hello world")
 /// ```
-/// 
+///
 /// main.rs
 /// ```
 /// let filepath = "helloworld.py";
-/// let output = run_python(filepath, "");
+/// let interpreter = script_runner.find_interpreter(filepath).unwrap();
+/// let output = run_script(&interpreter, filepath, "", &ScriptLimits::default());
 /// println!("{}", output);
 /// ```
-pub fn run_python(program_file: &str, args: &str) -> Result<Vec<u8>, String> {
-    let path = PathBuf::from(program_file);
-    let output = match Command::new("python3")
-        .arg(path)
-        .arg(args)
-        .output()
-    {
+///
+/// The script runs in its own process group, the rlimits are set in the child before the interpreter starts,
+/// if the script runs longer than the timeout or writes more than the output size limit the whole process group is killed.
+pub fn run_script(interpreter: &Interpreter, program_file: &str, args: &str, limits: &ScriptLimits) -> Result<Vec<u8>, ScriptError> {
+    let mut command = interpreter.command(program_file);
+    command.arg(args);
+    run_command(command, program_file, None, limits)
+}
+
+/// Runs a CGI/1.1 script (RFC 3875) and returns the CGI response it wrote on its stdout.
+///
+/// The script gets the meta-variables in `variables`, `PATH` and the environment of its interpreter as its only environment,
+/// the body of the request on its stdin and runs in its own directory unless its interpreter sets one.
+/// A script without interpreter has to be executable.
+pub fn run_cgi(interpreter: Option<&Interpreter>, program_file: &str, variables: &[(String, String)], body: &[u8], limits: &ScriptLimits) -> Result<Vec<u8>, ScriptError> {
+    let path = match fs::canonicalize(program_file) {
+        Ok(v) => v,
+        Err(e) => return Err(ScriptError::Failed(format!("Failed to find CGI script \"{}\": {}", program_file, e))),
+    };
+    let path_string = path.to_string_lossy().to_string();
+    let mut command = match interpreter {
+        Some(v) => v.command(&path_string),
+        None => Command::new(&path),
+    };
+    if interpreter.is_none_or(|v| v.working_directory.is_none()) {
+        if let Some(directory) = path.parent() {
+            command.current_dir(directory);
+        }
+    }
+    let interpreter_env = interpreter.map(|v| v.env.clone()).unwrap_or_default();
+    command.env_clear()
+        .env("PATH", env::var_os("PATH").unwrap_or_default())
+        .envs(interpreter_env.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .envs(variables.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    run_command(command, program_file, Some(body.to_vec()), limits)
+}
+
+/// Runs `command` in its own process group, writes `stdin` to it and returns its stdout
+fn run_command(mut command: Command, program_file: &str, stdin: Option<Vec<u8>>, limits: &ScriptLimits) -> Result<Vec<u8>, ScriptError> {
+    command.stdin(if stdin.is_some() {Stdio::piped()} else {Stdio::null()})
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+
+    set_process_limits(&mut command, limits);
+
+    let mut child = match command.spawn() {
         Ok(v) => v,
         Err(e) => {
-            return Err(format!("Failed to run script \"{:?}\": {}", program_file, e));
+            return Err(ScriptError::Failed(format!("Failed to run script \"{:?}\": {}", program_file, e)));
         }
     };
+    let started = Instant::now();
+
+    // written from another thread, the script could fill its stdout before reading all its stdin
+    if let (Some(mut pipe), Some(input)) = (child.stdin.take(), stdin) {
+        thread::spawn(move || {
+            let _ = pipe.write_all(&input);
+        });
+    }
+
+    let max_output = limits.output_size.unwrap_or(usize::MAX);
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take().map(|stdout| thread::spawn(move || {
+        let mut output = Vec::new();
+        let result = stdout.take((max_output as u64).saturating_add(1)).read_to_end(&mut output);
+        let _ = sender.send(output.len() > max_output);
+        result.map(|_| output)
+    }));
+    let stderr = child.stderr.take().map(|mut stderr| thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stderr.read_to_end(&mut output);
+        output
+    }));
+
+    // stdout is closed when the script exits (or reaches the output limit), then the script is reaped
+    let remaining = || limits.timeout.map(|t| t.saturating_sub(started.elapsed()));
+    let finished = match remaining() {
+        Some(v) => receiver.recv_timeout(v) == Ok(false),
+        None => receiver.recv() == Ok(false),
+    };
+    // a script can close its stdout and keep running, it is waited for until the end of its timeout
+    let waited = match (finished, limits.timeout) {
+        (false, _) => Ok(None),
+        (true, None) => child.wait().map(Some),
+        (true, Some(timeout)) => wait_until(&mut child, started + timeout),
+    };
+    let status = match waited {
+        Ok(v) => v,
+        Err(e) => {kill_group(&mut child); return Err(ScriptError::Failed(e.to_string()));},
+    };
+    if status.is_none() {
+        kill_group(&mut child);
+    }
+
+    let output = match stdout.map(|t| t.join()) {
+        Some(Ok(Ok(v))) => v,
+        _ => Vec::new(),
+    };
+    let error_output = match stderr.map(|t| t.join()) {
+        Some(Ok(v)) => v,
+        _ => Vec::new(),
+    };
+
+    if output.len() > max_output {
+        kill_group(&mut child);
+        return Err(ScriptError::OutputTooLarge);
+    }
+    match status {
+        Some(v) if v.success() => Ok(output),
+        Some(_) => Err(ScriptError::Failed(String::from_utf8_lossy(&error_output).to_string())),
+        None => Err(ScriptError::TimedOut),
+    }
+}
 
-    if output.status.success() {
-        Ok(output.stdout)
-    } else {
-        let result = String::from_utf8_lossy(&output.stderr);
-        Err(result.to_string())
+/// Waits for the child to exit until the deadline, returns `None` if it is still running. The standard library can't
+/// wait with a timeout, so the child is polled more and more slowly, at most every 50 ms.
+fn wait_until(child: &mut std::process::Child, deadline: Instant) -> std::io::Result<Option<std::process::ExitStatus>> {
+    let mut interval = Duration::from_millis(1);
+    loop {
+        if let Some(v) = child.try_wait()? {
+            return Ok(Some(v));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        thread::sleep(interval.min(remaining));
+        interval = (interval * 2).min(Duration::from_millis(50));
+    }
+}
+
+/// Sets the cpu time, memory and open files rlimits of `limits` in the process spawned by `command`
+pub fn set_process_limits(command: &mut Command, limits: &ScriptLimits) {
+    let (cpu_time, memory, open_files) = (limits.cpu_time, limits.memory, limits.open_files);
+    if cpu_time.is_none() && memory.is_none() && open_files.is_none() {
+        return;
+    }
+    // only async-signal-safe calls are allowed between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(v) = cpu_time {set_rlimit!(libc::RLIMIT_CPU, v);}
+            if let Some(v) = memory {set_rlimit!(libc::RLIMIT_AS, v);}
+            if let Some(v) = open_files {set_rlimit!(libc::RLIMIT_NOFILE, v);}
+            Ok(())
+        });
+    }
+}
+
+/// Kills the process group of the child and reaps it so that no zombie is left behind
+pub fn kill_group(child: &mut std::process::Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
     }
+    let _ = child.wait();
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::script_runner::*;
+
+    fn python() -> Interpreter {
+        default_interpreters().remove(0)
+    }
 
+    /// Writes a python script in the temp directory and returns its path
+    fn test_script(name: &str, code: &str) -> String {
+        let filepath = std::env::temp_dir().join(format!("webserver-rs-{}-{}.py", name, std::process::id()));
+        std::fs::write(&filepath, code).unwrap();
+        filepath.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_script_timeout() {
+        let script = test_script("timeout", "import time\nprint('started', flush=True)\ntime.sleep(30)");
+        let limits = ScriptLimits {timeout: Some(Duration::from_millis(300)), ..ScriptLimits::default()};
+        let started = Instant::now();
+        assert!(matches!(run_script(&python(), &script, "", &limits), Err(ScriptError::TimedOut)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_script_closing_stdout() {
+        let script = test_script("close_stdout", "import os, sys, time\nprint('done', flush=True)\nos.close(sys.stdout.fileno())\ntime.sleep(0.5)");
+        let started = Instant::now();
+        assert_eq!(b"done\n".to_vec(), run_script(&python(), &script, "", &ScriptLimits::default()).unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        let script = test_script("close_stdout_timeout", "import os, sys, time\nos.close(sys.stdout.fileno())\ntime.sleep(30)");
+        let limits = ScriptLimits {timeout: Some(Duration::from_millis(300)), ..ScriptLimits::default()};
+        let started = Instant::now();
+        assert!(matches!(run_script(&python(), &script, "", &limits), Err(ScriptError::TimedOut)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_script_output_size() {
+        let script = test_script("output", "print('a' * 100)");
+        let limits = ScriptLimits {output_size: Some(10), ..ScriptLimits::default()};
+        assert!(matches!(run_script(&python(), &script, "", &limits), Err(ScriptError::OutputTooLarge)));
+        let output = run_script(&python(), &script, "", &ScriptLimits::default()).unwrap();
+        assert_eq!(101, output.len());
+    }
+
+    #[test]
+    fn test_run_cgi() {
+        let script = test_script("cgi", "import os, sys\nprint('Content-Type: text/plain')\nprint()\nprint(os.environ['REQUEST_METHOD'], sys.stdin.read(), os.getcwd(), 'SERVER_CONFIG' in os.environ)");
+        let variables = vec![(String::from("REQUEST_METHOD"), String::from("POST"))];
+        let output = run_cgi(Some(&python()), &script, &variables, b"a=1", &ScriptLimits::default()).unwrap();
+        let directory = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        assert_eq!(format!("Content-Type: text/plain\n\nPOST a=1 {} False\n", directory.display()), String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn test_find_interpreter() {
+        let config = json::parse(r#"{
+            "ruby":{"extensions":["rb"], "command":"ruby", "args":["-W0"]},
+            "binary":{"extensions":["bin", "exe"]}
+        }"#).unwrap();
+        let runner = ScriptRunner::from_config(&HashMap::new(), &config);
+        assert_eq!(Some(vec![String::from("-W0")]), runner.find_interpreter("data/pages/get/page.rb").map(|v| v.args));
+        assert_eq!(Some(None), runner.find_interpreter("data/pages/get/tool.exe").map(|v| v.command));
+        assert_eq!(None, runner.find_interpreter("data/pages/get/homepage.py"));
+
+        let script = test_script("shebang", "");
+        let script = script.strip_suffix(".py").unwrap();
+        std::fs::write(script, "#!/usr/bin/env ruby -w\nputs 1").unwrap();
+        let interpreter = runner.find_interpreter(script).unwrap();
+        assert_eq!(("ruby", vec![String::from("-W0")]), (interpreter.name.as_str(), interpreter.args));
+        std::fs::write(script, "#!/bin/sh -e\necho 1").unwrap();
+        let interpreter = runner.find_interpreter(script).unwrap();
+        assert_eq!((Some(String::from("/bin/sh")), vec![String::from("-e")]), (interpreter.command, interpreter.args));
+        assert_eq!(b"1\n".to_vec(), run_script(&runner.find_interpreter(script).unwrap(), script, "", &ScriptLimits::default()).unwrap());
+    }
+
+    #[test]
+    fn test_script_limits_override() {
+        let route = HashMap::from([(String::from("script_timeout"), String::from("2")), (String::from("script_memory"), String::new())]);
+        let global = ScriptLimits {timeout: Some(Duration::from_secs(30)), memory: Some(1024), ..ScriptLimits::default()};
+        let limits = ScriptLimits::from_map(&route).or(&global);
+        assert_eq!(Some(Duration::from_secs(2)), limits.timeout);
+        assert_eq!(Some(1024), limits.memory);
+    }
+}
@@ -0,0 +1,247 @@
+use std::process::{Child, ChildStdin, Stdio};
+use std::os::unix::process::CommandExt;
+use std::io::{Read, Write};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::script_runner::{Interpreter, ScriptError, ScriptLimits, set_process_limits, kill_group};
+
+/// A pool of long-lived interpreter processes running [the worker loop](../lib/script_worker.py) of their language.
+///
+/// The server and a worker exchange frames over the stdin and stdout of the worker, a frame is a 4 bytes big endian length
+/// followed by the payload:
+/// ```text
+/// server -> worker: {"script":"data/pages/get/homepage.py","args":"<request as json>"}
+/// worker -> server: one status byte (0: success, 1: error) followed by the output of the script or its error
+/// ```
+/// A worker which crashes or times out is killed and replaced by a new one on the next request,
+/// a worker is also recycled after it has served `max_requests` requests.
+pub struct WorkerPool {
+    pub interpreter: Interpreter,
+    worker_script: String,
+    size: usize,
+    max_requests: usize,
+    limits: ScriptLimits,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+/// The idle workers of a [WorkerPool] and the number of workers alive, idle or busy
+struct PoolState {
+    idle: Vec<Worker>,
+    alive: usize,
+}
+
+impl WorkerPool {
+    /// Creates a new pool of at most `size` workers running the `worker` of the interpreter, the workers are started
+    /// when they are first needed.
+    ///
+    /// The memory and open files limits are applied to every worker, the cpu time limit is ignored since
+    /// a worker accumulates the cpu time of all the requests it serves.
+    pub fn new(interpreter: &Interpreter, size: usize, max_requests: usize, limits: &ScriptLimits) -> WorkerPool {
+        WorkerPool {
+            interpreter: interpreter.clone(),
+            worker_script: interpreter.worker.clone().unwrap_or_default(),
+            size: size.max(1),
+            max_requests: max_requests.max(1),
+            limits: ScriptLimits {cpu_time: None, ..limits.clone()},
+            state: Mutex::new(PoolState {idle: Vec::new(), alive: 0}),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Runs `program_file` in one of the workers of the pool and returns its output, waits for a worker if they are all busy
+    pub fn run(&self, program_file: &str, args: &str, limits: &ScriptLimits) -> Result<Vec<u8>, ScriptError> {
+        let mut worker = self.checkout()?;
+        let envelope = json::object!{script: program_file, args: args}.dump();
+        if let Err(e) = write_frame(&mut worker.stdin, envelope.as_bytes()) {
+            self.checkin(None);
+            return Err(ScriptError::Failed(format!("Failed to send the request to the {} worker: {}", self.interpreter.name, e)));
+        }
+
+        let response = match limits.timeout {
+            Some(v) => worker.frames.recv_timeout(v).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => ScriptError::TimedOut,
+                mpsc::RecvTimeoutError::Disconnected => ScriptError::Failed(String::from("The worker exited")),
+            }),
+            None => worker.frames.recv().map_err(|_| ScriptError::Failed(String::from("The worker exited"))),
+        };
+        let frame = match response.and_then(|v| v) {
+            Ok(v) => v,
+            Err(e) => {
+                // the worker is in an unknown state, it is killed when dropped and replaced on the next request
+                self.checkin(None);
+                return Err(e);
+            }
+        };
+
+        worker.served += 1;
+        if worker.served >= self.max_requests {
+            debug!("Recycling {} worker after {} requests", self.interpreter.name, worker.served);
+            self.checkin(None);
+        } else {
+            self.checkin(Some(worker));
+        }
+
+        match frame.split_first() {
+            Some((0, output)) if limits.output_size.is_some_and(|v| output.len() > v) => Err(ScriptError::OutputTooLarge),
+            Some((0, output)) => Ok(output.to_vec()),
+            Some((_, error)) => Err(ScriptError::Failed(String::from_utf8_lossy(error).to_string())),
+            None => Err(ScriptError::Failed(String::from("The worker sent an empty response"))),
+        }
+    }
+
+    /// Takes an idle worker out of the pool, starts a new one if there is none and the pool isn't full
+    fn checkout(&self) -> Result<Worker, ScriptError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(mut worker) = state.idle.pop() {
+                if let Ok(None) = worker.child.try_wait() {
+                    return Ok(worker);
+                }
+                warn!("A {} worker exited, restarting it", self.interpreter.name);
+                state.alive -= 1;
+                continue;
+            }
+            if state.alive < self.size {
+                state.alive += 1;
+                drop(state);
+                return self.spawn().inspect_err(|_| self.checkin(None));
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Gives a worker back to the pool, `None` means the worker was dropped and another one can be started
+    fn checkin(&self, worker: Option<Worker>) {
+        let mut state = self.state.lock().unwrap();
+        match worker {
+            Some(v) => state.idle.push(v),
+            None => state.alive -= 1,
+        }
+        self.available.notify_one();
+    }
+
+    /// Starts a new worker and the thread reading the frames it sends
+    fn spawn(&self) -> Result<Worker, ScriptError> {
+        let mut command = self.interpreter.command(&self.worker_script);
+        command.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .process_group(0);
+        set_process_limits(&mut command, &self.limits);
+
+        let mut child = match command.spawn() {
+            Ok(v) => v,
+            Err(e) => return Err(ScriptError::Failed(format!("Failed to start worker \"{}\": {}", self.worker_script, e))),
+        };
+        let (stdin, mut stdout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => {
+                kill_group(&mut child);
+                return Err(ScriptError::Failed(String::from("Failed to open the pipes of the worker")));
+            }
+        };
+
+        let max_frame = self.limits.output_size.map(|v| v.saturating_add(1)).unwrap_or(usize::MAX);
+        let (sender, frames) = mpsc::channel();
+        thread::spawn(move || loop {
+            let frame = read_frame(&mut stdout, max_frame);
+            let stop = frame.is_err();
+            if sender.send(frame).is_err() || stop {
+                break;
+            }
+        });
+        info!("Started {} worker {}", self.interpreter.name, child.id());
+        Ok(Worker {child, stdin, frames, served: 0})
+    }
+}
+
+/// A worker process of a [WorkerPool], it is killed when dropped
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    frames: mpsc::Receiver<Result<Vec<u8>, ScriptError>>,
+    served: usize,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        kill_group(&mut self.child);
+    }
+}
+
+/// Writes the length of the payload followed by the payload
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
+    stream.flush()
+}
+
+/// Reads a frame written by [write_frame], refuses the frames bigger than `max_len`
+fn read_frame(stream: &mut impl Read, max_len: usize) -> Result<Vec<u8>, ScriptError> {
+    let mut length = [0u8; 4];
+    if let Err(e) = stream.read_exact(&mut length) {
+        return Err(ScriptError::Failed(format!("The worker exited: {}", e)));
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_len {
+        return Err(ScriptError::OutputTooLarge);
+    }
+    let mut payload = vec![0; length];
+    match stream.read_exact(&mut payload) {
+        Ok(()) => Ok(payload),
+        Err(e) => Err(ScriptError::Failed(format!("The worker exited: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::script_workers::*;
+    use std::time::Duration;
+
+    /// Writes a python script in the temp directory and returns its path
+    fn test_script(name: &str, code: &str) -> String {
+        let filepath = std::env::temp_dir().join(format!("webserver-rs-worker-{}-{}.py", name, std::process::id()));
+        std::fs::write(&filepath, code).unwrap();
+        filepath.to_str().unwrap().to_string()
+    }
+
+    fn python() -> Interpreter {
+        let entry = json::parse(r#"{"extensions":["py"], "command":"python3", "worker":"lib/script_worker.py"}"#).unwrap();
+        Interpreter::from_json("python", &entry)
+    }
+
+    #[test]
+    fn test_worker_pool() {
+        let pool = WorkerPool::new(&python(), 1, 2, &ScriptLimits::default());
+        let script = test_script("pid", "import os, sys\nsys.stdout.write(str(os.getpid()) + ' ' + sys.argv[1])\nsys.exit()");
+        let limits = ScriptLimits {timeout: Some(Duration::from_secs(10)), ..ScriptLimits::default()};
+
+        let first = String::from_utf8(pool.run(&script, "a", &limits).unwrap()).unwrap();
+        let second = String::from_utf8(pool.run(&script, "b", &limits).unwrap()).unwrap();
+        let third = String::from_utf8(pool.run(&script, "c", &limits).unwrap()).unwrap();
+        let pid = |output: &str| output.split(' ').next().unwrap().to_string();
+        assert!(first.ends_with(" a") && second.ends_with(" b") && third.ends_with(" c"));
+        // the worker served the first two requests and was recycled before the third
+        assert_eq!(pid(&first), pid(&second));
+        assert_ne!(pid(&second), pid(&third));
+    }
+
+    #[test]
+    fn test_worker_errors() {
+        let pool = WorkerPool::new(&python(), 1, 100, &ScriptLimits::default());
+        let failing = test_script("fail", "raise ValueError('broken page')");
+        let sleeping = test_script("sleep", "import time\ntime.sleep(30)");
+        let working = test_script("ok", "print('ok')");
+        let limits = ScriptLimits {timeout: Some(Duration::from_millis(500)), ..ScriptLimits::default()};
+
+        assert!(matches!(pool.run(&failing, "", &limits), Err(ScriptError::Failed(e)) if e.contains("broken page")));
+        assert!(matches!(pool.run(&sleeping, "", &limits), Err(ScriptError::TimedOut)));
+        // the worker which timed out is replaced
+        assert_eq!(b"ok\n".to_vec(), pool.run(&working, "", &limits).unwrap());
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+use json::JsonValue;
+
+use crate::database_utils::Database;
+use crate::request_handler::RequestLimits;
+use crate::script_runner::ScriptRunner;
+use crate::reverse_proxy::ReverseProxy;
+use crate::websocket::WebSocketServer;
+use crate::event_stream::EventStreamServer;
+use crate::http2::Http2Settings;
+use crate::auth::Authenticator;
+use crate::sessions::SessionStore;
+use crate::rate_limiter::RateLimiter;
+
+/// The settings, the database pool and the script runner shared by every connection, built once in `main` from the server config file
+pub struct ServerConfig {
+    pub database: Database,
+    pub request_limits: RequestLimits,
+    pub script_runner: ScriptRunner,
+    pub reverse_proxy: ReverseProxy,
+    pub websockets: WebSocketServer,
+    pub event_streams: EventStreamServer,
+    pub http2: Http2Settings,
+    pub auth: Authenticator,
+    pub sessions: SessionStore,
+    pub rate_limiter: RateLimiter,
+}
+
+impl ServerConfig {
+    /// Builds the settings from the parsed config file, see [Database], [RequestLimits], [ScriptRunner], [ReverseProxy],
+    /// [WebSocketServer], [EventStreamServer], [Http2Settings], [Authenticator], [SessionStore] and [RateLimiter] for the keys they read
+    pub fn from_config(config_json: &JsonValue) -> ServerConfig {
+        let config = flatten_config(config_json);
+        ServerConfig {
+            database: Database::from_config(&config),
+            request_limits: RequestLimits::from_config(&config),
+            script_runner: ScriptRunner::from_config(&config, &config_json["interpreters"]),
+            reverse_proxy: ReverseProxy::from_config(&config),
+            websockets: WebSocketServer::from_config(&config),
+            event_streams: EventStreamServer::from_config(&config),
+            http2: Http2Settings::from_config(&config),
+            auth: Authenticator::from_config(&config),
+            sessions: SessionStore::from_config(&config),
+            rate_limiter: RateLimiter::from_config(&config_json["rate_limits"]),
+        }
+    }
+}
+
+/// Returns the top level values of the config file as strings, the objects and arrays are left out
+pub fn flatten_config(config_json: &JsonValue) -> HashMap<String, String> {
+    config_json.entries()
+        .filter(|(_, v)| !v.is_object() && !v.is_array() && !v.is_null())
+        .map(|(k, v)| (k.to_string(), v.as_str().map(String::from).unwrap_or_else(|| v.dump())))
+        .collect()
+}
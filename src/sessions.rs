@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::crypto_utils::{hex_encode, random_bytes};
+use crate::database_utils::{Comparison, Database, DatabaseError, Filter, Order, Row, Value, now};
+use crate::request_handler::IncomingRequest;
+
+/// A session is only written back to the database when it was last seen more than this number of seconds ago,
+/// so that a page loading a lot of resources doesn't write the session for each of them
+const REFRESH_INTERVAL: u64 = 60;
+
+/// The sessions of the users, stored in the `sessions` table, a user can be logged in from several clients at once.
+///
+/// The expiration slides: a session expires `session_expiration_time` seconds after it was last used. The expired
+/// sessions are deleted every `session_cleanup_interval` seconds:
+/// ```json
+/// {
+///     "session_expiration_time":1800,
+///     "session_cleanup_interval":3600
+/// }
+/// ```
+pub struct SessionStore {
+    lifetime: u64,
+    cleanup_interval: Duration,
+}
+
+/// A row of the `sessions` table, the times are UNIX timestamps in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    pub created: i64,
+    pub expires: i64,
+    pub last_seen: i64,
+    pub ip: String,
+    pub user_agent: String,
+    /// The token the forms of the session have to send back, see [check_csrf](crate::auth::check_csrf)
+    pub csrf_token: String,
+}
+
+impl SessionStore {
+    /// Creates the store with the session lifetime and the cleanup interval of the server config
+    pub fn from_config(config: &HashMap<String, String>) -> SessionStore {
+        SessionStore {
+            lifetime: config.get("session_expiration_time").and_then(|v| v.trim().parse::<u64>().ok()).unwrap_or(1800),
+            cleanup_interval: Duration::from_secs(config.get("session_cleanup_interval").and_then(|v| v.trim().parse::<u64>().ok()).filter(|v| *v > 0).unwrap_or(3600)),
+        }
+    }
+
+    /// The number of seconds a session lasts without being used, the `Max-Age` of the session cookie
+    pub fn lifetime(&self) -> u64 {
+        self.lifetime
+    }
+
+    /// Starts a new session for the user, its ID and its CSRF token are 32 bytes from the kernel CSPRNG
+    pub fn create(&self, database: &Database, username: &str, incoming_request: &IncomingRequest) -> Result<Session, DatabaseError> {
+        let (id, csrf_token) = (random_token()?, random_token()?);
+        let now = now();
+        let session = Session {
+            id,
+            username: username.to_string(),
+            created: now,
+            expires: now + self.lifetime as i64,
+            last_seen: now,
+            ip: incoming_request.remote_addr().map(|v| v.ip().to_string()).unwrap_or_default(),
+            user_agent: incoming_request.headers().get("user-agent").cloned().unwrap_or_default(),
+            csrf_token,
+        };
+        database.insert_row("sessions", &[
+            ("id", Value::from(session.id.as_str())),
+            ("username", Value::from(session.username.as_str())),
+            ("created", Value::from(session.created)),
+            ("expires", Value::from(session.expires)),
+            ("last_seen", Value::from(session.last_seen)),
+            ("ip", Value::from(session.ip.as_str())),
+            ("user_agent", Value::from(session.user_agent.as_str())),
+            ("csrf_token", Value::from(session.csrf_token.as_str())),
+        ])?;
+        Ok(session)
+    }
+
+    /// Returns the session with this ID if it hasn't expired, and pushes its expiration back
+    pub fn resolve(&self, database: &Database, id: &str) -> Result<Option<Session>, DatabaseError> {
+        let now = now();
+        let filter = Filter::new().where_eq("id", id).condition("expires", Comparison::Greater, now);
+        let Some(mut session) = database.select_rows("sessions", &filter)?.pop().map(Session::from_row) else {
+            return Ok(None);
+        };
+        if now - session.last_seen >= REFRESH_INTERVAL as i64 {
+            session.last_seen = now;
+            session.expires = now + self.lifetime as i64;
+            let values = [("last_seen", Value::from(session.last_seen)), ("expires", Value::from(session.expires))];
+            database.update_rows("sessions", &values, &Filter::new().where_eq("id", id))?;
+        }
+        Ok(Some(session))
+    }
+
+    /// Returns the sessions of the user which haven't expired, the most recently used first
+    #[allow(dead_code)]
+    pub fn list(&self, database: &Database, username: &str) -> Result<Vec<Session>, DatabaseError> {
+        let filter = Filter::new().where_eq("username", username).condition("expires", Comparison::Greater, now()).order_by("last_seen", Order::Descending);
+        Ok(database.select_rows("sessions", &filter)?.into_iter().map(Session::from_row).collect())
+    }
+
+    /// Ends a session, returns false if it didn't exist
+    pub fn revoke(&self, database: &Database, id: &str) -> Result<bool, DatabaseError> {
+        Ok(database.delete_rows("sessions", &Filter::new().where_eq("id", id))? > 0)
+    }
+
+    /// Ends every session of the user, returns how many there were
+    pub fn revoke_all(&self, database: &Database, username: &str) -> Result<usize, DatabaseError> {
+        database.delete_rows("sessions", &Filter::new().where_eq("username", username))
+    }
+
+    /// Deletes the expired sessions, returns how many there were
+    pub fn cleanup(&self, database: &Database) -> Result<usize, DatabaseError> {
+        database.delete_rows("sessions", &Filter::new().condition("expires", Comparison::LessOrEqual, now()))
+    }
+
+    /// Starts the thread which deletes the expired sessions every `session_cleanup_interval` seconds
+    pub fn start_cleanup(&self, database: &Database) {
+        let (store, database) = (SessionStore {lifetime: self.lifetime, cleanup_interval: self.cleanup_interval}, database.clone());
+        thread::spawn(move || loop {
+            thread::sleep(store.cleanup_interval);
+            match store.cleanup(&database) {
+                Ok(0) => (),
+                Ok(v) => debug!("Deleted {} expired sessions", v),
+                Err(e) => warn!("Error when deleting the expired sessions: {}", e),
+            }
+        });
+    }
+}
+
+impl Session {
+    fn from_row(mut row: Row) -> Session {
+        let mut text = |column: &str| match row.remove(column) {
+            Some(Value::String(v)) => v,
+            _ => String::new(),
+        };
+        let (id, username, ip, user_agent, csrf_token) = (text("id"), text("username"), text("ip"), text("user_agent"), text("csrf_token"));
+        let integer = |column: &str| match row.get(column) {
+            Some(Value::Integer(v)) => *v,
+            _ => 0,
+        };
+        Session {id, username, created: integer("created"), expires: integer("expires"), last_seen: integer("last_seen"), ip, user_agent, csrf_token}
+    }
+}
+
+/// Returns `length` bytes from the kernel CSPRNG, for the tokens and the secrets stored in the database
+pub fn random_secret(length: usize) -> Result<Vec<u8>, DatabaseError> {
+    random_bytes(length).map_err(DatabaseError::Random)
+}
+
+/// Returns 32 random bytes in hexadecimal, for the session IDs and the CSRF tokens
+pub fn random_token() -> Result<String, DatabaseError> {
+    random_secret(32).map(|v| hex_encode(&v))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sessions::*;
+    use crate::database_utils::tests::migrated_database;
+
+    #[test]
+    fn test_sessions() {
+        let database = migrated_database("sessions");
+        let store = SessionStore::from_config(&HashMap::from([(String::from("session_expiration_time"), String::from("600"))]));
+        let headers = HashMap::from([(String::from("user-agent"), String::from("test-agent"))]);
+        let request = IncomingRequest::from_parts("POST", "/login", "HTTP/1.1", headers, Vec::new(), None, None);
+
+        let desktop = store.create(&database, "alice", &request).unwrap();
+        let phone = store.create(&database, "alice", &request).unwrap();
+        let other = store.create(&database, "bob", &request).unwrap();
+        assert_ne!(desktop.id, phone.id);
+        assert_ne!(desktop.csrf_token, phone.csrf_token);
+        assert_eq!(Some(desktop.clone()), store.resolve(&database, &desktop.id).unwrap());
+        assert_eq!("test-agent", store.resolve(&database, &phone.id).unwrap().unwrap().user_agent);
+        assert_eq!(2, store.list(&database, "alice").unwrap().len());
+
+        // an old session slides forward when it is used, an expired one is gone
+        let values = [("last_seen", Value::from(desktop.last_seen - 300)), ("expires", Value::from(desktop.last_seen + 300))];
+        database.update_rows("sessions", &values, &Filter::new().where_eq("id", desktop.id.as_str())).unwrap();
+        assert_eq!(desktop.last_seen + 600, store.resolve(&database, &desktop.id).unwrap().unwrap().expires);
+        database.update_rows("sessions", &[("expires", Value::from(0))], &Filter::new().where_eq("id", other.id.as_str())).unwrap();
+        assert_eq!(None, store.resolve(&database, &other.id).unwrap());
+        assert_eq!(1, store.cleanup(&database).unwrap());
+
+        assert!(store.revoke(&database, &phone.id).unwrap());
+        assert!(!store.revoke(&database, &phone.id).unwrap());
+        assert!(store.resolve(&database, &desktop.id).unwrap().is_some());
+        assert_eq!(1, store.revoke_all(&database, "alice").unwrap());
+        assert_eq!(None, store.resolve(&database, &desktop.id).unwrap());
+    }
+}
@@ -1,79 +1,107 @@
-use std::thread;
-use std::sync::{mpsc, Arc, Mutex};
-
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
-}
-
-impl ThreadPool {
-    /// Create a new ThreadPool.
-    ///
-    /// The size is the number of threads in the pool
-    ///
-    /// # Panics
-    ///
-    /// The `new` function will panic is the size if zero.
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size>0);
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
-
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
-
-        ThreadPool{workers, sender: Some(sender)}
-    }
-    /// Execute the given closure in a thread from the thread pool
-    ///
-    ///
-    pub fn execute<F>(&self, f: F)
-    where F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
-    }
-}
-
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        drop(self.sender.take());
-
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-            
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            };
-        }
-    }
-}
-
-#[allow(dead_code)]
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-}
-
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-            match message {
-                Ok(job) => {
-                    // println!("worker {id} got a job; executing");
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down");
-                    break;
-                }
-            }
-        });
-        Worker {id, thread: Some(thread)}
-    }
-}
-
-type Job = Box<dyn FnOnce() + Send + 'static>;
+use std::thread;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic is the size if zero.
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size>0);
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Vec::with_capacity(size);
+
+        for id in 0..size {
+            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        }
+
+        ThreadPool{workers, sender: Some(sender)}
+    }
+    /// Execute the given closure in a thread from the thread pool
+    ///
+    ///
+    pub fn execute<F>(&self, f: F)
+    where F: FnOnce() + Send + 'static,
+    {
+        let job = Box::new(f);
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            println!("Shutting down worker {}", worker.id);
+            
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            };
+        }
+    }
+}
+
+#[allow(dead_code)]
+struct Worker {
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+        let thread = thread::spawn(move || loop {
+            let message = receiver.lock().unwrap().recv();
+            match message {
+                Ok(job) => {
+                    // println!("worker {id} got a job; executing");
+                    job();
+                }
+                Err(_) => {
+                    println!("Worker {id} disconnected; shutting down");
+                    break;
+                }
+            }
+        });
+        Worker {id, thread: Some(thread)}
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Caps the connections which get their own threads instead of a thread of the pool (HTTP/2, WebSocket and Server-Sent Events)
+pub struct ConnectionLimit {
+    max: usize,
+    active: Arc<AtomicUsize>,
+}
+
+/// A place among the connections of a [ConnectionLimit], released when dropped
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {max, active: Arc::new(AtomicUsize::new(0))}
+    }
+
+    /// Takes a place among the connections, returns `None` if they are all taken
+    pub fn reserve(&self) -> Option<ConnectionSlot> {
+        self.active.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| (v < self.max).then_some(v + 1)).ok()?;
+        Some(ConnectionSlot(self.active.clone()))
+    }
+}
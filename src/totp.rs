@@ -0,0 +1,293 @@
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::crypto_utils::{base32_decode, base32_encode, constant_time_eq, hex_encode, hmac_sha1, sha256};
+use crate::database_utils::{Comparison, Database, DatabaseError, Filter, Row, Value, now};
+use crate::sessions::{random_secret, random_token};
+
+/// The number of seconds each code lasts
+const STEP: i64 = 30;
+/// The codes of the steps before and after the current one are accepted too, for the clocks which drift
+const SKEW: i64 = 1;
+/// The length of the secrets in bytes, the length of the HMAC-SHA-1 key recommended by RFC 4226
+const SECRET_LENGTH: usize = 20;
+/// The number of recovery codes given when the second factor is enabled
+const RECOVERY_CODES: usize = 10;
+/// The number of seconds a login has to enter its code once its password was right
+pub const CHALLENGE_LIFETIME: i64 = 300;
+/// The number of wrong codes after which a login has to start over with its password
+const CHALLENGE_ATTEMPTS: i64 = 5;
+
+/// The TOTP (RFC 6238) second factor of a user, a row of the `totp` table.
+///
+/// The codes have 6 digits and change every 30 seconds, they are the HMAC-SHA-1 of the number of 30 seconds steps since
+/// the UNIX epoch keyed with the secret, which is stored in base32 as the authenticator apps need to compute the same
+/// codes. A code is only accepted once. The enrollment stays pending until the first code from the app confirms it,
+/// the user then gets recovery codes which replace a code once each, only their SHA-256 is stored.
+///
+/// A user who lost their device and their recovery codes can be reset with:
+/// ```text
+/// webserver-rs totp status alice
+/// webserver-rs totp reset alice
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Totp {
+    pub username: String,
+    /// The secret in base32, as shown to the user
+    pub secret: String,
+    pub enabled: bool,
+    /// The step of the last code accepted, the codes of this step and of the ones before are refused
+    last_counter: i64,
+}
+
+/// A login whose password was right and which waits for its second factor, a row of the `login_challenges` table.
+///
+/// Its ID is in the `loginChallenge` cookie, no session is issued before the code is entered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginChallenge {
+    pub id: String,
+    pub username: String,
+    pub expires: i64,
+    attempts: i64,
+}
+
+impl Totp {
+    /// Returns the second factor of the user, enabled or pending
+    pub fn of_user(database: &Database, username: &str) -> Result<Option<Totp>, DatabaseError> {
+        Ok(database.select_rows("totp", &Filter::new().where_eq("username", username))?.pop().map(Totp::from_row))
+    }
+
+    /// Starts the enrollment of the user with a new secret, or returns the one already started
+    pub fn enroll(database: &Database, username: &str) -> Result<Totp, DatabaseError> {
+        if let Some(totp) = Totp::of_user(database, username)? {
+            return Ok(totp);
+        }
+        let secret = base32_encode(&random_secret(SECRET_LENGTH)?);
+        database.insert_row("totp", &[("username", Value::from(username)), ("secret", Value::from(secret.as_str())), ("enabled", Value::from(0)), ("last_counter", Value::from(0)), ("created", Value::from(now()))])?;
+        Ok(Totp {username: username.to_string(), secret, enabled: false, last_counter: 0})
+    }
+
+    /// Returns the `otpauth://` URI which adds the account to an authenticator app, often shown as a QR code
+    pub fn provisioning_uri(&self, issuer: &str) -> String {
+        format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits=6&period={}",
+            uri_encode(issuer), uri_encode(&self.username), self.secret, uri_encode(issuer), STEP)
+    }
+
+    /// Checks a code of the authenticator app and records its step, so that it can't be used again.
+    ///
+    /// The step is only recorded if it is newer than the last one in the database, so that two requests sending the same
+    /// code at once can't both be accepted.
+    pub fn check_code(&mut self, database: &Database, code: &str) -> Result<bool, DatabaseError> {
+        let (Some(secret), Ok(_)) = (base32_decode(&self.secret), code.trim().parse::<u32>()) else {
+            return Ok(false);
+        };
+        let current = now() / STEP;
+        let step = (current - SKEW..=current + SKEW)
+            .filter(|v| *v > self.last_counter)
+            .find(|v| constant_time_eq(format!("{:06}", code_at(&secret, *v as u64)).as_bytes(), code.trim().as_bytes()));
+        let Some(step) = step else {
+            return Ok(false);
+        };
+        let filter = Filter::new().where_eq("username", self.username.as_str()).condition("last_counter", Comparison::Less, step);
+        if database.update_rows("totp", &[("last_counter", Value::from(step))], &filter)? != 1 {
+            return Ok(false);
+        }
+        self.last_counter = step;
+        Ok(true)
+    }
+
+    /// Checks a code of the authenticator app, or uses one of the recovery codes
+    pub fn verify(&mut self, database: &Database, code: &str) -> Result<bool, DatabaseError> {
+        if self.check_code(database, code)? {
+            return Ok(true);
+        }
+        let filter = Filter::new().where_eq("username", self.username.as_str()).where_eq("code_hash", recovery_code_hash(code));
+        let used = database.delete_rows("recovery_codes", &filter)? > 0;
+        if used {
+            info!("The user {:?} used a recovery code", self.username);
+        }
+        Ok(used)
+    }
+
+    /// Enables the second factor once a code confirmed the enrollment, returns the new recovery codes
+    pub fn enable(&mut self, database: &Database) -> Result<Vec<String>, DatabaseError> {
+        let codes: Vec<String> = random_secret(8 * RECOVERY_CODES)?.chunks(8)
+            .map(|v| hex_encode(v).as_bytes().chunks(4).map(|v| String::from_utf8_lossy(v).to_string()).collect::<Vec<String>>().join("-"))
+            .collect();
+        database.transaction(|t| {
+            t.delete_rows("recovery_codes", &Filter::new().where_eq("username", self.username.as_str()))?;
+            for code in &codes {
+                t.insert_row("recovery_codes", &[("username", Value::from(self.username.as_str())), ("code_hash", Value::from(recovery_code_hash(code)))])?;
+            }
+            t.update_rows("totp", &[("enabled", Value::from(1))], &Filter::new().where_eq("username", self.username.as_str()))
+        })?;
+        self.enabled = true;
+        info!("The user {:?} enabled two-factor authentication", self.username);
+        Ok(codes)
+    }
+
+    /// Removes the second factor and the recovery codes of the user, returns false if they had none
+    pub fn disable(database: &Database, username: &str) -> Result<bool, DatabaseError> {
+        database.transaction(|t| {
+            t.delete_rows("recovery_codes", &Filter::new().where_eq("username", username))?;
+            Ok(t.delete_rows("totp", &Filter::new().where_eq("username", username))? > 0)
+        })
+    }
+
+    /// Returns the number of recovery codes the user hasn't used
+    pub fn recovery_codes_left(database: &Database, username: &str) -> Result<usize, DatabaseError> {
+        Ok(database.select_rows("recovery_codes", &Filter::new().where_eq("username", username))?.len())
+    }
+
+    fn from_row(mut row: Row) -> Totp {
+        let mut text = |column: &str| match row.remove(column) {
+            Some(Value::String(v)) => v,
+            _ => String::new(),
+        };
+        let (username, secret) = (text("username"), text("secret"));
+        let integer = |column: &str| match row.get(column) {
+            Some(Value::Integer(v)) => *v,
+            _ => 0,
+        };
+        Totp {username, secret, enabled: integer("enabled") != 0, last_counter: integer("last_counter")}
+    }
+}
+
+impl LoginChallenge {
+    /// Starts the second step of the login of the user, and deletes the challenges which expired
+    pub fn create(database: &Database, username: &str) -> Result<LoginChallenge, DatabaseError> {
+        let id = random_token()?;
+        let now = now();
+        database.delete_rows("login_challenges", &Filter::new().condition("expires", Comparison::LessOrEqual, now))?;
+        let challenge = LoginChallenge {id, username: username.to_string(), expires: now + CHALLENGE_LIFETIME, attempts: 0};
+        database.insert_row("login_challenges", &[("id", Value::from(challenge.id.as_str())), ("username", Value::from(username)), ("expires", Value::from(challenge.expires)), ("attempts", Value::from(0))])?;
+        Ok(challenge)
+    }
+
+    /// Returns the challenge with this ID if it hasn't expired
+    pub fn resolve(database: &Database, id: &str) -> Result<Option<LoginChallenge>, DatabaseError> {
+        let filter = Filter::new().where_eq("id", id).condition("expires", Comparison::Greater, now());
+        Ok(database.select_rows("login_challenges", &filter)?.pop().map(|row| {
+            let text = |column: &str| match row.get(column) {
+                Some(Value::String(v)) => v.clone(),
+                _ => String::new(),
+            };
+            let integer = |column: &str| match row.get(column) {
+                Some(Value::Integer(v)) => *v,
+                _ => 0,
+            };
+            LoginChallenge {id: text("id"), username: text("username"), expires: integer("expires"), attempts: integer("attempts")}
+        }))
+    }
+
+    /// Records a wrong code, returns false if the challenge is over and the login has to start again
+    pub fn failed(&mut self, database: &Database) -> Result<bool, DatabaseError> {
+        self.attempts += 1;
+        if self.attempts >= CHALLENGE_ATTEMPTS {
+            self.delete(database)?;
+            return Ok(false);
+        }
+        database.update_rows("login_challenges", &[("attempts", Value::from(self.attempts))], &Filter::new().where_eq("id", self.id.as_str()))?;
+        Ok(true)
+    }
+
+    /// Ends the challenge once the code was right
+    pub fn delete(&self, database: &Database) -> Result<(), DatabaseError> {
+        database.delete_rows("login_challenges", &Filter::new().where_eq("id", self.id.as_str()))?;
+        Ok(())
+    }
+}
+
+/// Returns the HOTP code (RFC 4226) of the counter, the TOTP code of a step is the HOTP code of its number
+pub fn code_at(secret: &[u8], counter: u64) -> u32 {
+    let hash = hmac_sha1(secret, &counter.to_be_bytes());
+    let offset = (hash[19] & 0x0F) as usize;
+    let truncated = u32::from_be_bytes([hash[offset] & 0x7F, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    truncated % 1_000_000
+}
+
+/// Runs the `totp` subcommand, returns what has to be printed
+pub fn run_command(database: &Database, args: &[String]) -> Result<String, String> {
+    let usage = "Usage:\n    webserver-rs totp status <username>\n    webserver-rs totp reset <username>";
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["status", username] => match Totp::of_user(database, username).map_err(|e| e.to_string())? {
+            Some(v) if v.enabled => Ok(format!("{} uses two-factor authentication, {} recovery codes left", username,
+                Totp::recovery_codes_left(database, username).map_err(|e| e.to_string())?)),
+            Some(_) => Ok(format!("{} started enrolling but didn't confirm a code yet", username)),
+            None => Ok(format!("{} doesn't use two-factor authentication", username)),
+        },
+        ["reset", username] => match Totp::disable(database, username) {
+            Ok(true) => Ok(format!("Removed the second factor of {}, they have to enroll again if their auth level requires it", username)),
+            Ok(false) => Err(format!("{} doesn't use two-factor authentication", username)),
+            Err(e) => Err(e.to_string()),
+        },
+        _ => Err(String::from(usage)),
+    }
+}
+
+/// The recovery codes are compared without their dashes and their case
+fn recovery_code_hash(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_lowercase()).collect();
+    hex_encode(&sha256(normalized.as_bytes()))
+}
+
+/// Percent-encodes the label and the issuer of a provisioning URI
+fn uri_encode(text: &str) -> String {
+    text.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::totp::*;
+    use crate::database_utils::tests::migrated_database;
+
+    #[test]
+    fn test_totp() {
+        // the SHA-1 test vectors of RFC 6238, with 6 digits
+        let secret = b"12345678901234567890";
+        assert_eq!(287082, code_at(secret, 59 / 30));
+        assert_eq!(81804, code_at(secret, 1111111109 / 30));
+        assert_eq!(5924, code_at(secret, 1234567890 / 30));
+        assert_eq!(279037, code_at(secret, 2000000000 / 30));
+
+        let database = migrated_database("totp");
+
+        let mut totp = Totp::enroll(&database, "al ice").unwrap();
+        assert_eq!(totp, Totp::enroll(&database, "al ice").unwrap());
+        assert!(!totp.enabled && totp.secret.len() == 32);
+        assert!(totp.provisioning_uri("webserver-rs").starts_with(&format!("otpauth://totp/webserver-rs:al%20ice?secret={}&issuer=webserver-rs", totp.secret)));
+
+        let code = format!("{:06}", code_at(&base32_decode(&totp.secret).unwrap(), (now() / STEP) as u64));
+        // a request which loaded the row before the code was used can't use it again
+        let mut concurrent = Totp::of_user(&database, "al ice").unwrap().unwrap();
+        assert!(!totp.check_code(&database, "abcdef").unwrap());
+        assert!(totp.check_code(&database, &code).unwrap());
+        assert!(!concurrent.check_code(&database, &code).unwrap());
+        // a code is only used once
+        assert!(!Totp::of_user(&database, "al ice").unwrap().unwrap().check_code(&database, &code).unwrap());
+
+        let codes = totp.enable(&database).unwrap();
+        assert_eq!((RECOVERY_CODES, 19), (codes.len(), codes[0].len()));
+        assert!(Totp::of_user(&database, "al ice").unwrap().unwrap().enabled);
+        assert!(totp.verify(&database, &codes[3].to_uppercase()).unwrap());
+        assert!(!totp.verify(&database, &codes[3]).unwrap());
+        assert_eq!(RECOVERY_CODES - 1, Totp::recovery_codes_left(&database, "al ice").unwrap());
+
+        let mut challenge = LoginChallenge::create(&database, "al ice").unwrap();
+        assert_eq!(Some(challenge.clone()), LoginChallenge::resolve(&database, &challenge.id).unwrap());
+        for _ in 1..CHALLENGE_ATTEMPTS {
+            assert!(challenge.failed(&database).unwrap());
+        }
+        assert!(!challenge.failed(&database).unwrap());
+        assert_eq!(None, LoginChallenge::resolve(&database, &challenge.id).unwrap());
+
+        assert!(run_command(&database, &[String::from("status"), String::from("al ice")]).unwrap().contains("9 recovery codes left"));
+        assert!(run_command(&database, &[String::from("reset"), String::from("al ice")]).is_ok());
+        assert_eq!(None, Totp::of_user(&database, "al ice").unwrap());
+        assert_eq!(0, Totp::recovery_codes_left(&database, "al ice").unwrap());
+    }
+}
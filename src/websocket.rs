@@ -0,0 +1,636 @@
+use std::net::{Shutdown, TcpStream};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use crate::auth::url_authority;
+use crate::crypto_utils::{base64_encode, sha1};
+use crate::request_handler::{HTTPCode, IncomingRequest, ServerStatus};
+use crate::script_runner::{ScriptLimits, ScriptRunner, kill_group};
+use crate::thread_pool::ConnectionLimit;
+
+/// The GUID appended to the `Sec-WebSocket-Key` of the client to compute the `Sec-WebSocket-Accept` of the handshake
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// Accepts the WebSocket connections (RFC 6455) of the routes whose callback is a WebSocket endpoint:
+/// ```text
+/// websocket://data/pages/ws/flipbot.py
+/// broadcast://flipbot
+/// ```
+/// A `websocket://` route starts the script for each connection with the request as its first argument, like the other scripts.
+/// Every text message of the client is written on a line of the script's stdin and every line the script writes on its stdout
+/// is sent to the client as a text message, the connection is closed when the script exits and the script is killed when
+/// the connection is closed.
+///
+/// A `broadcast://` route is a built-in channel: every message received from a client is sent to all the other clients
+/// connected to the same channel.
+///
+/// Each connection runs in its own threads, the server pings the client every `websocket_ping_interval` seconds and closes
+/// the connection if nothing was received for two intervals. At most `websocket_max_connections` connections are open at
+/// once, the next clients get an error 503:
+/// ```json
+/// {
+///     "websocket_max_message":1048576,
+///     "websocket_ping_interval":30,
+///     "websocket_max_connections":256
+/// }
+/// ```
+pub struct WebSocketServer {
+    max_message: usize,
+    ping_interval: Duration,
+    hub: BroadcastHub,
+    connections: ConnectionLimit,
+}
+
+/// A message of a WebSocket connection, after the reassembly of its fragments
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text (String),
+    Binary (Vec<u8>),
+}
+
+/// Where the messages of a connection go and come from
+enum Backend {
+    Script (Child),
+    /// The channel, the id of the subscriber and its receiver
+    Broadcast (String, usize, mpsc::Receiver<Message>),
+}
+
+impl WebSocketServer {
+    /// Creates the server with the limits of the server config
+    pub fn from_config(config: &HashMap<String, String>) -> WebSocketServer {
+        WebSocketServer {
+            max_message: config.get("websocket_max_message").and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(1048576),
+            ping_interval: config.get("websocket_ping_interval")
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| *v > 0.0)
+                .map(Duration::from_secs_f64)
+                .unwrap_or(Duration::from_secs(30)),
+            hub: BroadcastHub::default(),
+            connections: ConnectionLimit::new(config.get("websocket_max_connections").and_then(|v| v.trim().parse::<usize>().ok()).unwrap_or(256)),
+        }
+    }
+
+    /// Returns true if the callback of a route is a WebSocket endpoint
+    pub fn is_endpoint(callback: &str) -> bool {
+        callback.starts_with("websocket://") || callback.starts_with("broadcast://")
+    }
+
+    /// Completes the handshake of a WebSocket endpoint and starts the thread of the connection.
+    ///
+    /// Nothing is written to the client when an error is returned: `Err400` if the request isn't a valid WebSocket handshake,
+    /// `Err403` if it comes from a page of another site, `Err503` if too many connections are open and `InternalError` if
+    /// the script could not be started.
+    pub fn accept(&self, stream: &TcpStream, incoming: &IncomingRequest, callback: &str, script_runner: &ScriptRunner, limits: &ScriptLimits) -> ServerStatus<()> {
+        let key = match handshake_key(incoming) {
+            Some(v) => v,
+            None => return ServerStatus::Error(HTTPCode::Err400),
+        };
+        // the handshake is a GET which isn't checked for CSRF, but the browsers send the `Origin` of the page opening it
+        let headers = incoming.headers();
+        let host = headers.get("host").map(|v| v.trim()).unwrap_or_default();
+        if headers.get("origin").is_some_and(|v| !url_authority(v.trim()).is_some_and(|v| v.eq_ignore_ascii_case(host))) {
+            info!("Refused a WebSocket handshake to {} from the origin {:?}", incoming.path(), headers.get("origin"));
+            return ServerStatus::Error(HTTPCode::Err403);
+        }
+        let slot = match self.connections.reserve() {
+            Some(v) => v,
+            None => {
+                warn!("Too many WebSocket connections open, refusing {}", incoming.path());
+                return ServerStatus::Error(HTTPCode::Err503);
+            }
+        };
+        let backend = match (callback.strip_prefix("websocket://"), callback.strip_prefix("broadcast://")) {
+            (Some(script), _) => match script_runner.spawn_script(script, &incoming.as_json(), &[], limits) {
+                Ok(v) => Backend::Script(v),
+                Err(e) => {
+                    error!("{}", e);
+                    return ServerStatus::InternalError;
+                }
+            },
+            // subscribed before the handshake so that the client gets every message sent once it is connected
+            (_, Some(channel)) => {
+                let (id, receiver) = self.hub.subscribe(channel);
+                Backend::Broadcast(channel.to_string(), id, receiver)
+            },
+            _ => return ServerStatus::InternalError,
+        };
+
+        let accept = base64_encode(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()));
+        let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept);
+        let mut handshake = stream;
+        let streams = handshake.write_all(response.as_bytes())
+            .and_then(|_| stream.set_read_timeout(None))
+            .and_then(|_| Ok((stream.try_clone()?, stream.try_clone()?)));
+        let (reader, writer) = match streams {
+            Ok(v) => v,
+            Err(e) => {
+                info!("Error when completing the WebSocket handshake: {}", e);
+                match backend {
+                    Backend::Script(mut child) => kill_group(&mut child),
+                    Backend::Broadcast(channel, id, _) => self.hub.unsubscribe(&channel, id),
+                }
+                return ServerStatus::Ok(());
+            }
+        };
+
+        let session = Session {
+            reader: BufReader::new(reader),
+            writer: Arc::new(Writer {stream: Mutex::new((writer, false))}),
+            max_message: self.max_message,
+            ping_interval: self.ping_interval,
+            hub: self.hub.clone(),
+        };
+        debug!("WebSocket connection opened on {}", callback);
+        thread::spawn(move || {
+            session.run(backend);
+            drop(slot);
+        });
+        ServerStatus::Ok(())
+    }
+}
+
+/// Returns the `Sec-WebSocket-Key` of the request if it is a valid WebSocket handshake
+fn handshake_key(incoming: &IncomingRequest) -> Option<String> {
+    let headers = incoming.headers();
+    let has_token = |name: &str, token: &str| headers.get(name)
+        .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    if incoming.method() != "GET" || !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return None;
+    }
+    if headers.get("sec-websocket-version").map(|v| v.trim()) != Some("13") {
+        return None;
+    }
+    headers.get("sec-websocket-key").map(|v| v.trim().to_string()).filter(|v| v.len() == 24)
+}
+
+/// The sending half of a connection, shared by the threads of the session. The flag is set once a close frame was sent.
+struct Writer {
+    stream: Mutex<(TcpStream, bool)>,
+}
+
+impl Writer {
+    /// Sends an unfragmented frame, nothing is sent after a close frame
+    fn send(&self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mut guard = self.stream.lock().unwrap();
+        let (stream, closed) = &mut *guard;
+        if *closed {
+            return Ok(());
+        }
+        *closed = opcode == OPCODE_CLOSE;
+        stream.write_all(&encode_frame(opcode, payload))?;
+        stream.flush()
+    }
+
+    fn send_message(&self, message: &Message) -> std::io::Result<()> {
+        match message {
+            Message::Text(v) => self.send(OPCODE_TEXT, v.as_bytes()),
+            Message::Binary(v) => self.send(OPCODE_BINARY, v),
+        }
+    }
+
+    fn close(&self, code: u16, reason: &str) {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        let _ = self.send(OPCODE_CLOSE, &payload);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.stream.lock().unwrap().1
+    }
+
+    fn shutdown(&self) {
+        let _ = self.stream.lock().unwrap().0.shutdown(Shutdown::Both);
+    }
+}
+
+/// A frame as it was received, the payload is already unmasked
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Why reading a frame failed: the connection is lost or the client has to be sent a close frame with this code
+enum FrameError {
+    Io,
+    Close (u16, &'static str),
+}
+
+/// Encodes a frame sent by the server, they are never masked
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        0..=125 => frame.push(payload.len() as u8),
+        126..=65535 => {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        },
+        _ => {
+            frame.push(127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads a frame sent by the client, refuses the unmasked frames and the frames bigger than `max_len`
+fn read_frame(reader: &mut impl Read, max_len: usize) -> Result<Frame, FrameError> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).map_err(|_| FrameError::Io)?;
+    let (fin, opcode) = (header[0] & 0x80 != 0, header[0] & 0x0F);
+    if header[0] & 0x70 != 0 {
+        return Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+    }
+    if header[1] & 0x80 == 0 {
+        return Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, "unmasked frame"));
+    }
+    let length = match header[1] & 0x7F {
+        126 => {
+            let mut length = [0u8; 2];
+            reader.read_exact(&mut length).map_err(|_| FrameError::Io)?;
+            u16::from_be_bytes(length) as u64
+        },
+        127 => {
+            let mut length = [0u8; 8];
+            reader.read_exact(&mut length).map_err(|_| FrameError::Io)?;
+            u64::from_be_bytes(length)
+        },
+        v => v as u64,
+    };
+    if opcode >= OPCODE_CLOSE && (!fin || length > 125) {
+        return Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, "invalid control frame"));
+    }
+    if length > max_len as u64 {
+        return Err(FrameError::Close(CLOSE_MESSAGE_TOO_BIG, "message too big"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).map_err(|_| FrameError::Io)?;
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).map_err(|_| FrameError::Io)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {fin, opcode, payload})
+}
+
+/// A WebSocket connection after the handshake, run in its own thread
+struct Session {
+    reader: BufReader<TcpStream>,
+    writer: Arc<Writer>,
+    max_message: usize,
+    ping_interval: Duration,
+    hub: BroadcastHub,
+}
+
+impl Session {
+    fn run(mut self, backend: Backend) {
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let pinger = {
+            let (writer, last_seen, interval) = (self.writer.clone(), last_seen.clone(), self.ping_interval);
+            thread::spawn(move || while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if last_seen.lock().unwrap().elapsed() >= interval * 2 {
+                    debug!("WebSocket client stopped answering, closing the connection");
+                    writer.close(CLOSE_GOING_AWAY, "ping timeout");
+                    writer.shutdown();
+                    break;
+                }
+                let _ = writer.send(OPCODE_PING, b"");
+            })
+        };
+
+        match backend {
+            Backend::Script(mut child) => {
+                let stdin = child.stdin.take();
+                if let Some(stdout) = child.stdout.take() {
+                    let writer = self.writer.clone();
+                    thread::spawn(move || {
+                        for line in BufReader::new(stdout).lines() {
+                            let line = match line {
+                                Ok(v) => v,
+                                Err(_) => break,
+                            };
+                            if writer.send(OPCODE_TEXT, line.as_bytes()).is_err() {
+                                break;
+                            }
+                        }
+                        writer.close(CLOSE_NORMAL, "");
+                    });
+                }
+                self.receive(&last_seen, |message| write_line(stdin.as_ref(), message));
+                kill_group(&mut child);
+            },
+            Backend::Broadcast(channel, id, receiver) => {
+                let writer = self.writer.clone();
+                thread::spawn(move || {
+                    for message in receiver {
+                        if writer.send_message(&message).is_err() {
+                            break;
+                        }
+                    }
+                });
+                let hub = self.hub.clone();
+                self.receive(&last_seen, |message| {
+                    hub.publish(&channel, Some(id), message);
+                    None
+                });
+                self.hub.unsubscribe(&channel, id);
+            },
+        }
+        drop(stop);
+        let _ = pinger.join();
+        self.writer.shutdown();
+        debug!("WebSocket connection closed");
+    }
+
+    /// Reads the frames of the client until the connection is closed, answers the control frames and passes the messages
+    /// to `on_message`, which returns a close code to end the connection
+    fn receive(&mut self, last_seen: &Mutex<Instant>, mut on_message: impl FnMut(Message) -> Option<(u16, &'static str)>) {
+        let mut fragments: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let frame = match read_frame(&mut self.reader, self.max_message) {
+                Ok(v) => v,
+                Err(FrameError::Io) => return,
+                Err(FrameError::Close(code, reason)) => return self.writer.close(code, reason),
+            };
+            *last_seen.lock().unwrap() = Instant::now();
+
+            let (opcode, payload) = match (frame.opcode, fragments.take()) {
+                (OPCODE_PING, fragments_left) => {
+                    fragments = fragments_left;
+                    let _ = self.writer.send(OPCODE_PONG, &frame.payload);
+                    continue;
+                },
+                (OPCODE_PONG, fragments_left) => {
+                    fragments = fragments_left;
+                    continue;
+                },
+                (OPCODE_CLOSE, _) => {
+                    // echo the code of the client, a close frame sent by the server first is just acknowledged.
+                    // The payload is empty or starts with a 2 bytes code (RFC 6455 section 5.5.1)
+                    return match frame.payload.as_slice() {
+                        [] => self.writer.close(CLOSE_NORMAL, ""),
+                        [_] => self.writer.close(CLOSE_PROTOCOL_ERROR, "invalid close frame"),
+                        [high, low, ..] => self.writer.close(u16::from_be_bytes([*high, *low]), ""),
+                    };
+                },
+                (OPCODE_TEXT | OPCODE_BINARY, None) => (frame.opcode, frame.payload),
+                (OPCODE_CONTINUATION, Some((opcode, mut payload))) => {
+                    if payload.len() + frame.payload.len() > self.max_message {
+                        return self.writer.close(CLOSE_MESSAGE_TOO_BIG, "message too big");
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    (opcode, payload)
+                },
+                _ => return self.writer.close(CLOSE_PROTOCOL_ERROR, "unexpected frame"),
+            };
+            if !frame.fin {
+                fragments = Some((opcode, payload));
+                continue;
+            }
+
+            let message = match opcode {
+                OPCODE_TEXT => match String::from_utf8(payload) {
+                    Ok(v) => Message::Text(v),
+                    Err(_) => return self.writer.close(CLOSE_INVALID_DATA, "invalid utf-8"),
+                },
+                _ => Message::Binary(payload),
+            };
+            if self.writer.is_closed() {
+                continue;
+            }
+            if let Some((code, reason)) = on_message(message) {
+                return self.writer.close(code, reason);
+            }
+        }
+    }
+}
+
+/// Writes a text message on a line of the script's stdin, the binary messages aren't supported
+fn write_line(stdin: Option<&ChildStdin>, message: Message) -> Option<(u16, &'static str)> {
+    let text = match message {
+        Message::Text(v) => v,
+        Message::Binary(_) => return Some((CLOSE_UNSUPPORTED_DATA, "binary messages are not supported")),
+    };
+    let mut stdin = stdin?;
+    let line = format!("{}\n", text.replace('\n', " "));
+    match stdin.write_all(line.as_bytes()).and_then(|_| stdin.flush()) {
+        Ok(()) => None,
+        Err(_) => Some((CLOSE_INTERNAL_ERROR, "the script exited")),
+    }
+}
+
+/// The subscribers of a channel of a [BroadcastHub] with their ids
+type Subscribers = Vec<(usize, mpsc::Sender<Message>)>;
+
+/// The channels of the `broadcast://` routes and the connections subscribed to them
+#[derive(Clone, Default)]
+pub struct BroadcastHub {
+    channels: Arc<Mutex<HashMap<String, Subscribers>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl BroadcastHub {
+    /// Subscribes to a channel, returns the id of the subscriber and the receiver of the messages of the channel
+    pub fn subscribe(&self, channel: &str) -> (usize, mpsc::Receiver<Message>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        self.channels.lock().unwrap().entry(channel.to_string()).or_default().push((id, sender));
+        (id, receiver)
+    }
+
+    pub fn unsubscribe(&self, channel: &str, id: usize) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.retain(|(v, _)| *v != id);
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+    }
+
+    /// Sends a message to every subscriber of the channel except `from`, returns the number of subscribers reached
+    pub fn publish(&self, channel: &str, from: Option<usize>, message: Message) -> usize {
+        let channels = self.channels.lock().unwrap();
+        let subscribers = match channels.get(channel) {
+            Some(v) => v,
+            None => return 0,
+        };
+        subscribers.iter()
+            .filter(|(id, _)| Some(*id) != from)
+            .filter(|(_, sender)| sender.send(message.clone()).is_ok())
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::websocket::*;
+
+    /// Encodes a frame the way a client does, with a mask
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode_frame(opcode, payload);
+        let header_len = frame.len() - payload.len();
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame[1] |= 0x80;
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let masked: Vec<u8> = payload.iter().enumerate().map(|(i, v)| v ^ mask[i % 4]).collect();
+        frame.truncate(header_len);
+        frame.extend_from_slice(&mask);
+        frame.extend_from_slice(&masked);
+        frame
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let frame = client_frame(true, OPCODE_TEXT, b"Hello");
+        let parsed = read_frame(&mut frame.as_slice(), 1024).ok().unwrap();
+        assert!(parsed.fin && parsed.opcode == OPCODE_TEXT && parsed.payload == b"Hello");
+
+        let long = vec![b'a'; 70000];
+        let parsed = read_frame(&mut client_frame(true, OPCODE_BINARY, &long).as_slice(), 100000).ok().unwrap();
+        assert_eq!(parsed.payload, long);
+        assert!(matches!(read_frame(&mut client_frame(true, OPCODE_BINARY, &long).as_slice(), 1024), Err(FrameError::Close(CLOSE_MESSAGE_TOO_BIG, _))));
+        // the frames of the clients must be masked
+        assert!(matches!(read_frame(&mut encode_frame(OPCODE_TEXT, b"Hello").as_slice(), 1024), Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, _))));
+        assert!(matches!(read_frame(&mut client_frame(false, OPCODE_PING, b"").as_slice(), 1024), Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, _))));
+    }
+
+    /// Runs a session subscribed to the channel `test` of the hub and returns the client connected to it
+    fn test_session(hub: &BroadcastHub) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let session = Session {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: Arc::new(Writer {stream: Mutex::new((stream, false))}),
+            max_message: 1024,
+            ping_interval: Duration::from_secs(30),
+            hub: hub.clone(),
+        };
+        let (id, session_receiver) = hub.subscribe("test");
+        let thread = thread::spawn(move || session.run(Backend::Broadcast(String::from("test"), id, session_receiver)));
+        (client, thread)
+    }
+
+    #[test]
+    fn test_broadcast_session() {
+        let hub = BroadcastHub::default();
+        let (_, receiver) = hub.subscribe("test");
+        let (mut client, thread) = test_session(&hub);
+
+        // a fragmented message with a ping in the middle
+        client.write_all(&client_frame(false, OPCODE_TEXT, b"Hel")).unwrap();
+        client.write_all(&client_frame(true, OPCODE_PING, b"p")).unwrap();
+        client.write_all(&client_frame(true, OPCODE_CONTINUATION, b"lo")).unwrap();
+        let mut pong = [0u8; 3];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x80 | OPCODE_PONG, 1, b'p']);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), Message::Text(String::from("Hello")));
+
+        // the messages of the channel are sent to the client
+        assert_eq!(hub.publish("test", None, Message::Text(String::from("hi"))), 2);
+        let mut message = [0u8; 4];
+        client.read_exact(&mut message).unwrap();
+        assert_eq!(message, [0x80 | OPCODE_TEXT, 2, b'h', b'i']);
+
+        // close handshake
+        client.write_all(&client_frame(true, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes())).unwrap();
+        let mut close = Vec::new();
+        client.read_to_end(&mut close).unwrap();
+        assert_eq!(close, [0x80 | OPCODE_CLOSE, 2, 0x03, 0xE8]);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_invalid_close_frame() {
+        // a close code takes 2 bytes, a payload of 1 byte is a protocol error
+        let hub = BroadcastHub::default();
+        let (mut client, thread) = test_session(&hub);
+        client.write_all(&client_frame(true, OPCODE_CLOSE, &[0x03])).unwrap();
+        let mut close = Vec::new();
+        client.read_to_end(&mut close).unwrap();
+        assert_eq!(close[0], 0x80 | OPCODE_CLOSE);
+        assert_eq!(close[2..4], CLOSE_PROTOCOL_ERROR.to_be_bytes());
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_cross_site_handshake() {
+        let server = WebSocketServer::from_config(&HashMap::new());
+        let runner = ScriptRunner::from_config(&HashMap::new(), &json::JsonValue::Null);
+        let handshake = |origin: &str| {
+            let request = format!("GET /chat HTTP/1.1\r\nHost: localhost:8080\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n{}\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", origin);
+            match IncomingRequest::from_bytes(request.as_bytes(), None, None) {
+                crate::request_handler::ParsedRequest::Ok(v) => v,
+                _ => panic!("invalid test request"),
+            }
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        for origin in ["Origin: https://attacker.example\r\n", "Origin: null\r\n", "Origin: http://localhost\r\n"] {
+            assert!(matches!(server.accept(&stream, &handshake(origin), "broadcast://test", &runner, &ScriptLimits::default()), ServerStatus::Error(HTTPCode::Err403)));
+        }
+        assert!(matches!(server.accept(&stream, &handshake("Origin: http://LOCALHOST:8080\r\n"), "broadcast://test", &runner, &ScriptLimits::default()), ServerStatus::Ok(())));
+        // the clients which aren't browsers send no origin
+        assert!(matches!(server.accept(&stream, &handshake(""), "broadcast://test", &runner, &ScriptLimits::default()), ServerStatus::Ok(())));
+        drop(client);
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let config = HashMap::from([(String::from("websocket_max_connections"), String::from("1"))]);
+        let server = WebSocketServer::from_config(&config);
+        let runner = ScriptRunner::from_config(&HashMap::new(), &json::JsonValue::Null);
+        let request = b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let incoming = match IncomingRequest::from_bytes(request, None, None) {
+            crate::request_handler::ParsedRequest::Ok(v) => v,
+            _ => panic!("invalid test request"),
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert!(matches!(server.accept(&stream, &incoming, "broadcast://test", &runner, &ScriptLimits::default()), ServerStatus::Ok(())));
+        // the only place is taken until the connection is closed
+        assert!(matches!(server.accept(&stream, &incoming, "broadcast://test", &runner, &ScriptLimits::default()), ServerStatus::Error(HTTPCode::Err503)));
+
+        client.write_all(&client_frame(true, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes())).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        let started = Instant::now();
+        while server.connections.reserve().is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}